@@ -1,30 +1,117 @@
-use failure::{Compat, Fail};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::{Compat, Fail, ResultExt};
 use futures::future::FutureResult;
 use futures::{future, Future, IntoFuture};
 use http::{header, Method, Request, Response, StatusCode};
 use hyper::service::{NewService, Service};
 use hyper::Body;
 use log::{debug, info};
+use serde_json::json;
 
-use crate::{logging, Error};
+use crate::supervisor::Status;
+use crate::{logging, Error, ErrorKind};
 
 #[derive(Clone)]
-pub struct ApiService;
+pub struct ApiService {
+    status: Status,
+}
 
 impl ApiService {
-    pub fn new() -> Self {
-        ApiService
+    pub fn new(status: Status) -> Self {
+        ApiService { status }
     }
 
     fn handle(&self, req: &Request<Body>) -> Result<Response<Body>, Error> {
         match (req.method(), req.uri().path()) {
             (&Method::GET, "/health") => Ok(Response::new(Body::empty())),
+            (&Method::GET, "/status") => self.status(),
+            (&Method::GET, "/ready") => self.ready(),
+            (&Method::GET, "/metrics") => Ok(self.metrics()),
             _ => Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::empty())
                 .unwrap()),
         }
     }
+
+    fn status(&self) -> Result<Response<Body>, Error> {
+        json_response(StatusCode::OK, &self.status)
+    }
+
+    fn ready(&self) -> Result<Response<Body>, Error> {
+        let status = if self.status.is_ready() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        json_response(status, &self.status)
+    }
+
+    fn metrics(&self) -> Response<Body> {
+        let mut body = String::new();
+        body.push_str(
+            "# HELP edge_proxy_certificate_expiry_days Days until a certificate used by a service expires.\n",
+        );
+        body.push_str("# TYPE edge_proxy_certificate_expiry_days gauge\n");
+
+        for service in self.status.services() {
+            for expiry in service.certificates() {
+                body.push_str(&format!(
+                    "edge_proxy_certificate_expiry_days{{service=\"{}\",source=\"{}\",subject=\"{}\"}} {}\n",
+                    escape(service.name()),
+                    expiry.source(),
+                    escape(expiry.subject()),
+                    expiry.days_until_expiry()
+                ));
+            }
+        }
+
+        body.push_str(
+            "# HELP edge_proxy_token_expiry_seconds Seconds until the token sent to the backend expires.\n",
+        );
+        body.push_str("# TYPE edge_proxy_token_expiry_seconds gauge\n");
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+        for service in self.status.services() {
+            if let Some(expiry) = service.token_expiry() {
+                body.push_str(&format!(
+                    "edge_proxy_token_expiry_seconds{{service=\"{}\"}} {}\n",
+                    escape(service.name()),
+                    expiry - now
+                ));
+            }
+        }
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body.into())
+            .unwrap()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn json_response(status_code: StatusCode, status: &Status) -> Result<Response<Body>, Error> {
+    let body = json!({ "services": status.services() });
+    let body = serde_json::to_string(&body).context(ErrorKind::Generic)?;
+
+    Ok(Response::builder()
+        .status(status_code)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body.into())
+        .unwrap())
 }
 
 impl Service for ApiService {
@@ -73,3 +160,71 @@ impl NewService for ApiService {
         future::ok(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use futures::{Future, Stream};
+    use http::Request;
+    use hyper::Body;
+
+    use crate::api::{escape, ApiService};
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::expiry::{CertificateExpiry, CertificateSource};
+    use crate::supervisor::Status;
+    use crate::x509::Certificate;
+
+    #[test]
+    fn it_exposes_certificate_expiry_metrics() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .days(10)
+            .generate()
+            .unwrap();
+        let status = Status::default();
+        let cert = Certificate::from_der(&cert.cert().to_der().unwrap()).unwrap();
+        let expiry = CertificateExpiry::new(CertificateSource::Backend, &cert);
+        status.certificate("management", expiry);
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = ApiService::new(status).handle(&req).unwrap();
+        let body = res.into_body().concat2().wait().unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let line = body.lines().find(|line| !line.starts_with('#')).unwrap();
+        let (labels, days) = line.split_at(line.rfind(' ').unwrap());
+        assert_eq!(
+            labels,
+            "edge_proxy_certificate_expiry_days{service=\"management\",source=\"backend\",subject=\"CN=iotedged\"}"
+        );
+        assert!((9..=10).contains(&days.trim().parse::<i64>().unwrap()));
+    }
+
+    #[test]
+    fn it_exposes_token_expiry_metrics() {
+        let status = Status::default();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        status.token_expiry("management", now + 600);
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = ApiService::new(status).handle(&req).unwrap();
+        let body = res.into_body().concat2().wait().unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let line = body.lines().find(|line| !line.starts_with('#')).unwrap();
+        let (labels, seconds) = line.split_at(line.rfind(' ').unwrap());
+        assert_eq!(
+            labels,
+            "edge_proxy_token_expiry_seconds{service=\"management\"}"
+        );
+        assert!((595..=600).contains(&seconds.trim().parse::<i64>().unwrap()));
+    }
+
+    #[test]
+    fn it_escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
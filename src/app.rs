@@ -1,36 +1,104 @@
-use std::path::Path;
+#[cfg(feature = "openssl")]
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, App, Arg, ArgMatches, SubCommand,
+};
+#[cfg(feature = "openssl")]
+use failure::ResultExt;
 use log::info;
 
-use crate::{logging, Error, Settings};
+use crate::cert::CertSettings;
+use crate::{logging, Error, ErrorKind, Settings};
 
-pub fn init() -> Result<Settings, Error> {
-    logging::init();
+pub enum Command {
+    Run(Settings),
+    Probe(Settings, String, Option<String>),
+    Cert(CertSettings),
+}
 
-    info!("Starting proxy server");
+pub fn init() -> Result<Command, Error> {
+    logging::init();
 
     let matches = create_app().get_matches();
+
+    let command = match matches.subcommand() {
+        ("probe", Some(matches)) => {
+            let settings = load_settings(matches)?;
+            let service = matches.value_of("service").unwrap_or_default().to_owned();
+            let path = matches.value_of("path").map(ToOwned::to_owned);
+            Command::Probe(settings, service, path)
+        }
+        ("cert", Some(matches)) => Command::Cert(cert_settings(matches)?),
+        _ => {
+            info!("Starting proxy server");
+            Command::Run(load_settings(&matches)?)
+        }
+    };
+
+    Ok(command)
+}
+
+fn load_settings(matches: &ArgMatches<'_>) -> Result<Settings, Error> {
     let config_file = matches
         .value_of_os("config")
-        .and_then(|name| {
+        .map(|name| {
             let path = Path::new(name);
             info!("Using config file: {}", path.display());
-            Some(path)
+            path
         })
         .or_else(|| {
             info!("Using default configuration");
             None
         });
 
-    let settings = Settings::new(config_file)?;
+    Settings::new(config_file)
+}
+
+fn cert_settings(matches: &ArgMatches<'_>) -> Result<CertSettings, Error> {
+    let values = |name| {
+        matches
+            .values_of(name)
+            .map(|values| values.map(ToOwned::to_owned).collect())
+            .unwrap_or_default()
+    };
+
+    let days = matches.value_of("days").unwrap_or_default();
+    let days = days
+        .parse::<u32>()
+        .ok()
+        .filter(|days| *days > 0)
+        .ok_or_else(|| ErrorKind::InvalidArgument(days.to_owned()))?;
+
+    Ok(CertSettings {
+        out: PathBuf::from(matches.value_of_os("out").unwrap_or_default()),
+        days,
+        servers: values("server"),
+        sans: values("san"),
+        clients: values("client"),
+        #[cfg(feature = "openssl")]
+        pkcs12_password: pkcs12_password(matches)?,
+    })
+}
+
+#[cfg(feature = "openssl")]
+fn pkcs12_password(matches: &ArgMatches<'_>) -> Result<Option<String>, Error> {
+    let password = match matches.value_of_os("pkcs12-password-file") {
+        Some(path) => {
+            let password = fs::read_to_string(path)
+                .context(ErrorKind::File(Path::new(path).display().to_string()))?;
+            Some(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+        }
+        None => None,
+    };
 
-    Ok(settings)
+    Ok(password)
 }
 
 fn create_app() -> App<'static, 'static> {
-    App::new(crate_name!())
-        .author(crate_authors!("\n"))
+    let app = App::new(crate_name!())
+        .author(crate_authors!())
         .version(crate_version!())
         .about(crate_description!())
         .arg(
@@ -39,6 +107,72 @@ fn create_app() -> App<'static, 'static> {
                 .long("config")
                 .value_name("FILE")
                 .help("Sets proxy configuration file")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
+        .subcommand(
+            SubCommand::with_name("probe")
+                .about("Sends one request through a configured service and prints diagnostics")
+                .arg(
+                    Arg::with_name("service")
+                        .help("Name of the service to probe")
+                        .required(true),
+                )
+                .arg(Arg::with_name("path").help("Request path and query, defaults to /")),
+        );
+
+    let cert = SubCommand::with_name("cert")
+        .about("Generates a development CA with server and client certificates")
+        .arg(
+            Arg::with_name("out")
+                .long("out")
+                .value_name("DIR")
+                .help("Sets output directory, an existing CA in it is reused")
+                .default_value("certs"),
+        )
+        .arg(
+            Arg::with_name("days")
+                .long("days")
+                .value_name("DAYS")
+                .help("Sets certificates validity period")
+                .default_value("365"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .value_name("NAME")
+                .help("Generates a server certificate for the given host name")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("san")
+                .long("san")
+                .value_name("NAME")
+                .help("Adds a DNS name or IP address to server certificates")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("client")
+                .long("client")
+                .value_name("NAME")
+                .help("Generates a client certificate with the given common name")
+                .multiple(true)
+                .number_of_values(1),
+        );
+
+    // PKCS #12 archives are written with OpenSSL
+    #[cfg(feature = "openssl")]
+    let cert = cert.arg(
+        Arg::with_name("pkcs12-password-file")
+            .long("pkcs12-password-file")
+            .value_name("FILE")
+            .help(
+                "Also writes server and client certificates as PKCS#12 archives \
+                 protected by the password read from the given file",
+            ),
+    );
+
+    app.subcommand(cert)
 }
@@ -0,0 +1,835 @@
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use failure::{Compat, Fail, ResultExt};
+use futures::future::{self, join_all, Either, ExecuteError, Executor};
+use futures::sync::oneshot::{self, Receiver, Sender};
+use futures::{Future, Poll, Stream};
+use hyper::service::make_service_fn;
+use hyper::Server;
+use log::{info, warn};
+use tokio::net::TcpListener;
+use url::Url;
+
+use crate::api::ApiService;
+use crate::expiry::ExpiryMonitor;
+use crate::proxy::{
+    get_config, load_crls, trust_anchors, Client, Crls, HttpsConnector, HyperHttpClient,
+    ProxyService, SharedToken, StaticCredentials, TlsReloader,
+};
+use crate::supervisor::{
+    self, supervise, wait_for_dependencies, ServiceStatus, Serving, Shutdown, Started, Status,
+};
+use crate::tls::{self, Acceptor, ClientIdentity, ServerIdentity, TlsStream};
+use crate::workload::{CertificateRenewal, TrustBundle, WorkloadClient};
+use crate::{
+    ApiSettings, Error, ErrorKind, ExpirySettings, ServiceSettings, Settings, StartupSettings,
+    TokenSourceSettings, WorkloadSettings,
+};
+
+pub type ServerFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+const API: &str = "api";
+
+#[derive(Clone, Default)]
+pub struct ProxyBuilder {
+    services: Vec<ServiceSettings>,
+    api: Option<ApiSettings>,
+    startup: StartupSettings,
+    expiry: ExpirySettings,
+    workload: Option<WorkloadSettings>,
+}
+
+impl ProxyBuilder {
+    pub fn new() -> Self {
+        ProxyBuilder::default()
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        ProxyBuilder {
+            services: settings.services().clone(),
+            api: settings.api().cloned(),
+            startup: settings.startup().clone(),
+            expiry: settings.expiry().clone(),
+            workload: settings.workload().cloned(),
+        }
+    }
+
+    pub fn service(&mut self, settings: ServiceSettings) -> &mut Self {
+        self.services.push(settings);
+        self
+    }
+
+    pub fn api(&mut self, settings: ApiSettings) -> &mut Self {
+        self.api = Some(settings);
+        self
+    }
+
+    pub fn startup(&mut self, settings: StartupSettings) -> &mut Self {
+        self.startup = settings;
+        self
+    }
+
+    pub fn expiry(&mut self, settings: ExpirySettings) -> &mut Self {
+        self.expiry = settings;
+        self
+    }
+
+    pub fn workload(&mut self, settings: WorkloadSettings) -> &mut Self {
+        self.workload = Some(settings);
+        self
+    }
+
+    pub fn build(&self) -> Result<Proxy, Error> {
+        if let Some(workload) = &self.workload {
+            workload.validate()?;
+        }
+
+        for settings in &self.services {
+            settings.validate()?;
+            settings.validate_workload(self.workload.as_ref())?;
+        }
+
+        Ok(Proxy {
+            services: self.services.clone(),
+            api: self.api.clone(),
+            startup: self.startup.clone(),
+            expiry: self.expiry.clone(),
+            workload: self.workload.clone(),
+        })
+    }
+}
+
+pub struct Proxy {
+    services: Vec<ServiceSettings>,
+    api: Option<ApiSettings>,
+    startup: StartupSettings,
+    expiry: ExpirySettings,
+    workload: Option<WorkloadSettings>,
+}
+
+impl Proxy {
+    /// Binds all entrypoints and spawns supervised servers and the
+    /// connections they accept on the given executor. Servers which fail to
+    /// start are retried in the background.
+    pub fn spawn<E>(self, executor: &E) -> Result<ProxyHandle, Error>
+    where
+        E: Executor<ServerFuture> + Clone + Send + Sync + 'static,
+    {
+        let executor = SharedExecutor(Arc::new(executor.clone()));
+
+        if self.services.is_empty() {
+            warn!("No proxy services specified in config file");
+        }
+
+        let status = Status::default();
+        let mut supervisors: Vec<ServerFuture> = Vec::new();
+        let mut senders = Vec::new();
+
+        for settings in self.services {
+            let (tx, rx) = oneshot::channel();
+            senders.push(tx);
+
+            let supervisor = supervise_proxy(
+                settings,
+                &self.startup,
+                &self.expiry,
+                self.workload.as_ref(),
+                &executor,
+                status.clone(),
+                rx.shared(),
+            );
+            supervisors.push(supervisor);
+        }
+
+        let api_status = Status::default();
+        if let Some(settings) = self.api {
+            let (tx, rx) = oneshot::channel();
+            senders.push(tx);
+
+            let new_service = ApiService::new(status.clone());
+            let executor = executor.clone();
+            let supervisor = supervise(
+                API.to_owned(),
+                api_status.clone(),
+                rx.shared(),
+                move |shutdown| start_api(&settings, new_service.clone(), &executor, shutdown),
+            );
+            supervisors.push(Box::new(supervisor));
+        }
+
+        let (completed_tx, completed_rx) = oneshot::channel();
+        let task = join_all(supervisors).then(|_| {
+            completed_tx.send(()).unwrap_or(());
+            Ok(())
+        });
+
+        executor
+            .execute(Box::new(task))
+            .map_err(|_| ErrorKind::Tokio)?;
+
+        Ok(ProxyHandle {
+            status,
+            api_status,
+            shutdown: ShutdownHandle(Arc::new(Mutex::new(senders))),
+            completed: completed_rx,
+        })
+    }
+}
+
+pub struct ProxyHandle {
+    status: Status,
+    api_status: Status,
+    shutdown: ShutdownHandle,
+    completed: Receiver<()>,
+}
+
+impl ProxyHandle {
+    pub fn local_addr(&self, service: &str) -> Option<SocketAddr> {
+        self.status
+            .service(service)
+            .and_then(|status| status.address())
+    }
+
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.api_status
+            .service(API)
+            .and_then(|status| status.address())
+    }
+
+    pub fn services(&self) -> Vec<ServiceStatus> {
+        self.status.services()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+}
+
+impl Future for ProxyHandle {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.completed
+            .poll()
+            .map_err(|_| Error::from(ErrorKind::Tokio))
+    }
+}
+
+/// Executor connections are spawned on by hyper, which needs one accepting
+/// any future rather than only boxed ones.
+#[derive(Clone)]
+struct SharedExecutor(Arc<dyn Executor<ServerFuture> + Send + Sync>);
+
+impl<F> Executor<F> for SharedExecutor
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    fn execute(&self, future: F) -> Result<(), ExecuteError<F>> {
+        // a failed execution hands back only the boxed future, so the future
+        // is kept aside until it is first polled to be able to return it
+        let slot = Arc::new(Mutex::new(Some(future)));
+        let task = {
+            let slot = slot.clone();
+            future::lazy(move || slot.lock().expect("executor lock poisoned").take().unwrap())
+        };
+
+        self.0.execute(Box::new(task)).map_err(|err| {
+            let future = slot.lock().expect("executor lock poisoned").take().unwrap();
+            ExecuteError::new(err.kind(), future)
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Mutex<Vec<Sender<()>>>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let mut senders = self.0.lock().expect("shutdown lock poisoned");
+        for tx in senders.drain(..) {
+            tx.send(()).unwrap_or(())
+        }
+    }
+}
+
+fn supervise_proxy(
+    settings: ServiceSettings,
+    startup: &StartupSettings,
+    expiry: &ExpirySettings,
+    workload: Option<&WorkloadSettings>,
+    executor: &SharedExecutor,
+    status: Status,
+    shutdown: Shutdown,
+) -> ServerFuture {
+    let name = settings.name().to_owned();
+    let workload = workload.map(WorkloadClient::new);
+    let start = {
+        let settings = settings.clone();
+        let expiry = expiry.clone();
+        let workload = workload.clone();
+        let executor = executor.clone();
+        let status = status.clone();
+        move |shutdown| {
+            start_proxy(
+                &settings,
+                &expiry,
+                workload.as_ref(),
+                &executor,
+                &status,
+                shutdown,
+            )
+        }
+    };
+
+    if !startup.wait_for_dependencies() {
+        let supervisor = supervise(name, status, shutdown.clone(), start);
+        return Box::new(supervisor);
+    }
+
+    let check = {
+        let settings = settings.clone();
+        move || check_files(&settings)
+    };
+    let wait = wait_for_dependencies(
+        name.clone(),
+        status.clone(),
+        shutdown.clone(),
+        startup.timeout(),
+        check,
+    );
+
+    let supervisor = wait.and_then(move |stopped| {
+        if stopped {
+            return Either::A(future::ok(()));
+        }
+
+        Either::B(supervise(name, status, shutdown, start))
+    });
+
+    Box::new(supervisor)
+}
+
+/// Checks that the token and certificate files of a service exist and parse,
+/// without running token commands or building clients as `get_config` does.
+fn check_files(settings: &ServiceSettings) -> Result<(), Error> {
+    check_token_files(&settings.token_source())?;
+    trust_anchors(settings)?;
+    load_crls(settings)?;
+    StaticCredentials::new(settings)?;
+
+    if settings.entrypoint().scheme() == "https" && settings.workload_certificate().is_none() {
+        ServerIdentity::load(settings)?;
+    }
+
+    Ok(())
+}
+
+/// Checks the files a token source reads. A chain needs only one of its
+/// sources to be available.
+fn check_token_files(source: &TokenSourceSettings) -> Result<(), Error> {
+    let path = match source {
+        TokenSourceSettings::File(path) => path.as_path(),
+        TokenSourceSettings::OAuth2(oauth) => oauth.client_secret(),
+        TokenSourceSettings::Sas(sas) => sas.key(),
+        TokenSourceSettings::Chain(sources) => {
+            let mut result = Ok(());
+            for source in sources {
+                result = check_token_files(source);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            return result;
+        }
+        _ => return Ok(()),
+    };
+
+    fs::File::open(path).context(ErrorKind::File(path.display().to_string()))?;
+    Ok(())
+}
+
+fn resolve(url: &Url) -> Result<SocketAddr, Error> {
+    url.to_socket_addrs()
+        .map_err(|err| Error::from(err.context(ErrorKind::InvalidUrl(url.to_string()))))?
+        .next()
+        .ok_or_else(|| {
+            let err =
+                ErrorKind::InvalidUrlWithReason(url.to_string(), "URL has no address".to_string());
+            Error::from(err)
+        })
+}
+
+fn start_api(
+    settings: &ApiSettings,
+    new_service: ApiService,
+    executor: &SharedExecutor,
+    shutdown: Shutdown,
+) -> Result<Started, Error> {
+    info!("Starting api server {}", settings.entrypoint());
+
+    let addr = resolve(settings.entrypoint())?;
+
+    let server = Server::try_bind(&addr)?
+        .executor(executor.clone())
+        .serve(new_service);
+    let addr = server.local_addr();
+    let server = server
+        .with_graceful_shutdown(shutdown.map(|_| ()))
+        .map_err(Error::from);
+
+    info!("Listening on {} with 1 thread for api", addr);
+
+    Ok((addr, Box::new(server), Box::new(future::ok(()))))
+}
+
+fn start_proxy(
+    settings: &ServiceSettings,
+    expiry: &ExpirySettings,
+    workload: Option<&WorkloadClient>,
+    executor: &SharedExecutor,
+    status: &Status,
+    shutdown: Shutdown,
+) -> Result<Started, Error> {
+    info!(
+        "Starting proxy server {} {}",
+        settings.name(),
+        settings.entrypoint()
+    );
+
+    let addr = resolve(settings.entrypoint())?;
+    let config = get_config(settings, status, workload)?;
+    let monitor = Arc::new(ExpiryMonitor::new(
+        settings.clone(),
+        config.tls(),
+        status.clone(),
+        expiry,
+    ));
+    monitor.check()?;
+    let config = config.with_verifier(monitor.clone());
+    let reloader = TlsReloader::new(settings.clone(), config.tls().clone());
+    let crls = config.tls().crls().clone();
+    let bundle = workload
+        .filter(|_| settings.workload_trust_bundle())
+        .map(|client| TrustBundle::new(client.clone(), settings, config.tls()));
+    let client = Client::new(config);
+    let new_service = ProxyService::new(client);
+
+    // requests are forwarded only once the backend can be verified
+    let ready: Box<dyn Future<Item = (), Error = Error> + Send> = match &bundle {
+        Some(bundle) => {
+            let monitor = monitor.clone();
+            Box::new(bundle.refresh().and_then(move |_| monitor.check()))
+        }
+        None => Box::new(future::ok(())),
+    };
+
+    let (addr, server, serving): Started = match settings.entrypoint().scheme() {
+        "https" => serve_tls(
+            settings,
+            workload,
+            &crls,
+            addr,
+            new_service,
+            executor,
+            ready,
+            shutdown,
+        )?,
+        _ => {
+            let server = Server::try_bind(&addr)?
+                .executor(executor.clone())
+                .serve(new_service);
+            let addr = server.local_addr();
+            let server = server
+                .with_graceful_shutdown(shutdown.clone().map(|_| ()))
+                .map_err(Error::from);
+            let serve =
+                move |_| -> Result<supervisor::ServerFuture, Error> { Ok(Box::new(server)) };
+            let (server, serving) = serve_when(ready, shutdown, serve);
+            (addr, server, serving)
+        }
+    };
+
+    let mut watchers: Vec<Box<dyn Future<Item = (), Error = Error> + Send>> =
+        vec![Box::new(reloader.watch()), Box::new(monitor.watch())];
+    if let Some(bundle) = bundle {
+        watchers.push(Box::new(bundle.watch()));
+    }
+    let watchers = future::select_all(watchers)
+        .map(|_| ())
+        .map_err(|(err, _, _)| err);
+    let server = server.select(watchers).map(|_| ()).map_err(|(err, _)| err);
+
+    info!(
+        "Listening on {} with 1 thread for {}",
+        addr,
+        settings.name()
+    );
+
+    Ok((addr, Box::new(server), serving))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve_tls(
+    settings: &ServiceSettings,
+    workload: Option<&WorkloadClient>,
+    crls: &Crls,
+    addr: SocketAddr,
+    new_service: ProxyService<SharedToken, HyperHttpClient<HttpsConnector>>,
+    executor: &SharedExecutor,
+    ready: Box<dyn Future<Item = (), Error = Error> + Send>,
+    shutdown: Shutdown,
+) -> Result<Started, Error> {
+    let new_service = match settings.client_auth() {
+        Some(client_auth) => new_service.with_client_auth(client_auth.clone()),
+        None => new_service,
+    };
+
+    let renewal = workload
+        .filter(|_| settings.workload_certificate().is_some())
+        .map(|client| CertificateRenewal::new(client.clone(), settings, crls));
+    let identity: Box<dyn Future<Item = ServerIdentity, Error = Error> + Send> = match &renewal {
+        Some(renewal) => Box::new(renewal.fetch()),
+        None => Box::new(future::ok(ServerIdentity::load(settings)?)),
+    };
+
+    let listener = TcpListener::bind(&addr).context(ErrorKind::Io)?;
+    let addr = listener.local_addr().context(ErrorKind::Io)?;
+    let settings = settings.clone();
+    let crls = crls.clone();
+    let executor = executor.clone();
+    let stopped = shutdown.clone();
+    let serve = move |identity: ServerIdentity| -> Result<supervisor::ServerFuture, Error> {
+        let acceptor = Acceptor::new(tls::acceptor(&settings, &identity, &crls)?);
+        let incoming = tls::incoming(listener, acceptor.clone());
+        let make_service = make_service_fn(move |stream: &TlsStream| {
+            let identity = ClientIdentity::from_stream(stream);
+            future::ok::<_, Compat<Error>>(new_service.with_identity(identity))
+        });
+        let server = Server::builder(incoming.map_err(Fail::compat))
+            .executor(executor)
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown.map(|_| ()))
+            .map_err(Error::from);
+
+        match renewal {
+            Some(renewal) => {
+                let watch = renewal.watch(&identity, acceptor);
+                Ok(Box::new(
+                    server.select(watch).map_err(|(err, _)| err).map(|_| ()),
+                ))
+            }
+            None => Ok(Box::new(server)),
+        }
+    };
+
+    let identity = ready.join(identity).map(|(_, identity)| identity);
+    let (server, serving) = serve_when(Box::new(identity), stopped, serve);
+    Ok((addr, server, serving))
+}
+
+/// Starts serving once `ready` resolves, unless shutdown is requested first.
+/// Listeners are bound beforehand, so connections queue up in the meantime.
+fn serve_when<T, F>(
+    ready: Box<dyn Future<Item = T, Error = Error> + Send>,
+    shutdown: Shutdown,
+    serve: F,
+) -> (supervisor::ServerFuture, Serving)
+where
+    T: Send + 'static,
+    F: FnOnce(T) -> Result<supervisor::ServerFuture, Error> + Send + 'static,
+{
+    let (serving_tx, serving_rx) = oneshot::channel();
+    let server = ready.select2(shutdown).then(move |result| match result {
+        Ok(Either::A((ready, _))) => {
+            let server = serve(ready).inspect(|_| serving_tx.send(()).unwrap_or(()));
+            Either::A(future::result(server).flatten())
+        }
+        Ok(Either::B(_)) | Err(Either::B(_)) => Either::B(future::ok(())),
+        Err(Either::A((err, _))) => Either::B(future::err(err)),
+    });
+
+    (Box::new(server), Box::new(serving_rx.map_err(|_| ())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ServerFuture;
+    use futures::future::{ExecuteError, Executor};
+    use hyper::{Client, StatusCode};
+    use openssl::ssl::{SslConnector, SslMethod};
+    use tempfile::TempDir;
+    use tokio::runtime::{Runtime, TaskExecutor};
+    use url::Url;
+
+    use crate::cert::{display_name, CertGenerator, CertKind};
+    use crate::workload::mock::MockWorkload;
+    use crate::{
+        ApiSettings, CommandTokenSettings, ErrorKind, ProxyBuilder, ServiceSettings, ServiceState,
+        StartupSettings, TokenSourceSettings, WorkloadCertificateSettings,
+    };
+
+    #[test]
+    fn it_reports_bound_addresses_and_shuts_down() {
+        let dir = TempDir::new().unwrap();
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+
+        let proxy = ProxyBuilder::new()
+            .service(ServiceSettings::new(
+                "management".to_owned(),
+                Url::parse("http://127.0.0.1:0").unwrap(),
+                Url::parse("https://iotedged:35000").unwrap(),
+                None,
+                &token,
+            ))
+            .api(ApiSettings::new(Url::parse("http://127.0.0.1:0").unwrap()))
+            .build()
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let handle = proxy.spawn(&runtime.executor()).unwrap();
+
+        assert_ne!(handle.local_addr("management").unwrap().port(), 0);
+        assert!(handle.local_addr("unknown").is_none());
+
+        let uri = format!("http://{}/health", handle.api_addr().unwrap());
+        let res = runtime
+            .block_on(Client::new().get(uri.parse().unwrap()))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        handle.shutdown();
+        runtime.block_on(handle).unwrap();
+    }
+
+    #[derive(Clone)]
+    struct CountingExecutor(TaskExecutor, Arc<AtomicUsize>);
+
+    impl Executor<ServerFuture> for CountingExecutor {
+        fn execute(&self, future: ServerFuture) -> Result<(), ExecuteError<ServerFuture>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.execute(future)
+        }
+    }
+
+    #[test]
+    fn it_spawns_connections_on_supplied_executor() {
+        let proxy = ProxyBuilder::new()
+            .api(ApiSettings::new(Url::parse("http://127.0.0.1:0").unwrap()))
+            .build()
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let executor = CountingExecutor(runtime.executor(), spawned.clone());
+        let handle = proxy.spawn(&executor).unwrap();
+        let supervisors = spawned.load(Ordering::SeqCst);
+
+        let uri = format!("http://{}/health", handle.api_addr().unwrap());
+        let res = runtime
+            .block_on(Client::new().get(uri.parse().unwrap()))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(spawned.load(Ordering::SeqCst) > supervisors);
+
+        handle.shutdown();
+        runtime.block_on(handle).unwrap();
+    }
+
+    #[test]
+    fn it_waits_for_dependencies_and_reports_not_ready() {
+        let dir = TempDir::new().unwrap();
+        let token = dir.path().join("token");
+
+        let proxy = ProxyBuilder::new()
+            .service(ServiceSettings::new(
+                "management".to_owned(),
+                Url::parse("http://127.0.0.1:0").unwrap(),
+                Url::parse("https://iotedged:35000").unwrap(),
+                None,
+                &token,
+            ))
+            .api(ApiSettings::new(Url::parse("http://127.0.0.1:0").unwrap()))
+            .startup(StartupSettings::new(true, Duration::from_secs(60)))
+            .build()
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let handle = proxy.spawn(&runtime.executor()).unwrap();
+
+        let uri = format!("http://{}/ready", handle.api_addr().unwrap());
+        let res = runtime
+            .block_on(Client::new().get(uri.parse().unwrap()))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(handle.services()[0].state(), ServiceState::Waiting);
+
+        fs::write(&token, "token").unwrap();
+        while handle.services()[0].state() != ServiceState::Running {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let res = runtime
+            .block_on(Client::new().get(uri.parse().unwrap()))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        handle.shutdown();
+        runtime.block_on(handle).unwrap();
+    }
+
+    #[test]
+    fn it_checks_only_files_while_waiting() {
+        let dir = TempDir::new().unwrap();
+        let ca = dir.path().join("ca.pem");
+        let runs = dir.path().join("runs");
+        let script = format!("echo run >> {}; echo token", runs.display());
+
+        let proxy = ProxyBuilder::new()
+            .service(
+                ServiceSettings::new(
+                    "management".to_owned(),
+                    Url::parse("http://127.0.0.1:0").unwrap(),
+                    Url::parse("https://iotedged:35000").unwrap(),
+                    Some(&ca),
+                    Path::new("token"),
+                )
+                .with_token_source(TokenSourceSettings::Command(
+                    CommandTokenSettings::new(
+                        "sh",
+                        vec!["-c".to_owned(), script],
+                        Duration::from_secs(300),
+                    ),
+                )),
+            )
+            .startup(StartupSettings::new(true, Duration::from_secs(60)))
+            .build()
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let handle = proxy.spawn(&runtime.executor()).unwrap();
+
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(handle.services()[0].state(), ServiceState::Waiting);
+        assert!(!runs.exists());
+
+        CertGenerator::new("ca", CertKind::Ca)
+            .generate()
+            .unwrap()
+            .write_cert(&ca)
+            .unwrap();
+        while handle.local_addr("management").is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+
+        handle.shutdown();
+        runtime.block_on(handle).unwrap();
+    }
+
+    #[test]
+    fn it_fails_to_build_with_unsupported_backend() {
+        let err = ProxyBuilder::new()
+            .service(ServiceSettings::new(
+                "management".to_owned(),
+                Url::parse("http://127.0.0.1:0").unwrap(),
+                Url::parse("http://iotedged:35000").unwrap(),
+                None,
+                Path::new("token"),
+            ))
+            .build()
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::UnsupportedSchema("http://iotedged:35000/".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_fails_to_build_workload_certificate_without_workload() {
+        let err = ProxyBuilder::new()
+            .service(
+                ServiceSettings::new(
+                    "management".to_owned(),
+                    Url::parse("https://127.0.0.1:0").unwrap(),
+                    Url::parse("https://iotedged:35000").unwrap(),
+                    None,
+                    Path::new("token"),
+                )
+                .with_workload_certificate(WorkloadCertificateSettings::default()),
+            )
+            .build()
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "workload_certificate of management requires workload settings".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_serves_certificate_issued_by_workload() {
+        let dir = TempDir::new().unwrap();
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+        let workload = MockWorkload::start();
+
+        let proxy = ProxyBuilder::new()
+            .service(
+                ServiceSettings::new(
+                    "management".to_owned(),
+                    Url::parse("https://127.0.0.1:0").unwrap(),
+                    Url::parse("https://iotedged:35000").unwrap(),
+                    None,
+                    &token,
+                )
+                .with_workload_certificate(WorkloadCertificateSettings::new(
+                    Some("localhost".to_owned()),
+                    1,
+                )),
+            )
+            .workload(workload.settings().clone())
+            .build()
+            .unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let handle = proxy.spawn(&runtime.executor()).unwrap();
+        let addr = handle.local_addr("management").unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector
+            .cert_store_mut()
+            .add_cert(workload.ca().cert().clone())
+            .unwrap();
+        let tcp = TcpStream::connect(addr).unwrap();
+        let stream = connector.build().connect("localhost", tcp).unwrap();
+
+        let cert = stream.ssl().peer_certificate().unwrap();
+        assert_eq!(display_name(cert.subject_name()), "CN=localhost");
+        assert_eq!(workload.requests().len(), 1);
+        drop(stream);
+
+        handle.shutdown();
+        runtime.block_on(handle).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! Development certificates of the `cert` subcommand. With OpenSSL they have
+//! RSA keys and can also be written as PKCS #12 archives, otherwise ring
+//! generates ECDSA P-256 keys.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use log::info;
+
+use crate::{Error, ErrorKind};
+
+#[cfg(any(test, feature = "openssl"))]
+mod openssl;
+#[cfg(not(feature = "openssl"))]
+mod ring;
+
+#[cfg(any(test, feature = "openssl"))]
+pub use self::openssl::{display_name, CertGenerator, Certificate};
+
+#[cfg(feature = "openssl")]
+use self::openssl as generator;
+#[cfg(not(feature = "openssl"))]
+use self::ring as generator;
+
+const KEY_MODE: u32 = 0o600;
+const CERT_MODE: u32 = 0o644;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CertKind {
+    Ca,
+    Server,
+    Client,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CertSettings {
+    pub out: PathBuf,
+    pub days: u32,
+    pub servers: Vec<String>,
+    pub sans: Vec<String>,
+    pub clients: Vec<String>,
+    #[cfg(feature = "openssl")]
+    pub pkcs12_password: Option<String>,
+}
+
+pub fn generate(settings: &CertSettings) -> Result<(), Error> {
+    validate_names(settings)?;
+
+    fs::create_dir_all(&settings.out)
+        .context(ErrorKind::File(settings.out.display().to_string()))?;
+
+    let ca_cert = settings.out.join("ca.pem");
+    let ca_key = settings.out.join("ca.key.pem");
+
+    let ca = if ca_cert.exists() && ca_key.exists() {
+        info!("Using existing CA {}", ca_cert.display());
+        generator::Certificate::load(&ca_cert, &ca_key)?
+    } else {
+        let ca = generator::CertGenerator::new("edge-proxy development CA", CertKind::Ca)
+            .days(settings.days)
+            .generate()?;
+        ca.write_cert(&ca_cert)?;
+        ca.write_key(&ca_key)?;
+        info!("Generated CA {}", ca_cert.display());
+        ca
+    };
+
+    let servers = settings.servers.iter().map(|name| (name, CertKind::Server));
+    let clients = settings.clients.iter().map(|name| (name, CertKind::Client));
+
+    for (name, kind) in servers.chain(clients) {
+        let mut certificate = generator::CertGenerator::new(name, kind);
+        certificate.days(settings.days).issuer(&ca);
+        if kind == CertKind::Server {
+            for san in &settings.sans {
+                certificate.san(san);
+            }
+        }
+
+        let cert = certificate.generate()?;
+
+        let cert_path = settings.out.join(format!("{}.pem", name));
+        cert.write_cert(&cert_path)?;
+        cert.write_key(&settings.out.join(format!("{}.key.pem", name)))?;
+
+        #[cfg(feature = "openssl")]
+        if let Some(password) = &settings.pkcs12_password {
+            cert.write_pkcs12(&settings.out.join(format!("{}.p12", name)), name, password)?;
+        }
+
+        info!("Generated {:?} certificate {}", kind, cert_path.display());
+    }
+
+    Ok(())
+}
+
+fn validate_names(settings: &CertSettings) -> Result<(), Error> {
+    let mut seen = vec!["ca"];
+    for name in settings.servers.iter().chain(&settings.clients) {
+        let plain = Path::new(name).file_name().and_then(|file| file.to_str()) == Some(name)
+            && !name.contains('\\');
+        if !plain || seen.contains(&name.as_str()) {
+            return Err(Error::from(ErrorKind::InvalidArgument(name.clone())));
+        }
+        seen.push(name);
+    }
+
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let content = fs::read(path).context(ErrorKind::File(path.display().to_string()))?;
+    Ok(content)
+}
+
+fn write(path: &Path, content: &[u8], mode: u32) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .context(ErrorKind::File(path.display().to_string()))?;
+
+    // mode is only applied on creation, so tighten permissions of existing files too
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .context(ErrorKind::File(path.display().to_string()))?;
+
+    file.write_all(content)
+        .context(ErrorKind::File(path.display().to_string()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[cfg(feature = "openssl")]
+    use openssl::pkcs12::Pkcs12;
+    use openssl::x509::X509;
+    use tempfile::TempDir;
+
+    use crate::cert::{generate, CertSettings};
+    use crate::ErrorKind;
+
+    #[test]
+    fn it_writes_files_with_safe_permissions() {
+        let dir = TempDir::new().unwrap();
+        let settings = CertSettings {
+            out: dir.path().to_path_buf(),
+            days: 30,
+            servers: vec!["iotedged".to_owned()],
+            clients: vec!["module".to_owned()],
+            ..CertSettings::default()
+        };
+
+        generate(&settings).unwrap();
+
+        let mode = |name: &str| {
+            let metadata = fs::metadata(dir.path().join(name)).unwrap();
+            metadata.permissions().mode() & 0o777
+        };
+        assert_eq!(mode("ca.pem"), 0o644);
+        assert_eq!(mode("ca.key.pem"), 0o600);
+        assert_eq!(mode("iotedged.pem"), 0o644);
+        assert_eq!(mode("iotedged.key.pem"), 0o600);
+
+        let read = |name: &str| X509::from_pem(&fs::read(dir.path().join(name)).unwrap()).unwrap();
+        let ca = read("ca.pem").public_key().unwrap();
+        assert!(read("iotedged.pem").verify(&ca).unwrap());
+        assert!(read("module.pem").verify(&ca).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "openssl")]
+    fn it_writes_pkcs12_archives() {
+        let dir = TempDir::new().unwrap();
+        let settings = CertSettings {
+            out: dir.path().to_path_buf(),
+            days: 30,
+            clients: vec!["module".to_owned()],
+            pkcs12_password: Some("secret".to_owned()),
+            ..CertSettings::default()
+        };
+
+        generate(&settings).unwrap();
+
+        let metadata = fs::metadata(dir.path().join("module.p12")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let ca = X509::from_pem(&fs::read(dir.path().join("ca.pem")).unwrap()).unwrap();
+        let client = fs::read(dir.path().join("module.p12")).unwrap();
+        let client = Pkcs12::from_der(&client).unwrap().parse2("secret").unwrap();
+        let client = client.cert.unwrap();
+        assert!(client.verify(&ca.public_key().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn it_reuses_existing_ca() {
+        let dir = TempDir::new().unwrap();
+        let settings = CertSettings {
+            out: dir.path().to_path_buf(),
+            days: 30,
+            ..CertSettings::default()
+        };
+
+        generate(&settings).unwrap();
+        let first = fs::read(dir.path().join("ca.pem")).unwrap();
+        generate(&settings).unwrap();
+        let second = fs::read(dir.path().join("ca.pem")).unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn it_rejects_reserved_or_nested_names() {
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+
+        for name in &[
+            "ca",
+            "",
+            ".",
+            "..",
+            "../escape",
+            "nested/module",
+            "module\\x",
+        ] {
+            let settings = CertSettings {
+                out: out.clone(),
+                days: 30,
+                clients: vec![(*name).to_owned()],
+                ..CertSettings::default()
+            };
+
+            let err = generate(&settings).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidArgument((*name).to_owned()));
+        }
+
+        let settings = CertSettings {
+            out: out.clone(),
+            days: 30,
+            servers: vec!["module".to_owned()],
+            clients: vec!["module".to_owned()],
+            ..CertSettings::default()
+        };
+        let err = generate(&settings).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidArgument("module".to_owned()));
+
+        assert!(!out.exists());
+        assert!(!dir.path().join("escape.pem").exists());
+    }
+}
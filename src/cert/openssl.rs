@@ -0,0 +1,239 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::stack::Stack;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Name, X509NameRef, X509};
+
+use crate::cert::{read, write, CertKind, CERT_MODE, KEY_MODE};
+use crate::Error;
+
+pub struct CertGenerator {
+    common_name: String,
+    kind: CertKind,
+    sans: Vec<String>,
+    days: u32,
+    issuer: Option<Certificate>,
+}
+
+impl CertGenerator {
+    pub fn new(common_name: &str, kind: CertKind) -> Self {
+        CertGenerator {
+            common_name: common_name.to_owned(),
+            kind,
+            sans: Vec::new(),
+            days: 365,
+            issuer: None,
+        }
+    }
+
+    pub fn san(&mut self, name: &str) -> &mut Self {
+        self.sans.push(name.to_owned());
+        self
+    }
+
+    pub fn days(&mut self, days: u32) -> &mut Self {
+        self.days = days;
+        self
+    }
+
+    pub fn issuer(&mut self, issuer: &Certificate) -> &mut Self {
+        self.issuer = Some(issuer.clone());
+        self
+    }
+
+    pub fn generate(&self) -> Result<Certificate, Error> {
+        let rsa = Rsa::generate(2048)?;
+        let key = PKey::from_rsa(rsa)?;
+
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &self.common_name)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(Asn1Integer::from_bn(&serial)?.as_ref())?;
+        builder.set_subject_name(&name)?;
+        builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+        builder.set_not_after(Asn1Time::days_from_now(self.days)?.as_ref())?;
+        builder.set_pubkey(&key)?;
+
+        let issuer = self.issuer.as_ref();
+        builder.set_issuer_name(issuer.map_or(&name, |issuer| issuer.cert.subject_name()))?;
+
+        match self.kind {
+            CertKind::Ca => {
+                let basic_constraints = BasicConstraints::new().critical().ca().build()?;
+                builder.append_extension(basic_constraints)?;
+                let key_usage = KeyUsage::new()
+                    .critical()
+                    .key_cert_sign()
+                    .crl_sign()
+                    .digital_signature()
+                    .build()?;
+                builder.append_extension(key_usage)?;
+            }
+            CertKind::Server | CertKind::Client => {
+                let basic_constraints = BasicConstraints::new().critical().build()?;
+                builder.append_extension(basic_constraints)?;
+                let key_usage = KeyUsage::new()
+                    .critical()
+                    .digital_signature()
+                    .key_encipherment()
+                    .build()?;
+                builder.append_extension(key_usage)?;
+
+                let mut ext_key_usage = ExtendedKeyUsage::new();
+                if self.kind == CertKind::Server {
+                    ext_key_usage.server_auth();
+                } else {
+                    ext_key_usage.client_auth();
+                }
+                builder.append_extension(ext_key_usage.build()?)?;
+            }
+        }
+
+        if self.kind != CertKind::Ca || !self.sans.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            if self.kind == CertKind::Server {
+                add_san(&mut san, &self.common_name);
+            }
+            for name in &self.sans {
+                add_san(&mut san, name);
+            }
+            let context = builder.x509v3_context(issuer.map(|issuer| issuer.cert.as_ref()), None);
+            let san = san.build(&context)?;
+            builder.append_extension(san)?;
+        }
+
+        let subject_key_identifier = SubjectKeyIdentifier::new()
+            .build(&builder.x509v3_context(issuer.map(|issuer| issuer.cert.as_ref()), None))?;
+        builder.append_extension(subject_key_identifier)?;
+        let authority_key_identifier = AuthorityKeyIdentifier::new()
+            .keyid(true)
+            .build(&builder.x509v3_context(issuer.map(|issuer| issuer.cert.as_ref()), None))?;
+        builder.append_extension(authority_key_identifier)?;
+
+        let signing_key = issuer.map_or(&key, |issuer| &issuer.key);
+        builder.sign(signing_key, MessageDigest::sha256())?;
+
+        Ok(Certificate {
+            cert: builder.build(),
+            key,
+            chain: issuer.map(|issuer| issuer.cert.clone()),
+        })
+    }
+}
+
+fn add_san(san: &mut SubjectAlternativeName, name: &str) {
+    if name.parse::<IpAddr>().is_ok() {
+        san.ip(name);
+    } else {
+        san.dns(name);
+    }
+}
+
+#[derive(Clone)]
+pub struct Certificate {
+    cert: X509,
+    key: PKey<Private>,
+    chain: Option<X509>,
+}
+
+impl Certificate {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, Error> {
+        let cert = read(cert).and_then(|pem| Ok(X509::from_pem(&pem)?))?;
+        let key = read(key).and_then(|pem| Ok(PKey::private_key_from_pem(&pem)?))?;
+
+        Ok(Certificate {
+            cert,
+            key,
+            chain: None,
+        })
+    }
+
+    pub fn cert(&self) -> &X509 {
+        &self.cert
+    }
+
+    pub fn key(&self) -> &PKey<Private> {
+        &self.key
+    }
+
+    pub fn write_cert(&self, path: &Path) -> Result<(), Error> {
+        write(path, &self.cert.to_pem()?, CERT_MODE)
+    }
+
+    pub fn write_key(&self, path: &Path) -> Result<(), Error> {
+        write(path, &self.key.private_key_to_pem_pkcs8()?, KEY_MODE)
+    }
+
+    pub fn write_pkcs12(&self, path: &Path, name: &str, password: &str) -> Result<(), Error> {
+        write(path, &self.to_pkcs12(name, password)?, KEY_MODE)
+    }
+
+    pub fn to_pkcs12(&self, name: &str, password: &str) -> Result<Vec<u8>, Error> {
+        let mut builder = Pkcs12::builder();
+        builder.name(name).pkey(&self.key).cert(&self.cert);
+
+        if let Some(ca) = &self.chain {
+            let mut chain = Stack::new()?;
+            chain.push(ca.clone())?;
+            builder.ca(chain);
+        }
+
+        let pkcs12 = builder.build2(password)?;
+        Ok(pkcs12.to_der()?)
+    }
+}
+
+pub fn display_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = String::from_utf8_lossy(entry.data().as_slice());
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cert::{CertGenerator, CertKind};
+
+    #[test]
+    fn it_generates_certs_signed_by_ca() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let server = CertGenerator::new("iotedged", CertKind::Server)
+            .san("127.0.0.1")
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+
+        assert!(server.cert().verify(ca.key()).unwrap());
+
+        let sans = server.cert().subject_alt_names().unwrap();
+        let names: Vec<_> = sans.iter().filter_map(|name| name.dnsname()).collect();
+        assert_eq!(names, vec!["iotedged"]);
+        assert_eq!(
+            sans.iter()
+                .filter(|name| name.ipaddress().is_some())
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Certificates with ECDSA P-256 keys for builds without OpenSSL. They carry
+//! the same extensions as the ones OpenSSL generates and are DER encoded here.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, EcdsaKeyPair, KeyPair, RsaKeyPair};
+
+use crate::cert::{read, write, CertKind, CERT_MODE, KEY_MODE};
+use crate::workload::rfc3339;
+use crate::x509::{self, pem_blocks};
+use crate::{Error, ErrorKind};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+const KEY_IDENTIFIER: u8 = 0x80;
+
+const SAN_DNS: u8 = 0x82;
+const SAN_IP: u8 = 0x87;
+
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+const SUBJECT_KEY_IDENTIFIER: &[u8] = &[0x55, 0x1d, 0x0e];
+const KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const AUTHORITY_KEY_IDENTIFIER: &[u8] = &[0x55, 0x1d, 0x23];
+const EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+const CLIENT_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02];
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub struct CertGenerator {
+    common_name: String,
+    kind: CertKind,
+    sans: Vec<String>,
+    days: u32,
+    issuer: Option<Certificate>,
+}
+
+impl CertGenerator {
+    pub fn new(common_name: &str, kind: CertKind) -> Self {
+        CertGenerator {
+            common_name: common_name.to_owned(),
+            kind,
+            sans: Vec::new(),
+            days: 365,
+            issuer: None,
+        }
+    }
+
+    pub fn san(&mut self, name: &str) -> &mut Self {
+        self.sans.push(name.to_owned());
+        self
+    }
+
+    pub fn days(&mut self, days: u32) -> &mut Self {
+        self.days = days;
+        self
+    }
+
+    pub fn issuer(&mut self, issuer: &Certificate) -> &mut Self {
+        self.issuer = Some(issuer.clone());
+        self
+    }
+
+    pub fn generate(&self) -> Result<Certificate, Error> {
+        let failed = || Error::from(ErrorKind::GenerateCertificate(self.common_name.clone()));
+
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| failed())?
+            .as_ref()
+            .to_vec();
+        let key_pair = Signer::from_pkcs8(&key).ok_or_else(failed)?;
+
+        let name = sequence(&[der(
+            SET,
+            &sequence(&[
+                oid(COMMON_NAME),
+                der(UTF8_STRING, self.common_name.as_bytes()),
+            ]),
+        )]);
+
+        let issuer = match &self.issuer {
+            Some(issuer) => Some((
+                issuer.cert.subject().der(),
+                Signer::from_pkcs8(&issuer.key).ok_or_else(failed)?,
+            )),
+            None => None,
+        };
+        let (issuer_name, signer) = match &issuer {
+            Some((name, signer)) => (*name, signer),
+            None => (name.as_slice(), &key_pair),
+        };
+
+        // positive and without leading zeros
+        let mut serial = [0; 16];
+        rng.fill(&mut serial).map_err(|_| failed())?;
+        serial[0] = serial[0] & 0x7f | 0x40;
+
+        let now = SystemTime::now();
+        let not_after = now + Duration::from_secs(u64::from(self.days) * SECONDS_PER_DAY);
+
+        let public_key = key_pair.public_key();
+        let public_key_info = sequence(&[
+            sequence(&[oid(EC_PUBLIC_KEY), oid(P256)]),
+            bit_string(public_key),
+        ]);
+
+        let mut extensions = Vec::new();
+        match self.kind {
+            CertKind::Ca => {
+                extensions.push(extension(
+                    BASIC_CONSTRAINTS,
+                    true,
+                    &sequence(&[der(BOOLEAN, &[0xff])]),
+                ));
+                // digitalSignature, keyCertSign and cRLSign
+                extensions.push(extension(KEY_USAGE, true, &der(BIT_STRING, &[0x01, 0x86])));
+            }
+            CertKind::Server | CertKind::Client => {
+                extensions.push(extension(BASIC_CONSTRAINTS, true, &sequence(&[])));
+                // digitalSignature, ECDSA keys cannot encipher keys
+                extensions.push(extension(KEY_USAGE, true, &der(BIT_STRING, &[0x07, 0x80])));
+
+                let usage = if self.kind == CertKind::Server {
+                    SERVER_AUTH
+                } else {
+                    CLIENT_AUTH
+                };
+                extensions.push(extension(EXT_KEY_USAGE, false, &sequence(&[oid(usage)])));
+            }
+        }
+
+        let mut names = Vec::new();
+        if self.kind == CertKind::Server {
+            names.push(general_name(&self.common_name));
+        }
+        names.extend(self.sans.iter().map(|name| general_name(name)));
+        if !names.is_empty() {
+            extensions.push(extension(SUBJECT_ALT_NAME, false, &sequence(&names)));
+        }
+
+        extensions.push(extension(
+            SUBJECT_KEY_IDENTIFIER,
+            false,
+            &der(OCTET_STRING, &key_id(public_key)),
+        ));
+        extensions.push(extension(
+            AUTHORITY_KEY_IDENTIFIER,
+            false,
+            &sequence(&[der(KEY_IDENTIFIER, &key_id(signer.public_key()))]),
+        ));
+
+        let tbs = sequence(&[
+            der(VERSION, &integer(&[2])),
+            integer(&serial),
+            signer.algorithm(),
+            issuer_name.to_vec(),
+            sequence(&[time(now), time(not_after)]),
+            name,
+            public_key_info,
+            der(EXTENSIONS, &sequence(&extensions)),
+        ]);
+        let signature = signer.sign(&rng, &tbs).ok_or_else(failed)?;
+        let cert = sequence(&[tbs, signer.algorithm(), bit_string(&signature)]);
+
+        Ok(Certificate {
+            cert: x509::Certificate::from_der(&cert)?,
+            key,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Certificate {
+    cert: x509::Certificate,
+    key: Vec<u8>,
+}
+
+impl Certificate {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, Error> {
+        let cert = read(cert).and_then(|pem| x509::Certificate::from_pem(&pem))?;
+        let pem = read(key)?;
+        let key = pem_blocks(&String::from_utf8_lossy(&pem), "PRIVATE KEY")?
+            .into_iter()
+            .next()
+            .filter(|key| Signer::from_pkcs8(key).is_some())
+            .ok_or_else(|| ErrorKind::InvalidKey(key.display().to_string()))?;
+
+        Ok(Certificate { cert, key })
+    }
+
+    pub fn write_cert(&self, path: &Path) -> Result<(), Error> {
+        write(path, &pem("CERTIFICATE", self.cert.der()), CERT_MODE)
+    }
+
+    pub fn write_key(&self, path: &Path) -> Result<(), Error> {
+        write(path, &pem("PRIVATE KEY", &self.key), KEY_MODE)
+    }
+}
+
+/// Key certificates are signed with. RSA keys are accepted so that a CA
+/// generated by OpenSSL builds keeps issuing certificates.
+enum Signer {
+    Ecdsa(EcdsaKeyPair),
+    Rsa(RsaKeyPair),
+}
+
+impl Signer {
+    fn from_pkcs8(pkcs8: &[u8]) -> Option<Self> {
+        EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)
+            .map(Signer::Ecdsa)
+            .or_else(|_| RsaKeyPair::from_pkcs8(pkcs8).map(Signer::Rsa))
+            .ok()
+    }
+
+    fn public_key(&self) -> &[u8] {
+        match self {
+            Signer::Ecdsa(key_pair) => key_pair.public_key().as_ref(),
+            Signer::Rsa(key_pair) => key_pair.public_key().as_ref(),
+        }
+    }
+
+    fn algorithm(&self) -> Vec<u8> {
+        match self {
+            Signer::Ecdsa(_) => sequence(&[oid(ECDSA_SHA256)]),
+            Signer::Rsa(_) => sequence(&[oid(RSA_SHA256), der(NULL, &[])]),
+        }
+    }
+
+    fn sign(&self, rng: &SystemRandom, message: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Signer::Ecdsa(key_pair) => key_pair
+                .sign(rng, message)
+                .ok()
+                .map(|signature| signature.as_ref().to_vec()),
+            Signer::Rsa(key_pair) => {
+                let mut signature = vec![0; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(&signature::RSA_PKCS1_SHA256, rng, message, &mut signature)
+                    .ok()?;
+                Some(signature)
+            }
+        }
+    }
+}
+
+/// Identifies a public key by its SHA-1 hash like OpenSSL does.
+fn key_id(public_key: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, public_key)
+        .as_ref()
+        .to_vec()
+}
+
+fn extension(id: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut fields = vec![oid(id)];
+    if critical {
+        fields.push(der(BOOLEAN, &[0xff]));
+    }
+    fields.push(der(OCTET_STRING, value));
+    sequence(&fields)
+}
+
+fn general_name(name: &str) -> Vec<u8> {
+    match name.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => der(SAN_IP, &address.octets()),
+        Ok(IpAddr::V6(address)) => der(SAN_IP, &address.octets()),
+        Err(_) => der(SAN_DNS, name.as_bytes()),
+    }
+}
+
+/// UTCTime up to 2049 and GeneralizedTime afterwards, as RFC 5280 requires.
+fn time(time: SystemTime) -> Vec<u8> {
+    let digits = rfc3339(time)
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+
+    if &digits[..4] < "2050" {
+        der(UTC_TIME, format!("{}Z", &digits[2..]).as_bytes())
+    } else {
+        der(GENERALIZED_TIME, format!("{}Z", digits).as_bytes())
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> Vec<u8> {
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64::encode(der).as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem.into_bytes()
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    der(SEQUENCE, &items.concat())
+}
+
+fn oid(id: &[u8]) -> Vec<u8> {
+    der(OID, id)
+}
+
+fn integer(value: &[u8]) -> Vec<u8> {
+    if value[0] & 0x80 == 0 {
+        der(INTEGER, value)
+    } else {
+        der(INTEGER, &[&[0], value].concat())
+    }
+}
+
+fn bit_string(value: &[u8]) -> Vec<u8> {
+    der(BIT_STRING, &[&[0], value].concat())
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    if content.len() < 0x80 {
+        der.push(content.len() as u8);
+    } else {
+        let length = (content.len() as u64).to_be_bytes();
+        let skip = length.iter().take_while(|byte| **byte == 0).count();
+        der.push(0x80 | (length.len() - skip) as u8);
+        der.extend_from_slice(&length[skip..]);
+    }
+    der.extend_from_slice(content);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use openssl::x509::X509;
+    use tempfile::TempDir;
+    use webpki::trust_anchor_util::cert_der_as_trust_anchor;
+    use webpki::{DNSNameRef, EndEntityCert, TLSServerTrustAnchors, Time};
+
+    use crate::cert::ring::{pem, CertGenerator, Certificate};
+    use crate::cert::{self, CertKind};
+    use crate::tls;
+
+    #[test]
+    fn it_generates_certs_signed_by_ca() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let server = CertGenerator::new("iotedged", CertKind::Server)
+            .san("127.0.0.1")
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+
+        let anchor = cert_der_as_trust_anchor(ca.cert.der()).unwrap();
+        let cert = EndEntityCert::from(server.cert.der()).unwrap();
+        cert.verify_is_valid_tls_server_cert(
+            &[&webpki::ECDSA_P256_SHA256],
+            &TLSServerTrustAnchors(&[anchor]),
+            &[],
+            Time::try_from(SystemTime::now()).unwrap(),
+        )
+        .unwrap();
+        cert.verify_is_valid_for_dns_name(DNSNameRef::try_from_ascii_str("iotedged").unwrap())
+            .unwrap();
+
+        assert_eq!(server.cert.subject().to_string(), "CN=iotedged");
+        assert_eq!(server.cert.issuer().to_string(), "CN=ca");
+        assert_eq!(server.cert.alt_names(), ["iotedged", "127.0.0.1"]);
+        assert!(tls::private_key(&pem("PRIVATE KEY", &server.key)).is_ok());
+    }
+
+    #[test]
+    fn it_issues_certs_with_openssl_ca() {
+        let dir = TempDir::new().unwrap();
+        let ca = cert::CertGenerator::new("ca", CertKind::Ca)
+            .generate()
+            .unwrap();
+        ca.write_cert(&dir.path().join("ca.pem")).unwrap();
+        ca.write_key(&dir.path().join("ca.key.pem")).unwrap();
+
+        let issuer =
+            Certificate::load(&dir.path().join("ca.pem"), &dir.path().join("ca.key.pem")).unwrap();
+        let client = CertGenerator::new("module", CertKind::Client)
+            .issuer(&issuer)
+            .generate()
+            .unwrap();
+
+        let client = X509::from_der(client.cert.der()).unwrap();
+        assert!(client.verify(ca.key()).unwrap());
+        assert_eq!(cert::display_name(client.issuer_name()), "CN=ca");
+    }
+}
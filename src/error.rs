@@ -1,3 +1,6 @@
+// failure_derive expands to impls nested in an anonymous const
+#![allow(non_local_definitions)]
+
 use std::fmt;
 use std::fmt::Display;
 use std::io::Error as IoError;
@@ -5,7 +8,8 @@ use std::io::Error as IoError;
 use failure::{Backtrace, Context, Fail};
 use http::uri::InvalidUri;
 use hyper::Error as HyperError;
-use native_tls::Error as NativeTlsError;
+#[cfg(any(test, feature = "openssl"))]
+use openssl::error::ErrorStack;
 use url::ParseError as UrlParseError;
 
 #[derive(Debug)]
@@ -13,7 +17,7 @@ pub struct Error {
     inner: Context<ErrorKind>,
 }
 
-#[derive(Debug, Fail, PartialEq)]
+#[derive(Clone, Debug, Fail, PartialEq)]
 pub enum ErrorKind {
     #[fail(display = "Could not load settings")]
     LoadSettings,
@@ -33,8 +37,49 @@ pub enum ErrorKind {
     #[fail(display = "HTTP connection error")]
     Hyper,
 
-    #[fail(display = "A native TLS error occurred")]
-    NativeTls,
+    #[cfg(any(test, feature = "openssl"))]
+    #[fail(display = "An OpenSSL error occurred")]
+    OpenSsl,
+
+    #[cfg(feature = "rustls")]
+    #[fail(display = "Rustls error")]
+    Rustls,
+
+    #[fail(display = "Invalid client authentication settings: {}", _0)]
+    InvalidClientAuth(String),
+
+    #[fail(display = "Invalid credential settings: {}", _0)]
+    InvalidCredential(String),
+
+    #[fail(display = "Invalid TLS settings: {}", _0)]
+    InvalidTlsSettings(String),
+
+    #[fail(display = "Backend did not present a certificate")]
+    PeerCertificate,
+
+    #[fail(
+        display = "Backend public key pin {} does not match any configured pin",
+        _0
+    )]
+    PinMismatch(String),
+
+    #[fail(display = "Certificate {} has been revoked", _0)]
+    Revoked(String),
+
+    #[fail(display = "Certificate revocation list of {} has expired", _0)]
+    ExpiredCrl(String),
+
+    #[fail(display = "Invalid certificate revocation list {:?}", _0)]
+    InvalidCrl(String),
+
+    #[fail(display = "Invalid certificate: {}", _0)]
+    InvalidCertificate(String),
+
+    #[fail(display = "Invalid private key {:?}", _0)]
+    InvalidKey(String),
+
+    #[fail(display = "Could not generate certificate {:?}", _0)]
+    GenerateCertificate(String),
 
     #[fail(display = "Parse error url error")]
     Parse,
@@ -45,12 +90,33 @@ pub enum ErrorKind {
     #[fail(display = "Invalid HTTP header value {:?}", _0)]
     HeaderValue(String),
 
+    #[fail(display = "Invalid HTTP header name {:?}", _0)]
+    HeaderName(String),
+
     #[fail(display = "An IO error occurred")]
     Io,
 
     #[fail(display = "An IO error occurred {:?}", _0)]
     File(String),
 
+    #[fail(display = "No certificates found in {:?}", _0)]
+    NoCertificates(String),
+
+    #[fail(display = "Could not obtain token: {}", _0)]
+    Token(String),
+
+    #[fail(display = "No token available for {}", _0)]
+    TokenUnavailable(String),
+
+    #[fail(display = "Workload API request failed: {}", _0)]
+    Workload(String),
+
+    #[fail(display = "Service {:?} is not configured", _0)]
+    UnknownService(String),
+
+    #[fail(display = "Invalid command line argument {:?}", _0)]
+    InvalidArgument(String),
+
     #[fail(display = "Error")]
     Generic,
 }
@@ -99,10 +165,11 @@ impl From<HyperError> for Error {
     }
 }
 
-impl From<NativeTlsError> for Error {
-    fn from(error: NativeTlsError) -> Self {
+#[cfg(any(test, feature = "openssl"))]
+impl From<ErrorStack> for Error {
+    fn from(error: ErrorStack) -> Self {
         Error {
-            inner: error.context(ErrorKind::NativeTls),
+            inner: error.context(ErrorKind::OpenSsl),
         }
     }
 }
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::Fail;
+use futures::{Future, Stream};
+use log::warn;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use tokio::timer::Interval;
+
+use crate::proxy::{trust_anchors, Tls, Verifier};
+use crate::supervisor::Status;
+use crate::x509::Certificate;
+use crate::{logging, Error, ErrorKind, ExpirySettings, ServiceSettings};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CertificateSource {
+    /// The CA certificate configured to verify the backend.
+    Configured,
+    /// The CA certificate of the workload trust bundle.
+    TrustBundle,
+    /// The certificate presented by the backend during the last handshake.
+    Backend,
+}
+
+impl CertificateSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CertificateSource::Configured => "configured",
+            CertificateSource::TrustBundle => "trust_bundle",
+            CertificateSource::Backend => "backend",
+        }
+    }
+}
+
+impl fmt::Display for CertificateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateExpiry {
+    source: CertificateSource,
+    subject: String,
+    not_after: i64,
+}
+
+impl CertificateExpiry {
+    pub fn new(source: CertificateSource, cert: &Certificate) -> Self {
+        CertificateExpiry {
+            source,
+            subject: cert.subject().to_string(),
+            not_after: cert.not_after(),
+        }
+    }
+
+    pub fn source(&self) -> CertificateSource {
+        self.source
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn not_after(&self) -> i64 {
+        self.not_after
+    }
+
+    /// Whole days left, negative once the certificate has expired.
+    pub fn days_until_expiry(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+
+        (self.not_after - now).div_euclid(SECONDS_PER_DAY)
+    }
+}
+
+impl Serialize for CertificateExpiry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CertificateExpiry", 4)?;
+        state.serialize_field("source", self.source.as_str())?;
+        state.serialize_field("subject", &self.subject)?;
+        state.serialize_field("not_after", &self.not_after)?;
+        state.serialize_field("days_until_expiry", &self.days_until_expiry())?;
+        state.end()
+    }
+}
+
+/// Tracks expiration of the configured CA, of the workload trust bundle and
+/// of the certificate observed on backend connections, records it in the
+/// service status and warns once per certificate whenever a threshold is
+/// crossed.
+pub struct ExpiryMonitor {
+    settings: ServiceSettings,
+    tls: Tls,
+    status: Status,
+    thresholds: Vec<u64>,
+    warned: Mutex<HashMap<(CertificateSource, i64), i64>>,
+}
+
+impl ExpiryMonitor {
+    pub fn new(
+        settings: ServiceSettings,
+        tls: &Tls,
+        status: Status,
+        expiry: &ExpirySettings,
+    ) -> Self {
+        ExpiryMonitor {
+            settings,
+            tls: tls.clone(),
+            status,
+            thresholds: expiry.thresholds().to_vec(),
+            warned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        let configured = trust_anchors(&self.settings)?;
+        if let Some(expiry) = soonest(CertificateSource::Configured, &configured)? {
+            self.record(expiry);
+        }
+
+        let trust_bundle = self.tls.trust_bundle();
+        if let Some(expiry) = soonest(CertificateSource::TrustBundle, &trust_bundle)? {
+            self.record(expiry);
+        }
+
+        let backend = self
+            .status
+            .service(self.settings.name())
+            .and_then(|status| {
+                status
+                    .certificates()
+                    .iter()
+                    .find(|expiry| expiry.source == CertificateSource::Backend)
+                    .cloned()
+            });
+        if let Some(expiry) = backend {
+            self.warn(&expiry);
+        }
+
+        Ok(())
+    }
+
+    pub fn watch(self: Arc<Self>) -> impl Future<Item = (), Error = Error> {
+        Interval::new(Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL)
+            .map_err(|err| Error::from(err.context(ErrorKind::Tokio)))
+            .for_each(move |_| {
+                if let Err(err) = self.check() {
+                    warn!(
+                        "Could not check certificate expiry for {}",
+                        self.settings.name()
+                    );
+                    logging::failure(&err);
+                }
+
+                Ok(())
+            })
+    }
+
+    fn record(&self, expiry: CertificateExpiry) {
+        self.warn(&expiry);
+        self.status.certificate(self.settings.name(), expiry);
+    }
+
+    fn warn(&self, expiry: &CertificateExpiry) {
+        let days = expiry.days_until_expiry();
+        let level = match level(days, &self.thresholds) {
+            Some(level) => level,
+            None => return,
+        };
+
+        let mut warned = self.warned.lock().expect("expiry lock poisoned");
+        let key = (expiry.source, expiry.not_after);
+        if warned.get(&key).is_some_and(|warned| *warned <= level) {
+            return;
+        }
+        warned.insert(key, level);
+
+        if days < 0 {
+            warn!(
+                "Certificate {} ({}) of {} expired {} days ago",
+                expiry.subject,
+                expiry.source,
+                self.settings.name(),
+                -days
+            );
+        } else {
+            warn!(
+                "Certificate {} ({}) of {} expires in {} days",
+                expiry.subject,
+                expiry.source,
+                self.settings.name(),
+                days
+            );
+        }
+    }
+}
+
+/// Records the backend certificate. Expiry is only monitored, so it never
+/// rejects the connection.
+impl Verifier for ExpiryMonitor {
+    fn verify(&self, chain: &[Certificate]) -> Result<(), Error> {
+        if let Some(cert) = chain.first() {
+            self.record(CertificateExpiry::new(CertificateSource::Backend, cert));
+        }
+
+        Ok(())
+    }
+}
+
+fn soonest(
+    source: CertificateSource,
+    certs: &[Vec<u8>],
+) -> Result<Option<CertificateExpiry>, Error> {
+    let mut soonest: Option<CertificateExpiry> = None;
+    for pem in certs {
+        let cert = Certificate::from_pem(pem)?;
+        let expiry = CertificateExpiry::new(source, &cert);
+        if soonest
+            .as_ref()
+            .is_none_or(|soonest| expiry.not_after < soonest.not_after)
+        {
+            soonest = Some(expiry);
+        }
+    }
+
+    Ok(soonest)
+}
+
+/// Returns the smallest threshold the remaining days have reached, or `-1`
+/// for an expired certificate.
+fn level(days: i64, thresholds: &[u64]) -> Option<i64> {
+    if days < 0 {
+        return Some(-1);
+    }
+
+    thresholds
+        .iter()
+        .map(|threshold| *threshold as i64)
+        .filter(|threshold| days <= *threshold)
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::expiry::{level, CertificateSource, ExpiryMonitor};
+    use crate::proxy::{pem_certificates, tls_connector_with, Tls, Verifier};
+    use crate::supervisor::Status;
+    use crate::x509::Certificate;
+    use crate::{ExpirySettings, ServiceSettings};
+
+    fn settings(cert: Option<&Path>) -> ServiceSettings {
+        ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            cert,
+            Path::new("token"),
+        )
+    }
+
+    fn monitor(cert: Option<&Path>, tls: Option<&Tls>, status: &Status) -> ExpiryMonitor {
+        let settings = settings(cert);
+        let tls = tls
+            .cloned()
+            .unwrap_or_else(|| Tls::new(tls_connector_with(&settings, &[]).unwrap()));
+
+        ExpiryMonitor::new(settings, &tls, status.clone(), &ExpirySettings::default())
+    }
+
+    #[test]
+    fn it_records_soonest_configured_certificate() {
+        let dir = TempDir::new().unwrap();
+        let cert = dir.path().join("bundle.pem");
+
+        let mut bundle = Vec::new();
+        for (name, days) in &[("long", 365), ("short", 20)] {
+            let path = dir.path().join(name);
+            CertGenerator::new(name, CertKind::Ca)
+                .days(*days)
+                .generate()
+                .unwrap()
+                .write_cert(&path)
+                .unwrap();
+            bundle.extend(fs::read(&path).unwrap());
+        }
+        fs::write(&cert, bundle).unwrap();
+
+        let status = Status::default();
+        monitor(Some(&cert), None, &status).check().unwrap();
+
+        let service = status.service("management").unwrap();
+        let expiry = &service.certificates()[0];
+        assert_eq!(expiry.source(), CertificateSource::Configured);
+        assert_eq!(expiry.subject(), "CN=short");
+        assert!((19..=20).contains(&expiry.days_until_expiry()));
+    }
+
+    #[test]
+    fn it_records_trust_bundle_certificate() {
+        let ca = CertGenerator::new("edge-ca", CertKind::Ca)
+            .days(10)
+            .generate()
+            .unwrap();
+        let pem = String::from_utf8(ca.cert().to_pem().unwrap()).unwrap();
+        let tls = Tls::new(tls_connector_with(&settings(None), &[]).unwrap());
+        tls.replace_trust_bundle(pem_certificates(&pem), |certs| {
+            tls_connector_with(&settings(None), certs)
+        })
+        .unwrap();
+
+        let status = Status::default();
+        monitor(None, Some(&tls), &status).check().unwrap();
+
+        let service = status.service("management").unwrap();
+        assert_eq!(service.certificates().len(), 1);
+        let expiry = &service.certificates()[0];
+        assert_eq!(expiry.source(), CertificateSource::TrustBundle);
+        assert_eq!(expiry.subject(), "CN=edge-ca");
+        assert!((9..=10).contains(&expiry.days_until_expiry()));
+    }
+
+    #[test]
+    fn it_records_backend_certificate() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .generate()
+            .unwrap();
+
+        let status = Status::default();
+        monitor(None, None, &status)
+            .verify(&[Certificate::from_der(&cert.cert().to_der().unwrap()).unwrap()])
+            .unwrap();
+
+        let service = status.service("management").unwrap();
+        assert_eq!(service.certificates().len(), 1);
+        assert_eq!(
+            service.certificates()[0].source(),
+            CertificateSource::Backend
+        );
+        assert_eq!(service.certificates()[0].subject(), "CN=iotedged");
+    }
+
+    #[test]
+    fn it_finds_crossed_threshold() {
+        let thresholds = [30, 7, 1];
+
+        assert_eq!(level(100, &thresholds), None);
+        assert_eq!(level(30, &thresholds), Some(30));
+        assert_eq!(level(5, &thresholds), Some(7));
+        assert_eq!(level(0, &thresholds), Some(1));
+        assert_eq!(level(-1, &thresholds), Some(-1));
+        assert_eq!(level(5, &[]), None);
+    }
+}
@@ -1,5 +1,7 @@
-use edge_proxy::{app, Error, Routine};
+use edge_proxy::app::{self, Command};
+use edge_proxy::cert;
 use edge_proxy::{logging, signal};
+use edge_proxy::{Error, Probe, Routine};
 
 fn main() {
     if let Err(e) = run() {
@@ -9,10 +11,17 @@ fn main() {
 }
 
 fn run() -> Result<(), Error> {
-    let settings = app::init()?;
-
-    let main = Routine::new(settings);
-    main.run_until(signal::shutdown())?;
+    match app::init()? {
+        Command::Run(settings) => {
+            let main = Routine::new(settings);
+            main.run_until(signal::shutdown())?;
+        }
+        Command::Probe(settings, service, path) => {
+            let probe = Probe::new(settings);
+            probe.run(&service, path.as_deref())?;
+        }
+        Command::Cert(settings) => cert::generate(&settings)?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,278 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use failure::{Fail, ResultExt};
+use futures::{Future, Stream};
+use http::header::{self, HeaderName};
+use http::{HeaderMap, HeaderValue, Request};
+use hyper::Body;
+use tokio::net::TcpStream;
+use tokio::runtime::current_thread::Runtime;
+use url::Url;
+
+use crate::proxy::{get_config, rejection, spki_pin, Client, Config, SharedToken};
+use crate::supervisor::Status;
+use crate::workload::{rfc3339, WorkloadClient};
+use crate::x509::Certificate;
+use crate::{CredentialSettings, CredentialTarget, Error, ErrorKind, IncomingCredential, Settings};
+
+pub struct Probe {
+    settings: Settings,
+}
+
+impl Probe {
+    pub fn new(settings: Settings) -> Self {
+        Probe { settings }
+    }
+
+    pub fn run(&self, service: &str, path: Option<&str>) -> Result<(), Error> {
+        let settings = self
+            .settings
+            .services()
+            .iter()
+            .find(|settings| settings.name() == service)
+            .ok_or_else(|| ErrorKind::UnknownService(service.to_owned()))?;
+
+        println!(
+            "Probing service {:?} ({} -> {})",
+            settings.name(),
+            settings.entrypoint(),
+            settings.backend()
+        );
+
+        let status = Status::default();
+        let workload = self.settings.workload().map(WorkloadClient::new);
+        let config = get_config(settings, &status, workload.as_ref())?;
+        if let Some(expiry) = status
+            .service(settings.name())
+            .and_then(|status| status.token_expiry())
+        {
+            println!("Token expiry: {} (Unix time)", expiry);
+        }
+
+        let (addr, elapsed) = resolve(settings.backend())?;
+        println!("Resolved address: {} ({:?})", addr, elapsed);
+
+        if settings.backend().scheme() == "https" {
+            let domain = config
+                .tls()
+                .server_name()
+                .or_else(|| settings.backend().host_str())
+                .unwrap_or_default();
+            println!("TLS server name: {}", domain);
+        }
+
+        let mut runtime = Runtime::new().context(ErrorKind::Tokio)?;
+        connect(&mut runtime, &config, addr);
+
+        let client = Client::new(config);
+
+        let mut req = Request::new(Body::empty());
+        *req.uri_mut() = path.unwrap_or("/").parse()?;
+
+        let req = runtime.block_on(client.prepare(req))?;
+        println!("Request headers:");
+        print_headers(req.headers(), settings.credential());
+
+        let started = Instant::now();
+        let task = client.send(req).and_then(move |res| {
+            let headers_elapsed = started.elapsed();
+            let (parts, body) = res.into_parts();
+            body.map_err(Error::from)
+                .concat2()
+                .map(move |body| (parts, body.len(), headers_elapsed, started.elapsed()))
+        });
+        let (parts, length, headers_elapsed, total_elapsed) = runtime.block_on(task)?;
+        println!(
+            "Response: {} ({:?} to headers, {:?} total, {} bytes)",
+            parts.status, headers_elapsed, total_elapsed, length
+        );
+        println!("Response headers:");
+        print_headers(&parts.headers, settings.credential());
+
+        Ok(())
+    }
+}
+
+fn resolve(url: &Url) -> Result<(SocketAddr, Duration), Error> {
+    let started = Instant::now();
+
+    let addr = url
+        .to_socket_addrs()
+        .map_err(|err| Error::from(err.context(ErrorKind::InvalidUrl(url.to_string()))))?
+        .next()
+        .ok_or_else(|| {
+            ErrorKind::InvalidUrlWithReason(url.to_string(), "URL has no address".to_string())
+        })?;
+
+    Ok((addr, started.elapsed()))
+}
+
+/// Connects to the backend the way the proxy does, reporting the duration of
+/// each step and the outcome of certificate verification, which the request
+/// alone would only report as a failed connection.
+fn connect(runtime: &mut Runtime, config: &Config<SharedToken>, addr: SocketAddr) {
+    let started = Instant::now();
+    let tcp = match runtime.block_on(TcpStream::connect(&addr)) {
+        Ok(tcp) => tcp,
+        Err(err) => {
+            println!("TCP connect: failed after {:?}: {}", started.elapsed(), err);
+            return;
+        }
+    };
+    println!("TCP connect: {:?}", started.elapsed());
+
+    if config.host().scheme() != "https" {
+        return;
+    }
+
+    let host = config.host().host_str().unwrap_or_default();
+    let started = Instant::now();
+    let chain = match runtime.block_on(config.tls().handshake(host, tcp)) {
+        Ok(chain) => chain,
+        Err(err) => {
+            println!("TLS handshake: failed after {:?}", started.elapsed());
+            match rejection(&err) {
+                Some(err) => println!("Verification: failed: {}", err),
+                None => println!("Verification: failed: {}", err),
+            }
+            return;
+        }
+    };
+    println!("TLS handshake: {:?}", started.elapsed());
+    print_chain(&chain);
+
+    let verified = config
+        .verifiers()
+        .iter()
+        .try_for_each(|verifier| verifier.verify(&chain));
+    match verified {
+        Ok(()) => println!("Verification: ok"),
+        Err(err) => println!("Verification: failed: {}", err),
+    }
+}
+
+fn print_chain(chain: &[Certificate]) {
+    if chain.is_empty() {
+        return;
+    }
+
+    println!("Certificate chain:");
+    for (i, cert) in chain.iter().enumerate() {
+        println!("  {}: {}", i, describe(cert));
+    }
+}
+
+fn describe(cert: &Certificate) -> String {
+    let time = |secs: i64| rfc3339(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64));
+
+    format!(
+        "subject={} issuer={} not_before={} not_after={} pin=sha256/{}",
+        cert.subject(),
+        cert.issuer(),
+        time(cert.not_before()),
+        time(cert.not_after()),
+        spki_pin(cert)
+    )
+}
+
+fn print_headers(headers: &HeaderMap, credential: &CredentialSettings) {
+    for (name, value) in headers {
+        println!("  {}", header_line(name, value, credential));
+    }
+}
+
+/// Formats a header, masking values marked sensitive by the client, such as
+/// injected credentials, and values of headers that may hold a credential.
+fn header_line(name: &HeaderName, value: &HeaderValue, credential: &CredentialSettings) -> String {
+    let text = value.to_str().unwrap_or("<binary>");
+    if value.is_sensitive() || is_credential(name, credential) {
+        format!("{}: {}", name, mask(text))
+    } else {
+        format!("{}: {}", name, text)
+    }
+}
+
+fn is_credential(name: &HeaderName, credential: &CredentialSettings) -> bool {
+    if name == header::AUTHORIZATION || name == header::COOKIE {
+        return true;
+    }
+
+    let forwarded = match credential.incoming() {
+        IncomingCredential::Forward(header) => Some(header.as_str()),
+        _ => None,
+    };
+    let target = match credential.target() {
+        CredentialTarget::Header(header) => Some(header),
+        _ => None,
+    };
+
+    target
+        .into_iter()
+        .chain(forwarded)
+        .any(|header| name.as_str().eq_ignore_ascii_case(header))
+}
+
+fn mask(value: &str) -> String {
+    match value.find(' ') {
+        Some(pos) => format!("{} <masked {} chars>", &value[..pos], value.len() - pos - 1),
+        None => format!("<masked {} chars>", value.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use http::header::{HeaderName, HeaderValue};
+
+    use crate::probe::{header_line, is_credential, mask, Probe};
+    use crate::{CredentialSettings, ErrorKind, IncomingCredential, Settings};
+
+    #[test]
+    fn it_masks_token_but_keeps_scheme() {
+        assert_eq!(mask("Bearer secret"), "Bearer <masked 6 chars>");
+        assert_eq!(mask("secret"), "<masked 6 chars>");
+    }
+
+    #[test]
+    fn it_masks_credential_headers() {
+        let credential = CredentialSettings::header("X-Api-Key").with_incoming(
+            IncomingCredential::Forward("x-module-authorization".to_owned()),
+        );
+        let masked = |name| is_credential(&HeaderName::from_static(name), &credential);
+
+        assert!(masked("authorization"));
+        assert!(masked("cookie"));
+        assert!(masked("x-api-key"));
+        assert!(masked("x-module-authorization"));
+        assert!(!masked("content-type"));
+    }
+
+    #[test]
+    fn it_masks_sensitive_headers() {
+        let credential = CredentialSettings::default();
+        let name = HeaderName::from_static("x-functions-key");
+        let mut value = HeaderValue::from_static("secret");
+
+        assert_eq!(
+            header_line(&name, &value, &credential),
+            "x-functions-key: secret"
+        );
+
+        value.set_sensitive(true);
+        assert_eq!(
+            header_line(&name, &value, &credential),
+            "x-functions-key: <masked 6 chars>"
+        );
+    }
+
+    #[test]
+    fn it_fails_to_probe_unknown_service() {
+        let settings = Settings::new(Some(Path::new("test/sample.yaml"))).unwrap();
+
+        let err = Probe::new(settings).run("unknown", None).unwrap_err();
+
+        assert_eq!(err.kind(), &ErrorKind::UnknownService("unknown".to_owned()));
+    }
+}
@@ -0,0 +1,19 @@
+//! TLS implementation used for backend connections. OpenSSL is used by
+//! default, the `rustls` feature replaces it with a pure Rust implementation,
+//! which is the only one available without the `openssl` feature.
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod openssl;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use self::openssl::{
+    client_connector, connect, http, https, peer_chain, tls_connector, Stream, TlsConnector,
+    TlsStream,
+};
+#[cfg(feature = "rustls")]
+pub use self::rustls::{
+    client_connector, connect, http, https, peer_chain, tls_connector, Stream, TlsConnector,
+    TlsStream,
+};
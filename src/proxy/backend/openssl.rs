@@ -0,0 +1,193 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use failure::Fail;
+use futures::{future, Future, Poll};
+use openssl::error::ErrorStack;
+use openssl::ssl::{ConnectConfiguration, SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509VerifyResult, X509};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::{ConnectConfigurationExt, SslStream};
+
+use crate::proxy::Crls;
+use crate::x509::Certificate;
+use crate::{tls, Error, ServiceSettings};
+
+pub type TlsStream = SslStream<TcpStream>;
+
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: SslConnector,
+    accept_invalid_certs: bool,
+    accept_invalid_hostnames: bool,
+}
+
+impl TlsConnector {
+    fn new(connector: SslConnector) -> Self {
+        TlsConnector {
+            connector,
+            accept_invalid_certs: false,
+            accept_invalid_hostnames: false,
+        }
+    }
+
+    fn configure(
+        &self,
+        crls: &Crls,
+        rejected: &Arc<Mutex<Option<Error>>>,
+    ) -> Result<ConnectConfiguration, ErrorStack> {
+        let mut config = self.connector.configure()?;
+        config.set_verify_hostname(!self.accept_invalid_hostnames);
+        if crls.is_empty() && !self.accept_invalid_certs {
+            return Ok(config);
+        }
+
+        let accept_invalid_certs = self.accept_invalid_certs;
+        let crls = crls.clone();
+        let rejected = rejected.clone();
+        config.set_verify_callback(SslVerifyMode::PEER, move |verified, context| {
+            if !verified && !accept_invalid_certs {
+                return false;
+            }
+
+            match crls.check_context(context) {
+                Ok(()) => true,
+                Err(err) => {
+                    context.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+                    *rejected.lock().expect("tls lock poisoned") = Some(err);
+                    false
+                }
+            }
+        });
+
+        Ok(config)
+    }
+}
+
+pub enum Stream {
+    Http(TcpStream),
+    Https(TlsStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Http(stream) => stream.read(buf),
+            Stream::Https(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Http(stream) => stream.write(buf),
+            Stream::Https(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Http(stream) => stream.flush(),
+            Stream::Https(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {}
+
+impl AsyncWrite for Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Stream::Http(stream) => AsyncWrite::shutdown(stream),
+            Stream::Https(stream) => stream.shutdown(),
+        }
+    }
+}
+
+pub fn tls_connector(
+    settings: &ServiceSettings,
+    anchors: &[Vec<u8>],
+) -> Result<TlsConnector, Error> {
+    let mut tls = SslConnector::builder(SslMethod::tls())?;
+    if !settings.system_roots() {
+        tls.set_cert_store(X509StoreBuilder::new()?.build());
+    }
+
+    tls::apply(&mut tls, settings.tls())?;
+
+    for pem in anchors {
+        tls.cert_store_mut().add_cert(X509::from_pem(pem)?)?;
+    }
+
+    let mut connector = TlsConnector::new(tls.build());
+
+    // pinned public keys replace the CA chain validation
+    connector.accept_invalid_certs = !settings.pins().is_empty();
+    connector.accept_invalid_hostnames = settings.danger_accept_invalid_hostnames();
+
+    Ok(connector)
+}
+
+/// Builds a connector for endpoints other than the backend, such as token
+/// servers, trusting the system roots and the given certificates.
+pub fn client_connector(anchors: &[Vec<u8>]) -> Result<TlsConnector, Error> {
+    let mut tls = SslConnector::builder(SslMethod::tls())?;
+
+    for pem in anchors {
+        tls.cert_store_mut().add_cert(X509::from_pem(pem)?)?;
+    }
+
+    Ok(TlsConnector::new(tls.build()))
+}
+
+pub fn connect(
+    connector: TlsConnector,
+    crls: &Crls,
+    host: &str,
+    tcp: TcpStream,
+) -> impl Future<Item = TlsStream, Error = io::Error> {
+    let rejected = Arc::new(Mutex::new(None));
+
+    let config = match connector.configure(crls, &rejected) {
+        Ok(config) => config,
+        Err(err) => return future::Either::B(future::err(io::Error::other(err))),
+    };
+
+    let connecting = config.connect_async(host, tcp).map_err(move |err| {
+        match rejected.lock().expect("tls lock poisoned").take() {
+            Some(rejected) => io::Error::other(rejected.compat()),
+            None => io::Error::other(err.to_string()),
+        }
+    });
+
+    future::Either::A(connecting)
+}
+
+pub fn http(tcp: TcpStream) -> Stream {
+    Stream::Http(tcp)
+}
+
+pub fn https(stream: TlsStream) -> Stream {
+    Stream::Https(stream)
+}
+
+/// Returns the chain the backend certificate was verified with, leaf first,
+/// or the presented one if verification was skipped.
+pub fn peer_chain(stream: &TlsStream) -> Result<Vec<Certificate>, Error> {
+    let ssl = stream.get_ref().ssl();
+
+    let mut chain = Vec::new();
+    for cert in ssl
+        .verified_chain()
+        .or_else(|| ssl.peer_cert_chain())
+        .into_iter()
+        .flatten()
+    {
+        chain.push(Certificate::from_der(&cert.to_der()?)?);
+    }
+
+    Ok(chain)
+}
@@ -0,0 +1,309 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use failure::Fail;
+use futures::{future, Future};
+use hyper_rustls::MaybeHttpsStream;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, Session,
+    TLSError,
+};
+use tokio::net::TcpStream;
+use webpki::DNSNameRef;
+
+use crate::proxy::Crls;
+use crate::tls;
+use crate::x509::{pem_blocks, Certificate as X509Certificate};
+use crate::{Error, ErrorKind, ServiceSettings};
+
+/// rustls configuration along with the verifier it uses, so that revocation
+/// lists can be checked on top of the verifier for each connection.
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    verifier: Arc<dyn ServerCertVerifier>,
+    anchors: Vec<X509Certificate>,
+}
+
+impl TlsConnector {
+    fn new(mut config: ClientConfig, verifier: Arc<dyn ServerCertVerifier>) -> Self {
+        config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        TlsConnector {
+            config: Arc::new(config),
+            verifier,
+            anchors: Vec::new(),
+        }
+    }
+
+    fn with_anchors(mut self, anchors: &[Vec<u8>]) -> Result<Self, Error> {
+        for pem in anchors {
+            for der in pem_blocks(&String::from_utf8_lossy(pem), "CERTIFICATE")? {
+                self.anchors.push(X509Certificate::from_der(&der)?);
+            }
+        }
+
+        Ok(self)
+    }
+
+    fn configure(&self, crls: &Crls, rejected: &Arc<Mutex<Option<Error>>>) -> Arc<ClientConfig> {
+        if crls.is_empty() {
+            return self.config.clone();
+        }
+
+        let mut config = (*self.config).clone();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(RevocationVerifier {
+                inner: self.verifier.clone(),
+                crls: crls.clone(),
+                anchors: self.anchors.clone(),
+                rejected: rejected.clone(),
+            }));
+
+        Arc::new(config)
+    }
+}
+
+pub type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+pub type Stream = MaybeHttpsStream<TcpStream>;
+
+pub fn tls_connector(
+    settings: &ServiceSettings,
+    anchors: &[Vec<u8>],
+) -> Result<TlsConnector, Error> {
+    let mut config = ClientConfig::new();
+    let (versions, suites) = tls::protocols(settings.tls())?;
+    config.versions = versions;
+    config.ciphersuites = suites;
+
+    if settings.system_roots() {
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+
+    add_anchors(&mut config, anchors)?;
+
+    // pinned public keys replace the CA chain validation
+    let verifier: Arc<dyn ServerCertVerifier> = if !settings.pins().is_empty() {
+        Arc::new(AcceptAnyCertificate)
+    } else {
+        Arc::new(ChainVerifier {
+            verify_hostname: !settings.danger_accept_invalid_hostnames(),
+        })
+    };
+
+    let connector = TlsConnector::new(config, verifier);
+    let connector = connector.with_anchors(anchors)?;
+
+    Ok(connector)
+}
+
+/// Builds a connector for endpoints other than the backend, such as token
+/// servers, trusting the system roots and the given certificates.
+pub fn client_connector(anchors: &[Vec<u8>]) -> Result<TlsConnector, Error> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    add_anchors(&mut config, anchors)?;
+
+    let verifier = Arc::new(ChainVerifier {
+        verify_hostname: true,
+    });
+    let connector = TlsConnector::new(config, verifier);
+    let connector = connector.with_anchors(anchors)?;
+
+    Ok(connector)
+}
+
+fn add_anchors(config: &mut ClientConfig, anchors: &[Vec<u8>]) -> Result<(), Error> {
+    for pem in anchors {
+        let certs = pemfile::certs(&mut pem.as_slice()).map_err(|_| ErrorKind::Rustls)?;
+        for cert in certs {
+            config
+                .root_store
+                .add(&cert)
+                .map_err(|err| Error::from(err.context(ErrorKind::Rustls)))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn connect(
+    connector: TlsConnector,
+    crls: &Crls,
+    host: &str,
+    tcp: TcpStream,
+) -> impl Future<Item = TlsStream, Error = io::Error> {
+    let domain = match DNSNameRef::try_from_ascii_str(host) {
+        Ok(domain) => domain,
+        Err(_) => return future::Either::B(future::err(invalid_name(host))),
+    };
+
+    let connecting = {
+        let rejected = Arc::new(Mutex::new(None));
+        let config = connector.configure(crls, &rejected);
+        tokio_rustls::TlsConnector::from(config)
+            .connect(domain, tcp)
+            .map_err(
+                move |err| match rejected.lock().expect("tls lock poisoned").take() {
+                    Some(rejected) => io::Error::other(rejected.compat()),
+                    None => err,
+                },
+            )
+    };
+
+    future::Either::A(connecting)
+}
+
+pub fn http(tcp: TcpStream) -> Stream {
+    MaybeHttpsStream::Http(tcp)
+}
+
+pub fn https(stream: TlsStream) -> Stream {
+    MaybeHttpsStream::Https(stream)
+}
+
+pub fn peer_chain(stream: &TlsStream) -> Result<Vec<X509Certificate>, Error> {
+    let (_, session) = stream.get_ref();
+
+    let mut chain = Vec::new();
+    for cert in session.get_peer_certificates().unwrap_or_default() {
+        chain.push(X509Certificate::from_der(&cert.0)?);
+    }
+
+    Ok(chain)
+}
+
+fn invalid_name(host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} is not a valid DNS name, rustls does not support IP addresses",
+            host
+        ),
+    )
+}
+
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Verifies the chain like the default verifier, optionally skipping the
+/// check of the name the certificate was issued for.
+struct ChainVerifier {
+    verify_hostname: bool,
+}
+
+impl ServerCertVerifier for ChainVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let (cert, chain) = presented_certs
+            .split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let chain = chain
+            .iter()
+            .map(|cert| cert.0.as_slice())
+            .collect::<Vec<_>>();
+        let anchors = roots
+            .roots
+            .iter()
+            .map(|root| root.to_trust_anchor())
+            .collect::<Vec<_>>();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+        let cert = webpki::EndEntityCert::from(&cert.0).map_err(TLSError::WebPKIError)?;
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TLSServerTrustAnchors(&anchors),
+            &chain,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+
+        if self.verify_hostname {
+            cert.verify_is_valid_for_dns_name(dns_name)
+                .map_err(TLSError::WebPKIError)?;
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+struct RevocationVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    crls: Crls,
+    anchors: Vec<X509Certificate>,
+    rejected: Arc<Mutex<Option<Error>>>,
+}
+
+impl RevocationVerifier {
+    fn check(&self, presented_certs: &[Certificate]) -> Result<(), Error> {
+        let mut chain = Vec::new();
+        for cert in presented_certs {
+            chain.push(X509Certificate::from_der(&cert.0)?);
+        }
+
+        self.crls.check_presented(chain, &self.anchors)
+    }
+}
+
+impl ServerCertVerifier for RevocationVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified =
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+
+        if let Err(err) = self.check(presented_certs) {
+            let message = err.to_string();
+            *self.rejected.lock().expect("tls lock poisoned") = Some(err);
+            return Err(TLSError::General(message));
+        }
+
+        Ok(verified)
+    }
+}
@@ -1,35 +1,46 @@
-use failure::ResultExt;
+use std::sync::Arc;
+
+use failure::{Fail, ResultExt};
 use futures::{Future, IntoFuture};
-use http::{header, HeaderValue};
+use http::header::{self, HeaderName};
+use http::HeaderValue;
 use hyper::client::connect::Connect;
-use hyper::client::HttpConnector;
 use hyper::{Body, Client as HyperClient, Request, Response};
-use hyper_tls::HttpsConnector;
-use log::info;
+use log::{info, warn};
+use url::Url;
 
-use crate::proxy::{Config, TokenSource};
-use crate::{Error, ErrorKind};
+use crate::proxy::{verification_error, Config, HttpsConnector, TokenSource};
+use crate::{logging, CredentialTarget, Error, ErrorKind, IncomingCredential, TokenFailurePolicy};
 
-#[derive(Clone)]
 pub struct Client<T, S>
 where
     T: TokenSource,
 {
     config: Config<T>,
-    client: S,
+    client: Arc<S>,
 }
 
-impl<T> Client<T, HyperHttpClient<HttpsConnector<HttpConnector>>>
+impl<T, S> Clone for Client<T, S>
+where
+    T: TokenSource + Clone,
+{
+    fn clone(&self) -> Self {
+        Client {
+            config: self.config.clone(),
+            client: self.client.clone(),
+        }
+    }
+}
+
+impl<T> Client<T, HyperHttpClient<HttpsConnector>>
 where
     T: TokenSource,
 {
     pub fn new(config: Config<T>) -> Self {
-        let mut http = HttpConnector::new(4);
-        http.enforce_http(false);
-
-        let https = HttpsConnector::from((http, config.tls().clone()));
-        let client = HyperHttpClient(HyperClient::builder().build(https));
+        let https =
+            HttpsConnector::new(config.tls().clone()).with_verifiers(config.verifiers().to_vec());
 
+        let client = HyperHttpClient::new(https).with_secret_query(config.credential().target());
         Client::with_client(client, config)
     }
 }
@@ -39,20 +50,42 @@ where
     T: TokenSource,
 {
     pub fn with_client(client: S, config: Config<T>) -> Self {
-        Client { config, client }
+        Client {
+            config,
+            client: Arc::new(client),
+        }
+    }
+
+    pub fn config(&self) -> &Config<T> {
+        &self.config
     }
 }
 
 impl<T, S> Client<T, S>
 where
     T: TokenSource,
-    S: HttpClient,
+    S: HttpClient + Send + Sync + 'static,
 {
     pub fn request(
+        &self,
+        req: Request<Body>,
+    ) -> impl Future<Item = Response<Body>, Error = Error> + Send {
+        let client = self.client.clone();
+        self.prepare(req).and_then(move |req| client.request(req))
+    }
+
+    pub fn send(&self, req: Request<Body>) -> ResponseFuture {
+        self.client.request(req)
+    }
+
+    /// Directs the request to the backend and adds the token and the
+    /// configured credentials without sending it.
+    pub fn prepare(
         &self,
         mut req: Request<Body>,
-    ) -> impl Future<Item = Response<Body>, Error = Error> {
-        self.config
+    ) -> impl Future<Item = Request<Body>, Error = Error> + Send {
+        let req = self
+            .config
             .host()
             .join(req.uri().path_and_query().map_or("", |p| p.as_str()))
             .map_err(Error::from)
@@ -65,42 +98,184 @@ where
                     req.headers_mut().insert(header::HOST, host);
                 }
 
-                // add authorization header with bearer token to authenticate request
-                if let Some(token) = self.config.token().get() {
-                    let token = HeaderValue::from_str(format!("Bearer {}", token).as_str())
-                        .context(ErrorKind::HeaderValue("Authorization".to_owned()))?;
+                Ok(req)
+            });
+
+        let token_failure = self.config.token_failure();
+        let host = self.config.host().to_string();
+        let token_host = host.clone();
+        let token = self.config.token().get().then(move |token| {
+            token.or_else(|err| match token_failure {
+                TokenFailurePolicy::Forward => {
+                    warn!("Could not obtain token, sending request without it");
+                    logging::failure(&err);
+                    Ok(None)
+                }
+                TokenFailurePolicy::Reject => Err(Error::from(
+                    err.context(ErrorKind::TokenUnavailable(token_host)),
+                )),
+            })
+        });
+
+        // unlike a token, configured credentials are always required
+        let headers = self
+            .config
+            .static_credentials()
+            .headers()
+            .map_err(|err| Error::from(err.context(ErrorKind::TokenUnavailable(host))));
+
+        let credential = self.config.credential().clone();
+        let scheme = self.config.auth_scheme().to_owned();
+        req.into_future()
+            .join3(token, headers)
+            .and_then(move |(mut req, token, headers)| {
+                forward_incoming(&mut req, credential.incoming())?;
+
+                for (name, value) in headers {
+                    req.headers_mut().insert(name, value);
+                }
 
-                    req.headers_mut().insert(header::AUTHORIZATION, token);
+                // add the token to authenticate request
+                if let Some(token) = token {
+                    add_token(&mut req, credential.target(), &scheme, &token)?;
                 }
 
                 Ok(req)
             })
-            .map(|req| self.client.request(req))
-            .into_future()
-            .flatten()
     }
 }
 
-pub struct HyperHttpClient<C>(HyperClient<C>);
+fn forward_incoming(req: &mut Request<Body>, incoming: &IncomingCredential) -> Result<(), Error> {
+    match incoming {
+        IncomingCredential::Strip => {
+            req.headers_mut().remove(header::AUTHORIZATION);
+        }
+        IncomingCredential::Preserve => (),
+        IncomingCredential::Forward(name) => {
+            if let Some(value) = req.headers_mut().remove(header::AUTHORIZATION) {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .context(ErrorKind::HeaderName(name.clone()))?;
+                req.headers_mut().insert(name, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the token where the backend expects it, replacing a credential of
+/// the same name sent by the module.
+fn add_token(
+    req: &mut Request<Body>,
+    target: CredentialTarget<'_>,
+    scheme: &str,
+    token: &str,
+) -> Result<(), Error> {
+    match target {
+        CredentialTarget::Header(name) => {
+            let value = if scheme.is_empty() {
+                token.to_owned()
+            } else {
+                format!("{} {}", scheme, token)
+            };
+            let mut value = HeaderValue::from_str(value.as_str())
+                .context(ErrorKind::HeaderValue(name.to_owned()))?;
+            value.set_sensitive(true);
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .context(ErrorKind::HeaderName(name.to_owned()))?;
+
+            req.headers_mut().insert(name, value);
+        }
+        CredentialTarget::Query(parameter) => {
+            let mut url = Url::parse(&req.uri().to_string())?;
+            let pairs: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(name, _)| name != parameter)
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            url.query_pairs_mut()
+                .clear()
+                .extend_pairs(pairs)
+                .append_pair(parameter, token);
+
+            *req.uri_mut() = url.as_str().parse()?;
+        }
+        CredentialTarget::Cookie(cookie) => {
+            let prefix = format!("{}=", cookie);
+            let mut cookies: Vec<String> = req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty() && !pair.starts_with(&prefix))
+                .map(ToOwned::to_owned)
+                .collect();
+            cookies.push(format!("{}{}", prefix, token));
+
+            let mut value = HeaderValue::from_str(&cookies.join("; "))
+                .context(ErrorKind::HeaderValue("Cookie".to_owned()))?;
+            value.set_sensitive(true);
+            req.headers_mut().insert(header::COOKIE, value);
+        }
+    }
+
+    Ok(())
+}
+
+pub struct HyperHttpClient<C> {
+    client: HyperClient<C>,
+    secret_query: Option<String>,
+}
+
+impl HyperHttpClient<HttpsConnector> {
+    pub fn new(https: HttpsConnector) -> Self {
+        HyperHttpClient {
+            client: HyperClient::builder().build(https),
+            secret_query: None,
+        }
+    }
+}
+
+impl<C> HyperHttpClient<C> {
+    /// Redacts the query parameter the token is sent in from logged
+    /// requests.
+    pub fn with_secret_query(mut self, target: CredentialTarget<'_>) -> Self {
+        if let CredentialTarget::Query(parameter) = target {
+            self.secret_query = Some(parameter.to_owned());
+        }
+        self
+    }
+}
 
 impl<C> HttpClient for HyperHttpClient<C>
 where
     C: Connect + Sync + 'static,
 {
     fn request(&self, req: Request<Body>) -> ResponseFuture {
-        let request = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+        let request = format!(
+            "{} {} {:?}",
+            req.method(),
+            logging::redact(req.uri(), self.secret_query.as_ref().map(AsRef::as_ref)),
+            req.version()
+        );
 
-        let fut = self.0.request(req).map_err(Error::from).map(move |res| {
-            let body_length = res
-                .headers()
-                .get(header::CONTENT_LENGTH)
-                .and_then(|length| length.to_str().ok().map(ToString::to_string))
-                .unwrap_or_else(|| "-".to_string());
+        let fut = self
+            .client
+            .request(req)
+            .map_err(|err| verification_error(&err).unwrap_or_else(|| Error::from(err)))
+            .map(move |res| {
+                let body_length = res
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok().map(ToString::to_string))
+                    .unwrap_or_else(|| "-".to_string());
 
-            info!("\"{}\" {} {}", request, res.status(), body_length);
+                info!("\"{}\" {} {}", request, res.status(), body_length);
 
-            res
-        });
+                res
+            });
 
         Box::new(fut)
     }
@@ -114,17 +289,25 @@ pub trait HttpClient {
 
 #[cfg(test)]
 mod tests {
-    use futures::{Future, IntoFuture, Stream};
-    use http::{Request, Response, Uri};
+    use std::fs;
+    use std::path::Path;
+
+    use futures::{future, Future, IntoFuture, Stream};
+    use http::{header, Request, Response, Uri};
     use hyper::Body;
-    use native_tls::TlsConnector;
+    use tempfile::TempDir;
     use tokio::runtime::current_thread;
     use url::Url;
 
+    use crate::proxy::backend::TlsConnector;
     use crate::proxy::client::ResponseFuture;
-    use crate::proxy::config::ValueToken;
-    use crate::proxy::{Client, Config, HttpClient};
-    use crate::{Error, ErrorKind};
+    use crate::proxy::config::tls_connector;
+    use crate::proxy::token::ValueToken;
+    use crate::proxy::{Client, Config, HttpClient, StaticCredentials, TokenFuture, TokenSource};
+    use crate::{
+        BasicAuthSettings, CredentialSettings, Error, ErrorKind, ExtraCredentialSettings,
+        IncomingCredential, ServiceSettings, TokenFailurePolicy,
+    };
 
     #[test]
     fn it_redirects_req_to_server() {
@@ -145,11 +328,22 @@ mod tests {
         assert_eq!(body.as_ref(), b"This Is Fine");
     }
 
+    fn connector() -> TlsConnector {
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:8080").unwrap(),
+            None,
+            Path::new("token"),
+        );
+        tls_connector(&settings).unwrap()
+    }
+
     fn config() -> Config<ValueToken> {
         Config::new(
             Url::parse("https://iotedged:8080").unwrap(),
             ValueToken(None),
-            TlsConnector::builder().build().unwrap(),
+            connector(),
         )
     }
 
@@ -174,12 +368,239 @@ mod tests {
         current_thread::block_on_all(task).unwrap();
     }
 
+    #[test]
+    fn it_sends_token_with_configured_scheme() {
+        let token = || ValueToken(Some("sr=hub&sig=abc&se=1".to_owned()));
+        let url = Url::parse("https://iotedged:8080").unwrap();
+
+        for (config, expected) in [
+            (
+                Config::new(url.clone(), token(), connector()),
+                "Bearer sr=hub&sig=abc&se=1",
+            ),
+            (
+                Config::new(url.clone(), token(), connector())
+                    .with_auth_scheme("SharedAccessSignature"),
+                "SharedAccessSignature sr=hub&sig=abc&se=1",
+            ),
+            (
+                Config::new(url.clone(), token(), connector()).with_auth_scheme(""),
+                "sr=hub&sig=abc&se=1",
+            ),
+        ] {
+            let http = client_fn(move |req: Request<Body>| {
+                assert_eq!(req.headers()[header::AUTHORIZATION], expected);
+                Ok(Response::new(Body::empty()))
+            });
+            let client = Client::with_client(http, config);
+
+            current_thread::block_on_all(client.request(Request::new(Body::empty()))).unwrap();
+        }
+    }
+
+    fn credential_config(credential: CredentialSettings) -> Config<ValueToken> {
+        Config::new(
+            Url::parse("https://iotedged:8080").unwrap(),
+            ValueToken(Some("token".to_owned())),
+            connector(),
+        )
+        .with_credential(credential)
+        .with_auth_scheme("")
+    }
+
+    #[test]
+    fn it_sends_token_in_configured_header() {
+        let http = client_fn(|req: Request<Body>| {
+            assert_eq!(req.headers()["x-api-key"], "token");
+            assert!(req.headers().get(header::AUTHORIZATION).is_none());
+            Ok(Response::new(Body::empty()))
+        });
+        let config = credential_config(CredentialSettings::header("X-Api-Key"));
+        let client = Client::with_client(http, config);
+
+        current_thread::block_on_all(client.request(Request::new(Body::empty()))).unwrap();
+    }
+
+    #[test]
+    fn it_sends_token_in_query_parameter() {
+        let http = client_fn(|req: Request<Body>| {
+            assert_eq!(
+                req.uri(),
+                "https://iotedged:8080/api/values?version=v1&code=token"
+            );
+            Ok(Response::new(Body::empty()))
+        });
+        let client =
+            Client::with_client(http, credential_config(CredentialSettings::query("code")));
+        let mut req = Request::new(Body::empty());
+        *req.uri_mut() = "/api/values?code=forged&version=v1".parse().unwrap();
+
+        current_thread::block_on_all(client.request(req)).unwrap();
+    }
+
+    #[test]
+    fn it_sends_token_in_cookie() {
+        let http = client_fn(|req: Request<Body>| {
+            assert_eq!(req.headers()[header::COOKIE], "theme=dark; session=token");
+            Ok(Response::new(Body::empty()))
+        });
+        let config = credential_config(CredentialSettings::cookie("session"));
+        let client = Client::with_client(http, config);
+        let req = Request::builder()
+            .header(header::COOKIE, "session=forged; theme=dark")
+            .body(Body::empty())
+            .unwrap();
+
+        current_thread::block_on_all(client.request(req)).unwrap();
+    }
+
+    #[test]
+    fn it_handles_incoming_authorization() {
+        let cases = [
+            (IncomingCredential::Strip, None, None),
+            (IncomingCredential::Preserve, Some("Basic module"), None),
+            (
+                IncomingCredential::Forward("x-module-authorization".to_owned()),
+                None,
+                Some("Basic module"),
+            ),
+        ];
+
+        for (incoming, authorization, forwarded) in cases.iter().cloned() {
+            let http = client_fn(move |req: Request<Body>| {
+                let header = |name| req.headers().get(name).map(|value| value.to_str().unwrap());
+                assert_eq!(header("authorization"), authorization);
+                assert_eq!(header("x-module-authorization"), forwarded);
+                assert_eq!(header("x-api-key"), Some("token"));
+                Ok(Response::new(Body::empty()))
+            });
+            let credential = CredentialSettings::header("x-api-key").with_incoming(incoming);
+            let client = Client::with_client(http, credential_config(credential));
+            let req = Request::builder()
+                .header(header::AUTHORIZATION, "Basic module")
+                .body(Body::empty())
+                .unwrap();
+
+            current_thread::block_on_all(client.request(req)).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_sends_basic_auth_with_token_in_other_header() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("username"), "edge").unwrap();
+        fs::write(dir.path().join("password"), "s3cr3t").unwrap();
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:8080").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_basic_auth(BasicAuthSettings::new(
+            &dir.path().join("username"),
+            &dir.path().join("password"),
+        ));
+
+        let http = client_fn(|req: Request<Body>| {
+            assert_eq!(
+                req.headers()[header::AUTHORIZATION],
+                "Basic ZWRnZTpzM2NyM3Q="
+            );
+            assert_eq!(req.headers()["x-api-key"], "token");
+            assert!(req.headers()[header::AUTHORIZATION].is_sensitive());
+            assert!(req.headers()["x-api-key"].is_sensitive());
+            Ok(Response::new(Body::empty()))
+        });
+        let config = credential_config(CredentialSettings::header("x-api-key"))
+            .with_static_credentials(StaticCredentials::new(&settings).unwrap());
+        let client = Client::with_client(http, config);
+        let req = Request::builder()
+            .header(header::AUTHORIZATION, "Basic module")
+            .body(Body::empty())
+            .unwrap();
+
+        current_thread::block_on_all(client.request(req)).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_request_when_credentials_are_unreadable() {
+        let dir = TempDir::new().unwrap();
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:8080").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_extra_credential(ExtraCredentialSettings::new(
+            "x-functions-key",
+            &dir.path().join("key"),
+        ));
+        fs::write(dir.path().join("key"), "key").unwrap();
+        let credentials = StaticCredentials::new(&settings).unwrap();
+        fs::remove_file(dir.path().join("key")).unwrap();
+
+        let http = client_fn(|_| -> Result<Response<Body>, Error> {
+            panic!("request must not be sent without credentials")
+        });
+        let config = credential_config(CredentialSettings::header("x-api-key"))
+            .with_static_credentials(credentials);
+        let client = Client::with_client(http, config);
+
+        let err =
+            current_thread::block_on_all(client.request(Request::new(Body::empty()))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::TokenUnavailable("https://iotedged:8080/".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_sends_request_without_token_when_source_fails() {
+        let config = Config::new(
+            Url::parse("https://iotedged:8080").unwrap(),
+            FailingToken,
+            connector(),
+        );
+        let http = client_fn(|req: Request<Body>| {
+            assert!(req.headers().get(header::AUTHORIZATION).is_none());
+            Ok(Response::new(Body::empty()))
+        });
+        let client = Client::with_client(http, config);
+
+        current_thread::block_on_all(client.request(Request::new(Body::empty()))).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_request_without_token_when_configured() {
+        let config = Config::new(
+            Url::parse("https://iotedged:8080").unwrap(),
+            FailingToken,
+            connector(),
+        )
+        .with_token_failure(TokenFailurePolicy::Reject);
+        let http = client_fn(|_| -> Result<Response<Body>, Error> {
+            panic!("request must not be sent without a token")
+        });
+        let client = Client::with_client(http, config);
+
+        let task = client.request(Request::new(Body::empty()));
+
+        let err = current_thread::block_on_all(task).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::TokenUnavailable("https://iotedged:8080/".to_owned())
+        );
+    }
+
     #[test]
     fn it_fails_when_token_is_invalid() {
         let config = Config::new(
             Url::parse("https://iotedged:8080").unwrap(),
             ValueToken(Some(String::from_utf8(vec![10]).unwrap())),
-            TlsConnector::builder().build().unwrap(),
+            connector(),
         );
         let http = client_fn(|_| Ok(Response::new("This Is Fine".into())));
         let client = Client::with_client(http, config);
@@ -206,6 +627,16 @@ mod tests {
         assert_eq!(err.kind(), &ErrorKind::Hyper);
     }
 
+    struct FailingToken;
+
+    impl TokenSource for FailingToken {
+        fn get(&self) -> TokenFuture {
+            Box::new(future::err(Error::from(ErrorKind::Token(
+                "unavailable".to_owned(),
+            ))))
+        }
+    }
+
     pub fn client_fn<F, S>(f: F) -> HttpClientFn<F>
     where
         F: Fn(Request<Body>) -> S,
@@ -1,10 +1,16 @@
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use failure::ResultExt;
-use native_tls::{Certificate, TlsConnector};
 use url::Url;
 
-use crate::{Error, ErrorKind, ServiceSettings};
+use crate::proxy::backend::{self, TlsConnector};
+use crate::proxy::{load_crls, Crls, PinVerifier, Verifier};
+use crate::proxy::{token_source, SharedToken, StaticCredentials, Tls, TokenSource};
+use crate::supervisor::Status;
+use crate::workload::WorkloadClient;
+use crate::{CredentialSettings, Error, ErrorKind, ServiceSettings, TokenFailurePolicy};
 
 #[derive(Clone)]
 pub struct Config<T>
@@ -13,7 +19,12 @@ where
 {
     host: Url,
     token: T,
-    tls: TlsConnector,
+    credential: CredentialSettings,
+    auth_scheme: String,
+    static_credentials: StaticCredentials,
+    token_failure: TokenFailurePolicy,
+    tls: Tls,
+    verifiers: Vec<Arc<dyn Verifier>>,
 }
 
 impl<T> Config<T>
@@ -21,64 +32,211 @@ where
     T: TokenSource,
 {
     pub fn new(host: Url, token: T, tls: TlsConnector) -> Self {
-        Config { host, token, tls }
+        Config {
+            host,
+            token,
+            credential: CredentialSettings::default(),
+            auth_scheme: "Bearer".to_owned(),
+            static_credentials: StaticCredentials::default(),
+            token_failure: TokenFailurePolicy::default(),
+            tls: Tls::new(tls),
+            verifiers: Vec::new(),
+        }
+    }
+
+    pub fn with_credential(mut self, credential: CredentialSettings) -> Self {
+        self.credential = credential;
+        self
+    }
+
+    pub fn with_auth_scheme(mut self, auth_scheme: &str) -> Self {
+        self.auth_scheme = auth_scheme.to_owned();
+        self
+    }
+
+    pub fn with_static_credentials(mut self, static_credentials: StaticCredentials) -> Self {
+        self.static_credentials = static_credentials;
+        self
+    }
+
+    pub fn with_token_failure(mut self, token_failure: TokenFailurePolicy) -> Self {
+        self.token_failure = token_failure;
+        self
+    }
+
+    pub fn with_server_name(mut self, server_name: Option<String>) -> Self {
+        self.tls = self.tls.with_server_name(server_name);
+        self
+    }
+    pub fn with_crls(mut self, crls: Crls) -> Self {
+        self.tls = self.tls.with_crls(crls);
+        self
+    }
+    pub fn with_verifier(mut self, verifier: Arc<dyn Verifier>) -> Self {
+        self.verifiers.push(verifier);
+        self
     }
 
     pub fn host(&self) -> &Url {
         &self.host
     }
 
-    pub fn tls(&self) -> &TlsConnector {
+    pub fn tls(&self) -> &Tls {
         &self.tls
     }
+    pub fn verifiers(&self) -> &[Arc<dyn Verifier>] {
+        &self.verifiers
+    }
 
     pub fn token(&self) -> &impl TokenSource {
         &self.token
     }
-}
 
-pub fn get_config(settings: &ServiceSettings) -> Result<Config<ValueToken>, Error> {
-    let token = fs::read_to_string(settings.token())
-        .context(ErrorKind::File(settings.token().display().to_string()))?;
+    pub fn credential(&self) -> &CredentialSettings {
+        &self.credential
+    }
 
-    let mut tls = TlsConnector::builder();
+    pub fn static_credentials(&self) -> &StaticCredentials {
+        &self.static_credentials
+    }
 
-    if let Some(path) = settings.certificate() {
-        let file = fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
-        let cert = Certificate::from_pem(file.as_bytes())?;
+    pub fn auth_scheme(&self) -> &str {
+        &self.auth_scheme
+    }
 
-        tls.add_root_certificate(cert);
+    pub fn token_failure(&self) -> TokenFailurePolicy {
+        self.token_failure
     }
+}
 
-    Ok(Config::new(
+/// Creates the backend configuration of a service. Expiry of the token is
+/// recorded in the given status if it is known. The workload client signs
+/// tokens of services configured to use it.
+pub fn get_config(
+    settings: &ServiceSettings,
+    status: &Status,
+    workload: Option<&WorkloadClient>,
+) -> Result<Config<SharedToken>, Error> {
+    let config = Config::new(
         settings.backend().clone(),
-        ValueToken(Some(token)),
-        tls.build()?,
-    ))
+        token_source(settings, status, workload)?,
+        tls_connector(settings)?,
+    )
+    .with_credential(settings.credential().clone())
+    .with_auth_scheme(settings.auth_scheme())
+    .with_static_credentials(StaticCredentials::new(settings)?)
+    .with_token_failure(settings.token_failure())
+    .with_server_name(settings.tls_server_name().map(ToOwned::to_owned));
+    let config = config.with_crls(Crls::new(load_crls(settings)?));
+    if settings.pins().is_empty() {
+        Ok(config)
+    } else {
+        Ok(config.with_verifier(Arc::new(PinVerifier::new(settings.pins()))))
+    }
+}
+
+pub fn tls_connector(settings: &ServiceSettings) -> Result<TlsConnector, Error> {
+    tls_connector_with(settings, &[])
 }
 
-pub trait TokenSource {
-    fn get(&self) -> Option<String>;
+/// Builds a connector trusting the configured certificates along with the
+/// given PEM encoded ones.
+pub fn tls_connector_with(
+    settings: &ServiceSettings,
+    anchors: &[Vec<u8>],
+) -> Result<TlsConnector, Error> {
+    let mut certs = trust_anchors(settings)?;
+    certs.extend_from_slice(anchors);
+
+    backend::tls_connector(settings, &certs)
 }
 
-#[derive(Clone, Debug)]
-pub struct ValueToken(pub Option<String>);
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+const CERT_EXTENSIONS: [&str; 3] = ["pem", "crt", "cer"];
+
+/// Loads every PEM encoded certificate from the configured files, PEM bundles
+/// and directories.
+pub fn trust_anchors(settings: &ServiceSettings) -> Result<Vec<Vec<u8>>, Error> {
+    let mut certs = Vec::new();
+
+    for path in certificate_files(settings)? {
+        let file =
+            fs::read_to_string(&path).context(ErrorKind::File(path.display().to_string()))?;
 
-impl TokenSource for ValueToken {
-    fn get(&self) -> Option<String> {
-        self.0.clone()
+        let blocks = pem_certificates(&file);
+        if blocks.is_empty() {
+            return Err(Error::from(ErrorKind::NoCertificates(
+                path.display().to_string(),
+            )));
+        }
+
+        certs.extend(blocks);
     }
+
+    Ok(certs)
+}
+
+pub fn pem_certificates(pem: &str) -> Vec<Vec<u8>> {
+    let mut certs = Vec::new();
+
+    let mut rest = pem;
+    while let Some(begin) = rest.find(PEM_BEGIN) {
+        let end = rest[begin..]
+            .find(PEM_END)
+            .map_or(rest.len(), |end| begin + end + PEM_END.len());
+
+        certs.push(rest.as_bytes()[begin..end].to_vec());
+        rest = &rest[end..];
+    }
+
+    certs
+}
+
+pub fn certificate_files(settings: &ServiceSettings) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+
+    for path in settings.certificates() {
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            continue;
+        }
+
+        let entries = fs::read_dir(path).context(ErrorKind::File(path.display().to_string()))?;
+        let mut entries = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_certificate_file(path))
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        files.extend(entries);
+    }
+
+    Ok(files)
+}
+
+fn is_certificate_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'));
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    !hidden && extension.is_some_and(|extension| CERT_EXTENSIONS.contains(&extension))
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
 
+    use futures::Future;
     use tempfile::TempDir;
     use url::Url;
 
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::proxy::config::{certificate_files, trust_anchors};
     use crate::proxy::{get_config, TokenSource};
-    use crate::tls::CertGenerator;
+    use crate::supervisor::Status;
     use crate::{ErrorKind, ServiceSettings};
 
     #[test]
@@ -89,7 +247,11 @@ mod tests {
         fs::write(&token, "token").unwrap();
 
         let cert = dir.path().join("cert.pem");
-        CertGenerator::default().cert(&cert).generate().unwrap();
+        CertGenerator::new("localhost", CertKind::Ca)
+            .generate()
+            .unwrap()
+            .write_cert(&cert)
+            .unwrap();
 
         let settings = ServiceSettings::new(
             "management".to_owned(),
@@ -99,9 +261,12 @@ mod tests {
             &token,
         );
 
-        let config = get_config(&settings).unwrap();
+        let config = get_config(&settings, &Status::default(), None).unwrap();
 
-        assert_eq!(config.token().get(), Some("token".to_string()));
+        assert_eq!(
+            config.token().get().wait().unwrap(),
+            Some("token".to_string())
+        );
         assert_eq!(
             config.host(),
             &Url::parse("https://iotedged:30000").unwrap()
@@ -123,7 +288,9 @@ mod tests {
             &token,
         );
 
-        let err = get_config(&settings).err().unwrap();
+        let err = get_config(&settings, &Status::default(), None)
+            .err()
+            .unwrap();
 
         assert_eq!(err.kind(), &ErrorKind::File(token.display().to_string()));
     }
@@ -145,7 +312,9 @@ mod tests {
             &token,
         );
 
-        let err = get_config(&settings).err().unwrap();
+        let err = get_config(&settings, &Status::default(), None)
+            .err()
+            .unwrap();
 
         assert_eq!(err.kind(), &ErrorKind::File(cert.display().to_string()));
     }
@@ -157,6 +326,38 @@ mod tests {
         let token = dir.path().join("token");
         fs::write(&token, "token").unwrap();
 
+        let cert = dir.path().join("cert.pem");
+        fs::write(
+            &cert,
+            "-----BEGIN CERTIFICATE-----\ncert\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            Some(&cert),
+            &token,
+        );
+
+        let err = get_config(&settings, &Status::default(), None)
+            .err()
+            .unwrap();
+
+        #[cfg(not(feature = "rustls"))]
+        assert_eq!(err.kind(), &ErrorKind::OpenSsl);
+        #[cfg(feature = "rustls")]
+        assert_eq!(err.kind(), &ErrorKind::Rustls);
+    }
+
+    #[test]
+    fn it_fails_to_load_config_if_cert_has_no_certificates() {
+        let dir = TempDir::new().unwrap();
+
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+
         let cert = dir.path().join("cert.pem");
         fs::write(&cert, "cert").unwrap();
 
@@ -168,9 +369,54 @@ mod tests {
             &token,
         );
 
-        let err = get_config(&settings).err().unwrap();
+        let err = get_config(&settings, &Status::default(), None)
+            .err()
+            .unwrap();
 
-        assert_eq!(err.kind(), &ErrorKind::NativeTls);
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::NoCertificates(cert.display().to_string())
+        );
     }
 
+    #[test]
+    fn it_loads_certificates_from_bundles_and_directories() {
+        let dir = TempDir::new().unwrap();
+
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+
+        let generate = || {
+            let cert = CertGenerator::new("localhost", CertKind::Ca)
+                .generate()
+                .unwrap();
+            String::from_utf8(cert.cert().to_pem().unwrap()).unwrap()
+        };
+
+        let bundle = dir.path().join("bundle.pem");
+        fs::write(&bundle, generate() + &generate()).unwrap();
+
+        let certs = dir.path().join("certs");
+        fs::create_dir(&certs).unwrap();
+        fs::write(certs.join("first.crt"), generate()).unwrap();
+        fs::write(certs.join("second.pem"), generate()).unwrap();
+        fs::write(certs.join("README"), "not a certificate").unwrap();
+
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            Some(&bundle),
+            &token,
+        )
+        .with_certificates(vec![certs.clone()])
+        .with_system_roots(false);
+
+        assert_eq!(
+            certificate_files(&settings).unwrap(),
+            vec![bundle, certs.join("first.crt"), certs.join("second.pem")]
+        );
+        assert_eq!(trust_anchors(&settings).unwrap().len(), 4);
+        assert!(get_config(&settings, &Status::default(), None).is_ok());
+    }
 }
@@ -0,0 +1,521 @@
+use std::error::Error as StdError;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+use failure::{Compat, Fail};
+use futures::Future;
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::HttpConnector;
+use hyper::Error as HyperError;
+use tokio::net::TcpStream;
+
+use crate::proxy::backend::{self, Stream, TlsConnector, TlsStream};
+use crate::proxy::{Crls, Verifier};
+use crate::x509::Certificate;
+use crate::{Error, ErrorKind};
+
+/// TLS connector shared between a client and the certificate watcher, so that
+/// a reloaded connector applies to new connections while established ones
+/// keep the connector they were created with.
+#[derive(Clone)]
+pub struct Tls {
+    connector: Arc<RwLock<TlsConnector>>,
+    server_name: Option<String>,
+    crls: Crls,
+    trust_bundle: Arc<RwLock<Vec<Vec<u8>>>>,
+    rebuild: Arc<Mutex<()>>,
+}
+
+impl Tls {
+    pub fn new(connector: TlsConnector) -> Self {
+        Tls {
+            connector: Arc::new(RwLock::new(connector)),
+            server_name: None,
+            crls: Crls::default(),
+            trust_bundle: Arc::default(),
+            rebuild: Arc::default(),
+        }
+    }
+
+    pub fn with_server_name(mut self, server_name: Option<String>) -> Self {
+        self.server_name = server_name;
+        self
+    }
+
+    pub fn with_crls(mut self, crls: Crls) -> Self {
+        self.crls = crls;
+        self
+    }
+
+    pub fn connector(&self) -> TlsConnector {
+        self.connector.read().expect("tls lock poisoned").clone()
+    }
+
+    /// Replaces the connector with one built from the current trust bundle.
+    /// Rebuilds are serialized, so that a connector built from an outdated
+    /// bundle never replaces a newer one.
+    pub fn rebuild<F>(&self, build: F) -> Result<(), Error>
+    where
+        F: FnOnce(&[Vec<u8>]) -> Result<TlsConnector, Error>,
+    {
+        let _rebuild = self.rebuild.lock().expect("tls lock poisoned");
+        let connector = build(&self.trust_bundle())?;
+        *self.connector.write().expect("tls lock poisoned") = connector;
+
+        Ok(())
+    }
+
+    /// Returns the name used for SNI and certificate verification instead of
+    /// the host of the backend URL.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn crls(&self) -> &Crls {
+        &self.crls
+    }
+
+    /// Performs the handshake of a backend connection on the given stream and
+    /// returns the chain the backend certificate was verified with.
+    pub fn handshake(
+        &self,
+        host: &str,
+        tcp: TcpStream,
+    ) -> impl Future<Item = Vec<Certificate>, Error = io::Error> {
+        let host = self.server_name().unwrap_or(host);
+        backend::connect(self.connector(), &self.crls, host, tcp).and_then(|stream| {
+            backend::peer_chain(&stream).map_err(|err| io::Error::other(err.compat()))
+        })
+    }
+
+    /// Returns the certificates trusted in addition to the configured ones,
+    /// so that they survive a reload of the configured files.
+    pub fn trust_bundle(&self) -> Vec<Vec<u8>> {
+        self.trust_bundle.read().expect("tls lock poisoned").clone()
+    }
+
+    /// Replaces the trust bundle and the connector built from it, unless the
+    /// bundle is unchanged. Returns whether they were replaced.
+    pub fn replace_trust_bundle<F>(&self, certs: Vec<Vec<u8>>, build: F) -> Result<bool, Error>
+    where
+        F: FnOnce(&[Vec<u8>]) -> Result<TlsConnector, Error>,
+    {
+        let _rebuild = self.rebuild.lock().expect("tls lock poisoned");
+        if certs == self.trust_bundle() {
+            return Ok(false);
+        }
+
+        let connector = build(&certs)?;
+        *self.connector.write().expect("tls lock poisoned") = connector;
+        *self.trust_bundle.write().expect("tls lock poisoned") = certs;
+
+        Ok(true)
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpsConnector {
+    http: HttpConnector,
+    tls: Tls,
+    verifiers: Vec<Arc<dyn Verifier>>,
+}
+
+impl HttpsConnector {
+    pub fn new(tls: Tls) -> Self {
+        let mut http = HttpConnector::new(4);
+        http.enforce_http(false);
+
+        HttpsConnector {
+            http,
+            tls,
+            verifiers: Vec::new(),
+        }
+    }
+
+    pub fn with_verifiers(mut self, verifiers: Vec<Arc<dyn Verifier>>) -> Self {
+        self.verifiers = verifiers;
+        self
+    }
+}
+
+pub type Connecting = Box<dyn Future<Item = (Stream, Connected), Error = io::Error> + Send>;
+
+impl Connect for HttpsConnector {
+    type Transport = Stream;
+    type Error = io::Error;
+    type Future = Connecting;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let is_https = dst.scheme() == "https";
+        let host = self.tls.server_name().unwrap_or(dst.host()).to_owned();
+        let connecting = self.http.connect(dst);
+
+        if !is_https {
+            return Box::new(connecting.map(|(tcp, connected)| (backend::http(tcp), connected)));
+        }
+
+        let tls = self.tls.connector();
+        let crls = self.tls.crls().clone();
+        let verifiers = self.verifiers.clone();
+        let fut = connecting.and_then(move |(tcp, connected)| {
+            backend::connect(tls, &crls, &host, tcp).and_then(move |stream| {
+                verify_peer(&stream, &verifiers)
+                    .map(|_| (backend::https(stream), connected))
+                    .map_err(|err| io::Error::other(err.compat()))
+            })
+        });
+
+        Box::new(fut)
+    }
+}
+
+fn verify_peer(stream: &TlsStream, verifiers: &[Arc<dyn Verifier>]) -> Result<(), Error> {
+    if verifiers.is_empty() {
+        return Ok(());
+    }
+
+    let chain = backend::peer_chain(stream)?;
+    if chain.is_empty() {
+        return Err(Error::from(ErrorKind::PeerCertificate));
+    }
+
+    for verifier in verifiers {
+        verifier.verify(&chain)?;
+    }
+
+    Ok(())
+}
+
+/// Extracts an error raised by a verifier from the connection error reported
+/// by hyper, so that it is not hidden behind a generic connection error.
+pub fn verification_error(err: &HyperError) -> Option<Error> {
+    let mut source = StdError::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>().and_then(rejection) {
+            return Some(err);
+        }
+
+        source = err.source();
+    }
+
+    None
+}
+
+/// Extracts an error raised by a verifier or a revocation check from a failed
+/// connection to the backend.
+pub fn rejection(err: &io::Error) -> Option<Error> {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<Compat<Error>>())
+        .map(|compat| Error::from(compat.get_ref().kind().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use hyper::{Body, Request, StatusCode};
+    use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslVersion};
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+    use tokio::runtime::current_thread::Runtime;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind, Certificate};
+    use crate::proxy::config::tls_connector;
+    use crate::proxy::revocation::tests::crl;
+    use crate::proxy::token::ValueToken;
+    use crate::proxy::{rejection, spki_pin, Client, Config, Crls, PinVerifier, Verifier};
+    use crate::x509;
+    use crate::{Error, ErrorKind, ServiceSettings, TlsSettings, TlsVersion};
+
+    fn serve_once(name: &str) -> (SocketAddr, Certificate, Certificate) {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let server = CertGenerator::new(name, CertKind::Server)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+
+        (serve(&server, &[&ca]), ca, server)
+    }
+
+    fn serve(server: &Certificate, chain: &[&Certificate]) -> SocketAddr {
+        serve_with(server, chain, |_| ())
+    }
+
+    fn serve_with<F>(server: &Certificate, chain: &[&Certificate], configure: F) -> SocketAddr
+    where
+        F: FnOnce(&mut SslAcceptorBuilder),
+    {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(server.cert()).unwrap();
+        for cert in chain {
+            acceptor.add_extra_chain_cert(cert.cert().clone()).unwrap();
+        }
+        acceptor.set_private_key(server.key()).unwrap();
+        configure(&mut acceptor);
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+
+        addr
+    }
+
+    fn request(config: Config<ValueToken>) -> Result<StatusCode, Error> {
+        let client = Client::new(config);
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(client.request(Request::new(Body::empty())))
+            .map(|res| res.status())
+    }
+
+    fn config(
+        addr: SocketAddr,
+        ca: Certificate,
+        accept_invalid_hostnames: bool,
+    ) -> Config<ValueToken> {
+        config_with(addr, ca, |settings| {
+            settings.with_danger_accept_invalid_hostnames(accept_invalid_hostnames)
+        })
+    }
+
+    fn config_with<F>(addr: SocketAddr, ca: Certificate, configure: F) -> Config<ValueToken>
+    where
+        F: FnOnce(ServiceSettings) -> ServiceSettings,
+    {
+        let dir = TempDir::new().unwrap();
+        let cert = dir.path().join("ca.pem");
+        ca.write_cert(&cert).unwrap();
+
+        let url = Url::parse(&format!("https://localhost:{}", addr.port())).unwrap();
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            url.clone(),
+            Some(&cert),
+            &dir.path().join("token"),
+        )
+        .with_system_roots(false);
+        let tls = tls_connector(&configure(settings)).unwrap();
+
+        Config::new(url, ValueToken(None), tls)
+    }
+
+    #[test]
+    fn it_rejects_backend_with_mismatched_name() {
+        let (addr, ca, _) = serve_once("iotedged");
+
+        let err = request(config(addr, ca, false)).unwrap_err();
+
+        assert_eq!(err.kind(), &ErrorKind::Hyper);
+    }
+
+    #[test]
+    fn it_verifies_backend_against_server_name_override() {
+        let (addr, ca, _) = serve_once("iotedged");
+        let config = config(addr, ca, false).with_server_name(Some("iotedged".to_owned()));
+
+        assert_eq!(request(config).unwrap(), StatusCode::OK);
+    }
+
+    #[test]
+    fn it_accepts_invalid_hostnames_when_allowed() {
+        let (addr, ca, _) = serve_once("iotedged");
+
+        assert_eq!(request(config(addr, ca, true)).unwrap(), StatusCode::OK);
+    }
+
+    #[test]
+    fn it_offers_only_configured_cipher_suites() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        let only = |cipher: &str| {
+            let cipher = cipher.to_owned();
+            move |settings: ServiceSettings| {
+                settings.with_tls(TlsSettings::new(
+                    None,
+                    Some(TlsVersion::Tls12),
+                    Some(vec![cipher]),
+                ))
+            }
+        };
+        let serve = || {
+            serve_with(&server, &[&ca], |acceptor| {
+                acceptor
+                    .set_max_proto_version(Some(SslVersion::TLS1_2))
+                    .unwrap();
+                acceptor
+                    .set_cipher_list("ECDHE-RSA-AES256-GCM-SHA384")
+                    .unwrap();
+            })
+        };
+
+        let addr = serve();
+        let config = config_with(addr, ca.clone(), only("ECDHE-RSA-AES128-GCM-SHA256"));
+        let err = request(config).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Hyper);
+
+        let addr = serve();
+        let config = config_with(addr, ca, only("ECDHE-RSA-AES256-GCM-SHA384"));
+        assert_eq!(request(config).unwrap(), StatusCode::OK);
+    }
+
+    #[test]
+    fn it_rejects_backend_above_max_version() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        let addr = serve_with(&server, &[&ca], |acceptor| {
+            acceptor
+                .set_min_proto_version(Some(SslVersion::TLS1_3))
+                .unwrap();
+        });
+
+        let config = config_with(addr, ca, |settings| {
+            settings.with_tls(TlsSettings::new(None, Some(TlsVersion::Tls12), None))
+        });
+        let err = request(config).unwrap_err();
+
+        assert_eq!(err.kind(), &ErrorKind::Hyper);
+    }
+
+    #[test]
+    fn it_verifies_pinned_backend_instead_of_chain() {
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .generate()
+            .unwrap();
+        let untrusted = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let pinned = |pin: String| {
+            let pins = vec![pin];
+            config_with(serve(&server, &[]), untrusted.clone(), |settings| {
+                settings.with_pins(pins.clone())
+            })
+            .with_verifier(Arc::new(PinVerifier::new(&pins)))
+        };
+
+        let der = server.cert().to_der().unwrap();
+        let pin = spki_pin(&x509::Certificate::from_der(&der).unwrap());
+        assert_eq!(
+            request(pinned(format!("sha256/{}", pin))).unwrap(),
+            StatusCode::OK
+        );
+
+        let err = request(pinned("sha256/other".to_owned())).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::PinMismatch(format!("sha256/{}", pin))
+        );
+    }
+
+    #[test]
+    fn it_rejects_revoked_backend_certificate() {
+        let (addr, ca, server) = serve_once("localhost");
+        let crls = Crls::new(vec![crl(&ca, &[&server])]);
+        let config = config(addr, ca, false).with_crls(crls);
+
+        let err = request(config).unwrap_err();
+
+        assert_eq!(err.kind(), &ErrorKind::Revoked("CN=localhost".to_owned()));
+    }
+
+    #[test]
+    fn it_rejects_backend_with_revoked_intermediate() {
+        let root = CertGenerator::new("root", CertKind::Ca).generate().unwrap();
+        let intermediate = CertGenerator::new("intermediate", CertKind::Ca)
+            .issuer(&root)
+            .generate()
+            .unwrap();
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .issuer(&intermediate)
+            .generate()
+            .unwrap();
+        let addr = serve(&server, &[&intermediate]);
+
+        let crls = Crls::new(vec![crl(&root, &[&intermediate])]);
+        let err = request(config(addr, root.clone(), false).with_crls(crls.clone())).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Revoked("CN=intermediate".to_owned())
+        );
+
+        let addr = serve(&server, &[&intermediate]);
+        crls.replace(vec![crl(&root, &[]), crl(&intermediate, &[])]);
+        let status = request(config(addr, root, false).with_crls(crls)).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn it_reports_chain_or_rejection_of_handshake() {
+        let handshake = |config: Config<ValueToken>, addr| {
+            let mut runtime = Runtime::new().unwrap();
+            let tcp = runtime.block_on(TcpStream::connect(&addr)).unwrap();
+            runtime.block_on(config.tls().handshake("localhost", tcp))
+        };
+
+        let (addr, ca, _) = serve_once("localhost");
+        let chain = handshake(config(addr, ca, false), addr).unwrap();
+        let subjects: Vec<_> = chain
+            .iter()
+            .map(|cert| cert.subject().to_string())
+            .collect();
+        assert_eq!(subjects[..2], ["CN=localhost", "CN=ca"]);
+
+        let (addr, ca, server) = serve_once("localhost");
+        let crls = Crls::new(vec![crl(&ca, &[&server])]);
+        let err = handshake(config(addr, ca, false).with_crls(crls), addr).unwrap_err();
+        assert_eq!(
+            rejection(&err).unwrap().kind(),
+            &ErrorKind::Revoked("CN=localhost".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_passes_verified_chain_to_verifiers() {
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Verifier for Recorder {
+            fn verify(&self, chain: &[x509::Certificate]) -> Result<(), Error> {
+                *self.0.lock().unwrap() = chain
+                    .iter()
+                    .map(|cert| cert.subject().to_string())
+                    .collect();
+                Ok(())
+            }
+        }
+
+        let root = CertGenerator::new("root", CertKind::Ca).generate().unwrap();
+        let intermediate = CertGenerator::new("intermediate", CertKind::Ca)
+            .issuer(&root)
+            .generate()
+            .unwrap();
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .issuer(&intermediate)
+            .generate()
+            .unwrap();
+        let addr = serve(&server, &[&intermediate]);
+
+        let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+        let config = config(addr, root, false).with_verifier(recorder.clone());
+        assert_eq!(request(config).unwrap(), StatusCode::OK);
+
+        let chain = recorder.0.lock().unwrap().clone();
+        assert_eq!(chain[..2], ["CN=localhost", "CN=intermediate"]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::Path;
+
+use failure::ResultExt;
+use futures::future::{self, Either};
+use futures::Future;
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+
+use crate::proxy::token::blocking;
+use crate::{BasicAuthSettings, Error, ErrorKind, ExtraCredentialSettings, ServiceSettings};
+
+/// Credentials sent to the backend besides the token. They are read from
+/// files on every request, off the reactor, so that rotated secrets are
+/// picked up.
+#[derive(Clone, Debug, Default)]
+pub struct StaticCredentials {
+    basic_auth: Option<BasicAuthSettings>,
+    extra: Vec<ExtraCredentialSettings>,
+}
+
+impl StaticCredentials {
+    /// Creates the credentials configured for a service. The files are read
+    /// once here, so that a missing file is reported before the service
+    /// starts.
+    pub fn new(settings: &ServiceSettings) -> Result<Self, Error> {
+        let credentials = StaticCredentials {
+            basic_auth: settings.basic_auth().cloned(),
+            extra: settings.extra_credentials().to_vec(),
+        };
+        credentials.read_headers()?;
+
+        Ok(credentials)
+    }
+
+    pub fn headers(&self) -> impl Future<Item = Vec<(HeaderName, HeaderValue)>, Error = Error> {
+        let credentials = self.clone();
+        if credentials.basic_auth.is_none() && credentials.extra.is_empty() {
+            return Either::A(future::ok(Vec::new()));
+        }
+
+        Either::B(blocking(move || credentials.read_headers()))
+    }
+
+    fn read_headers(&self) -> Result<Vec<(HeaderName, HeaderValue)>, Error> {
+        let mut headers = Vec::new();
+
+        if let Some(basic_auth) = &self.basic_auth {
+            let username = read(basic_auth.username())?;
+            let password = read(basic_auth.password())?;
+            let credentials = base64::encode(format!("{}:{}", username, password).as_bytes());
+
+            headers.push((
+                AUTHORIZATION,
+                value("Authorization", &format!("Basic {}", credentials))?,
+            ));
+        }
+
+        for extra in &self.extra {
+            let name = HeaderName::from_bytes(extra.header().as_bytes())
+                .context(ErrorKind::HeaderName(extra.header().to_owned()))?;
+            headers.push((name, value(extra.header(), &read(extra.file())?)?));
+        }
+
+        Ok(headers)
+    }
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    let secret = fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn value(header: &str, value: &str) -> Result<HeaderValue, Error> {
+    let mut value =
+        HeaderValue::from_str(value).context(ErrorKind::HeaderValue(header.to_owned()))?;
+    value.set_sensitive(true);
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use futures::Future;
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::proxy::credentials::StaticCredentials;
+    use crate::{BasicAuthSettings, ErrorKind, ExtraCredentialSettings, ServiceSettings};
+
+    fn settings(dir: &TempDir) -> ServiceSettings {
+        let path = |name| dir.path().join(name);
+        fs::write(path("username"), "edge\n").unwrap();
+        fs::write(path("password"), "s3cr3t:\n").unwrap();
+        fs::write(path("key"), "api-key").unwrap();
+
+        ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_basic_auth(BasicAuthSettings::new(&path("username"), &path("password")))
+        .with_extra_credential(ExtraCredentialSettings::new("x-api-key", &path("key")))
+    }
+
+    #[test]
+    fn it_rereads_credentials() {
+        let dir = TempDir::new().unwrap();
+        let credentials = StaticCredentials::new(&settings(&dir)).unwrap();
+
+        let headers = credentials.headers().wait().unwrap();
+        assert_eq!(headers[0].0, "authorization");
+        assert_eq!(headers[0].1, "Basic ZWRnZTpzM2NyM3Q6");
+        assert!(headers[0].1.is_sensitive());
+        assert_eq!(headers[1].0, "x-api-key");
+        assert_eq!(headers[1].1, "api-key");
+
+        fs::write(dir.path().join("key"), "rotated").unwrap();
+        assert_eq!(credentials.headers().wait().unwrap()[1].1, "rotated");
+    }
+
+    #[test]
+    fn it_requires_credential_files() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings(&dir);
+        let password = dir.path().join("password");
+        fs::remove_file(&password).unwrap();
+
+        let err = StaticCredentials::new(&settings).err().unwrap();
+
+        assert_eq!(err.kind(), &ErrorKind::File(password.display().to_string()));
+    }
+}
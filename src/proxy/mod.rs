@@ -1,7 +1,22 @@
+mod backend;
 mod client;
 mod config;
+mod connector;
+mod credentials;
+mod reload;
+pub(crate) mod revocation;
 mod service;
+mod token;
+mod verify;
 
-pub use self::config::{get_config, Config, TokenSource};
-pub use client::{Client, HttpClient};
+pub use self::config::{
+    certificate_files, get_config, pem_certificates, tls_connector_with, trust_anchors, Config,
+};
+pub use client::{Client, HttpClient, HyperHttpClient};
+pub use connector::{rejection, verification_error, HttpsConnector, Tls};
+pub use credentials::StaticCredentials;
+pub use reload::TlsReloader;
+pub use revocation::{load_crls, Crls};
 pub use service::ProxyService;
+pub use token::{token_source, SharedToken, TokenFuture, TokenSource};
+pub use verify::{spki_pin, PinVerifier, Verifier};
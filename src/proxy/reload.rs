@@ -0,0 +1,181 @@
+use std::fs;
+use std::time::Duration;
+
+use failure::{Fail, ResultExt};
+use futures::{Future, Stream};
+use log::{info, warn};
+use tokio::timer::Interval;
+
+use crate::proxy::load_crls;
+use crate::proxy::{certificate_files, tls_connector_with, Tls};
+use crate::{logging, Error, ErrorKind, ServiceSettings};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watches the CA certificates and revocation lists configured for a service
+/// and swaps the shared TLS connector and lists when they change. A failed
+/// reload keeps the previous connector.
+pub struct TlsReloader {
+    settings: ServiceSettings,
+    tls: Tls,
+    fingerprint: Vec<u8>,
+}
+
+impl TlsReloader {
+    pub fn new(settings: ServiceSettings, tls: Tls) -> Self {
+        let fingerprint = fingerprint(&settings).unwrap_or_default();
+
+        TlsReloader {
+            settings,
+            tls,
+            fingerprint,
+        }
+    }
+
+    /// Rebuilds the connector if the certificates changed since the last
+    /// attempt and returns whether it was replaced.
+    pub fn reload(&mut self) -> Result<bool, Error> {
+        let fingerprint = fingerprint(&self.settings)?;
+        if fingerprint == self.fingerprint {
+            return Ok(false);
+        }
+
+        // remember the attempt so that a broken file is reported only once
+        self.fingerprint = fingerprint;
+
+        let crls = load_crls(&self.settings)?;
+        let settings = &self.settings;
+        self.tls
+            .rebuild(|trust_bundle| tls_connector_with(settings, trust_bundle))?;
+        self.tls.crls().replace(crls);
+
+        Ok(true)
+    }
+
+    pub fn watch(mut self) -> impl Future<Item = (), Error = Error> {
+        Interval::new_interval(RELOAD_INTERVAL)
+            .map_err(|err| Error::from(err.context(ErrorKind::Tokio)))
+            .for_each(move |_| {
+                match self.reload() {
+                    Ok(true) => info!("Reloaded certificates for {}", self.settings.name()),
+                    Ok(false) => (),
+                    Err(err) => {
+                        warn!(
+                            "Could not reload certificates for {}, keeping previous ones",
+                            self.settings.name()
+                        );
+                        logging::failure(&err);
+                    }
+                }
+
+                Ok(())
+            })
+    }
+}
+
+fn fingerprint(settings: &ServiceSettings) -> Result<Vec<u8>, Error> {
+    let mut fingerprint = Vec::new();
+
+    let files = certificate_files(settings)?;
+    for path in files.iter().chain(settings.crls()) {
+        let content = fs::read(path).context(ErrorKind::File(path.display().to_string()))?;
+        fingerprint.extend(path.to_string_lossy().as_bytes());
+        fingerprint.extend(content);
+    }
+
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::proxy::revocation::tests::{certificate, x509_crl};
+    use crate::proxy::{get_config, TlsReloader};
+    use crate::supervisor::Status;
+    use crate::{ErrorKind, ServiceSettings};
+
+    #[test]
+    fn it_reloads_changed_certificate() {
+        let dir = TempDir::new().unwrap();
+
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+
+        let cert = dir.path().join("cert.pem");
+        let generate = || {
+            CertGenerator::new("localhost", CertKind::Ca)
+                .generate()
+                .unwrap()
+                .write_cert(&cert)
+                .unwrap();
+        };
+        generate();
+
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            Some(&cert),
+            &token,
+        );
+        let config = get_config(&settings, &Status::default(), None).unwrap();
+        let mut reloader = TlsReloader::new(settings, config.tls().clone());
+
+        assert!(!reloader.reload().unwrap());
+
+        fs::write(&cert, "cert").unwrap();
+        let err = reloader.reload().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::NoCertificates(cert.display().to_string())
+        );
+        assert!(!reloader.reload().unwrap());
+
+        generate();
+        assert!(reloader.reload().unwrap());
+    }
+
+    #[test]
+    fn it_reloads_changed_revocation_list() {
+        let dir = TempDir::new().unwrap();
+
+        let token = dir.path().join("token");
+        fs::write(&token, "token").unwrap();
+
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        let path = dir.path().join("ca.crl");
+        fs::write(&path, x509_crl(&ca, &[]).to_pem().unwrap()).unwrap();
+
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            &token,
+        )
+        .with_crls(vec![path.clone()]);
+        let config = get_config(&settings, &Status::default(), None).unwrap();
+        let crls = config.tls().crls().clone();
+        let mut reloader = TlsReloader::new(settings, config.tls().clone());
+        assert!(crls
+            .check(&certificate(cert.cert()), &certificate(ca.cert()))
+            .is_ok());
+
+        fs::write(&path, x509_crl(&ca, &[&cert]).to_pem().unwrap()).unwrap();
+        assert!(reloader.reload().unwrap());
+
+        let err = crls
+            .check(&certificate(cert.cert()), &certificate(ca.cert()))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Revoked("CN=iotedged".to_owned()));
+    }
+}
@@ -0,0 +1,368 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::ResultExt;
+use log::warn;
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+use openssl::x509::X509StoreContextRef;
+
+use crate::x509::{pem_blocks, Certificate, Crl};
+use crate::{Error, ErrorKind, ServiceSettings};
+
+const PEM_LABEL: &str = "X509 CRL";
+
+/// Certificate revocation lists shared between the upstream connector, the
+/// TLS entrypoint and the certificate watcher, so that reloaded lists apply to
+/// subsequent handshakes.
+#[derive(Clone, Default)]
+pub struct Crls(Arc<RwLock<Vec<Crl>>>);
+
+impl Crls {
+    pub fn new(crls: Vec<Crl>) -> Self {
+        Crls(Arc::new(RwLock::new(crls)))
+    }
+
+    pub fn replace(&self, crls: Vec<Crl>) {
+        *self.0.write().expect("crl lock poisoned") = crls;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().expect("crl lock poisoned").is_empty()
+    }
+
+    /// Fails if any of the lists signed by the issuer contains the
+    /// certificate or is past its next update. Lists carrying the issuer's
+    /// name but not its signature are ignored.
+    pub fn check(&self, cert: &Certificate, issuer: &Certificate) -> Result<(), Error> {
+        let crls = self.0.read().expect("crl lock poisoned");
+        if crls.is_empty() {
+            return Ok(());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+        for crl in crls.iter() {
+            if crl.issuer() != issuer.subject() {
+                continue;
+            }
+
+            if !crl.is_signed_by(issuer) {
+                warn!(
+                    "Ignoring revocation list of {} with invalid signature",
+                    crl.issuer()
+                );
+                continue;
+            }
+
+            if crl
+                .next_update()
+                .is_some_and(|next_update| next_update < now)
+            {
+                return Err(Error::from(ErrorKind::ExpiredCrl(crl.issuer().to_string())));
+            }
+
+            if crl.is_revoked(cert) {
+                return Err(Error::from(ErrorKind::Revoked(cert.subject().to_string())));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks every certificate of a chain, leaf first, against the lists of
+    /// the certificate following it. The last one is its own issuer.
+    pub fn check_chain(&self, chain: &[Certificate]) -> Result<(), Error> {
+        for (index, cert) in chain.iter().enumerate() {
+            self.check(cert, chain.get(index + 1).unwrap_or(cert))?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks a chain presented in a handshake, which usually leaves out the
+    /// trust anchor, after completing it with the anchor that issued its last
+    /// certificate.
+    #[cfg(feature = "rustls")]
+    pub fn check_presented(
+        &self,
+        mut chain: Vec<Certificate>,
+        anchors: &[Certificate],
+    ) -> Result<(), Error> {
+        let anchor = chain.last().and_then(|last| {
+            anchors.iter().find(|anchor| {
+                anchor.subject() == last.issuer() && anchor.subject() != last.subject()
+            })
+        });
+        chain.extend(anchor.cloned());
+
+        self.check_chain(&chain)
+    }
+
+    /// Checks the chain an OpenSSL verify callback is invoked for once it
+    /// reaches the leaf, which is verified last.
+    #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+    pub fn check_context(&self, context: &X509StoreContextRef) -> Result<(), Error> {
+        if context.error_depth() != 0 {
+            return Ok(());
+        }
+
+        let chain = match context.chain() {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+
+        let mut certs = Vec::new();
+        for cert in chain {
+            certs.push(Certificate::from_der(&cert.to_der()?)?);
+        }
+
+        self.check_chain(&certs)
+    }
+}
+
+pub fn load_crls(settings: &ServiceSettings) -> Result<Vec<Crl>, Error> {
+    let mut crls = Vec::new();
+
+    for path in settings.crls() {
+        crls.extend(load_file(path)?);
+    }
+
+    Ok(crls)
+}
+
+fn load_file(path: &Path) -> Result<Vec<Crl>, Error> {
+    let file = fs::read(path).context(ErrorKind::File(path.display().to_string()))?;
+    let invalid = || ErrorKind::InvalidCrl(path.display().to_string());
+
+    let pem = String::from_utf8_lossy(&file);
+    let blocks = pem_blocks(&pem, PEM_LABEL).context(invalid())?;
+    if blocks.is_empty() {
+        return Ok(vec![Crl::from_der(&file).context(invalid())?]);
+    }
+
+    let mut crls = Vec::new();
+    for der in blocks {
+        crls.push(Crl::from_der(&der).context(invalid())?);
+    }
+
+    Ok(crls)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time, Asn1TimeRef};
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::extension::CrlNumber;
+    use openssl::x509::X509;
+    use openssl::x509::{X509Crl, X509CrlBuilder, X509Extension, X509RevokedBuilder};
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind, Certificate};
+    use crate::proxy::{load_crls, Crls};
+    use crate::x509::{self, Crl};
+    use crate::{ErrorKind, ServiceSettings};
+
+    pub fn crl(ca: &Certificate, revoked: &[&Certificate]) -> Crl {
+        parse(&x509_crl(ca, revoked))
+    }
+
+    pub fn x509_crl(ca: &Certificate, revoked: &[&Certificate]) -> X509Crl {
+        crl_until(ca, revoked, &Asn1Time::days_from_now(7).unwrap())
+    }
+
+    fn parse(crl: &X509Crl) -> Crl {
+        Crl::from_der(&crl.to_der().unwrap()).unwrap()
+    }
+
+    pub fn certificate(cert: &X509) -> x509::Certificate {
+        x509::Certificate::from_der(&cert.to_der().unwrap()).unwrap()
+    }
+
+    fn crl_until(ca: &Certificate, revoked: &[&Certificate], next_update: &Asn1TimeRef) -> X509Crl {
+        let mut builder = X509CrlBuilder::new().unwrap();
+        builder.set_issuer_name(ca.cert().subject_name()).unwrap();
+        builder
+            .set_last_update(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder.set_next_update(next_update).unwrap();
+
+        // AuthorityKeyIdentifier ::= SEQUENCE { keyIdentifier [0] OCTET STRING }
+        let key_id = ca.cert().subject_key_id().unwrap().as_slice();
+        let mut authority_key_id = vec![0x30, key_id.len() as u8 + 2, 0x80, key_id.len() as u8];
+        authority_key_id.extend(key_id);
+        let authority_key_id = X509Extension::new_from_der(
+            &Asn1Object::from_str("2.5.29.35").unwrap(),
+            false,
+            &Asn1OctetString::new_from_bytes(&authority_key_id).unwrap(),
+        )
+        .unwrap();
+        builder.append_extension(authority_key_id).unwrap();
+        let number = CrlNumber::new(BigNum::from_u32(1).unwrap()).unwrap();
+        builder.append_extension(number.build().unwrap()).unwrap();
+
+        for cert in revoked {
+            let mut entry = X509RevokedBuilder::new().unwrap();
+            entry
+                .set_serial_number(cert.cert().serial_number())
+                .unwrap();
+            entry
+                .set_revocation_date(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            builder.add_revoked(entry.build()).unwrap();
+        }
+        builder.sort().unwrap();
+        builder.sign(ca.key(), MessageDigest::sha256()).unwrap();
+        builder.build().unwrap()
+    }
+
+    fn settings(crls: Vec<&Path>) -> ServiceSettings {
+        ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_crls(crls.into_iter().map(Path::to_path_buf).collect())
+    }
+
+    fn issue(name: &str, ca: &Certificate) -> Certificate {
+        CertGenerator::new(name, CertKind::Client)
+            .issuer(ca)
+            .generate()
+            .unwrap()
+    }
+
+    #[test]
+    fn it_rejects_revoked_certificate() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let revoked = issue("module-a", &ca);
+        let valid = issue("module-b", &ca);
+
+        let crls = Crls::new(vec![crl(&ca, &[&revoked])]);
+
+        assert!(crls
+            .check(&certificate(valid.cert()), &certificate(ca.cert()))
+            .is_ok());
+        let err = crls
+            .check(&certificate(revoked.cert()), &certificate(ca.cert()))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::Revoked("CN=module-a".to_owned()));
+
+        crls.replace(Vec::new());
+        assert!(crls
+            .check(&certificate(revoked.cert()), &certificate(ca.cert()))
+            .is_ok());
+    }
+
+    #[test]
+    fn it_ignores_lists_of_other_issuers() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let other = CertGenerator::new("other", CertKind::Ca)
+            .generate()
+            .unwrap();
+        let cert = issue("module-a", &ca);
+
+        let crls = Crls::new(vec![crl(&other, &[&cert])]);
+
+        assert!(crls
+            .check(&certificate(cert.cert()), &certificate(ca.cert()))
+            .is_ok());
+    }
+
+    #[test]
+    fn it_ignores_lists_not_signed_by_issuer() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let forged = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let cert = issue("module-a", &ca);
+
+        let crls = Crls::new(vec![crl(&forged, &[&cert])]);
+
+        assert!(crls
+            .check(&certificate(cert.cert()), &certificate(ca.cert()))
+            .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_expired_list() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let cert = issue("module-a", &ca);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let expired = Asn1Time::from_unix(now - 60).unwrap();
+        let crls = Crls::new(vec![parse(&crl_until(&ca, &[], &expired))]);
+
+        let err = crls
+            .check(&certificate(cert.cert()), &certificate(ca.cert()))
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::ExpiredCrl("CN=ca".to_owned()));
+    }
+
+    #[test]
+    fn it_checks_whole_chain() {
+        let root = CertGenerator::new("root", CertKind::Ca).generate().unwrap();
+        let intermediate = CertGenerator::new("intermediate", CertKind::Ca)
+            .issuer(&root)
+            .generate()
+            .unwrap();
+        let cert = issue("module-a", &intermediate);
+        let chain = vec![
+            certificate(cert.cert()),
+            certificate(intermediate.cert()),
+            certificate(root.cert()),
+        ];
+
+        let crls = Crls::new(vec![crl(&root, &[]), crl(&intermediate, &[])]);
+        assert!(crls.check_chain(&chain).is_ok());
+
+        crls.replace(vec![crl(&root, &[&intermediate]), crl(&intermediate, &[])]);
+        let err = crls.check_chain(&chain).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Revoked("CN=intermediate".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_loads_pem_and_der_lists() {
+        let dir = TempDir::new().unwrap();
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+
+        let pem = dir.path().join("bundle.crl.pem");
+        let mut bundle = x509_crl(&ca, &[]).to_pem().unwrap();
+        bundle.extend(x509_crl(&ca, &[]).to_pem().unwrap());
+        fs::write(&pem, bundle).unwrap();
+
+        let der = dir.path().join("ca.crl");
+        fs::write(&der, x509_crl(&ca, &[]).to_der().unwrap()).unwrap();
+
+        let crls = load_crls(&settings(vec![&pem, &der])).unwrap();
+        assert_eq!(crls.len(), 3);
+    }
+
+    #[test]
+    fn it_fails_to_load_invalid_list() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ca.crl");
+        fs::write(&path, "not a crl").unwrap();
+
+        let err = load_crls(&settings(vec![&path])).err().unwrap();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidCrl(path.display().to_string())
+        );
+    }
+}
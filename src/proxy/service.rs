@@ -1,20 +1,27 @@
 use std::sync::Arc;
 
-use failure::{Compat, Fail};
+use failure::{Compat, Fail, ResultExt};
 use futures::future::FutureResult;
 use futures::{future, Future};
+use http::header::{HeaderName, HeaderValue};
 use hyper::service::{NewService, Service};
-use hyper::{Body, Request, Response};
-use log::debug;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{debug, info};
+use url::percent_encoding::percent_decode;
 
 use crate::proxy::{Client, HttpClient, TokenSource};
-use crate::{logging, Error};
+use crate::tls::ClientIdentity;
+use crate::{logging, ClientAuthSettings, CredentialTarget, Error, ErrorKind};
+
+type ServiceFuture = Box<dyn Future<Item = Response<Body>, Error = Compat<Error>> + Send>;
 
 pub struct ProxyService<T, S>
 where
     T: TokenSource,
 {
     client: Arc<Client<T, S>>,
+    client_auth: Option<Arc<ClientAuthSettings>>,
+    identity: Option<ClientIdentity>,
 }
 
 impl<T, S> ProxyService<T, S>
@@ -24,7 +31,89 @@ where
     pub fn new(client: Client<T, S>) -> Self {
         ProxyService {
             client: Arc::new(client),
+            client_auth: None,
+            identity: None,
+        }
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuthSettings) -> Self {
+        self.client_auth = Some(Arc::new(client_auth));
+        self
+    }
+
+    pub fn with_identity(&self, identity: Option<ClientIdentity>) -> Self {
+        let mut service = self.clone();
+        service.identity = identity;
+        service
+    }
+
+    fn is_allowed(&self, req: &Request<Body>) -> bool {
+        let client_auth = match &self.client_auth {
+            Some(client_auth) => client_auth,
+            None => return true,
+        };
+
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => return false,
+        };
+
+        // the backend URL is resolved without dot segments, so a path with
+        // them could reach a different path than the rules were checked for
+        let path = req.uri().path();
+        client_auth.rules().is_empty()
+            || (!has_dot_segments(path)
+                && client_auth
+                    .rules()
+                    .iter()
+                    .any(|rule| rule.allows(identity.names(), req.method(), path)))
+    }
+
+    /// Applies the access rules to a request and forwards the verified
+    /// identity of the client, or returns the response to a denied request.
+    fn authorize(
+        &self,
+        mut req: Request<Body>,
+        request: &str,
+    ) -> Result<Request<Body>, ServiceFuture> {
+        if !self.is_allowed(&req) {
+            info!("Denied request {}", request);
+            let res = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap();
+            return Err(Box::new(future::ok(res)));
+        }
+
+        if let Err(err) = self.forward_identity(&mut req) {
+            logging::failure(&err);
+            return Err(Box::new(future::err(err.compat())));
+        }
+
+        Ok(req)
+    }
+
+    fn forward_identity(&self, req: &mut Request<Body>) -> Result<(), Error> {
+        let header = match self
+            .client_auth
+            .as_ref()
+            .and_then(|auth| auth.identity_header())
+        {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+
+        let name = HeaderName::from_bytes(header.as_bytes())
+            .context(ErrorKind::HeaderName(header.to_owned()))?;
+        req.headers_mut().remove(&name);
+
+        if let Some(identity) = &self.identity {
+            let value = HeaderValue::from_str(identity.subject())
+                .context(ErrorKind::HeaderValue(header.to_owned()))?;
+            req.headers_mut().insert(name, value);
         }
+
+        Ok(())
     }
 }
 
@@ -35,6 +124,8 @@ where
     fn clone(&self) -> Self {
         ProxyService {
             client: self.client.clone(),
+            client_auth: self.client_auth.clone(),
+            identity: self.identity.clone(),
         }
     }
 }
@@ -42,28 +133,52 @@ where
 impl<T, S> Service for ProxyService<T, S>
 where
     T: TokenSource + 'static,
-    S: HttpClient + 'static,
+    S: HttpClient + Send + Sync + 'static,
 {
     type ReqBody = Body;
     type ResBody = Body;
     type Error = Compat<Error>;
-    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
+    type Future = ServiceFuture;
 
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        let request = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+        let secret = match self.client.config().credential().target() {
+            CredentialTarget::Query(parameter) => Some(parameter),
+            _ => None,
+        };
+        let request = format!(
+            "{} {} {:?}",
+            req.method(),
+            logging::redact(req.uri(), secret),
+            req.version()
+        );
+        let request = match &self.identity {
+            Some(identity) => format!("{} from {}", request, identity),
+            None => request,
+        };
         debug!("Starting request {}", request);
 
-        let fut = self
-            .client
-            .request(req)
-            .map_err(|err| {
-                logging::failure(&err);
-                err.compat()
-            })
-            .map(move |res| {
+        let req = match self.authorize(req, &request) {
+            Ok(req) => req,
+            Err(fut) => return fut,
+        };
+
+        let fut = self.client.request(req).then(move |res| match res {
+            Ok(res) => {
                 debug!("Finished request {}", request);
-                res
-            });
+                Ok(res)
+            }
+            Err(err) => {
+                logging::failure(&err);
+                match err.kind() {
+                    // the backend would refuse a request without a token
+                    ErrorKind::TokenUnavailable(_) => Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::empty())
+                        .unwrap()),
+                    _ => Err(err.compat()),
+                }
+            }
+        });
 
         Box::new(fut)
     }
@@ -72,7 +187,7 @@ where
 impl<T, S> NewService for ProxyService<T, S>
 where
     T: TokenSource + 'static,
-    S: HttpClient + 'static,
+    S: HttpClient + Send + Sync + 'static,
 {
     type ReqBody = Body;
     type ResBody = Body;
@@ -85,3 +200,179 @@ where
         future::ok(self.clone())
     }
 }
+
+/// Returns whether a path has `.` or `..` segments, including percent encoded
+/// ones and ones separated by backslashes, which URL parsing treats alike.
+fn has_dot_segments(path: &str) -> bool {
+    path.split(['/', '\\']).any(|segment| {
+        let segment = percent_decode(segment.as_bytes()).collect::<Vec<_>>();
+        segment == b"." || segment == b".."
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use futures::{future, Future};
+    use http::{Method, Request, Response, StatusCode};
+    use hyper::service::Service;
+    use hyper::Body;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::proxy::client::ResponseFuture;
+    use crate::proxy::config::tls_connector;
+    use crate::proxy::revocation::tests::certificate;
+    use crate::proxy::token::ValueToken;
+    use crate::proxy::{Client, Config, HttpClient, ProxyService, TokenFuture, TokenSource};
+    use crate::tls::ClientIdentity;
+    use crate::{
+        AccessRule, ClientAuthSettings, Error, ErrorKind, ServiceSettings, TokenFailurePolicy,
+    };
+
+    const HEADER: &str = "x-client-identity";
+
+    struct EchoIdentity;
+
+    impl HttpClient for EchoIdentity {
+        fn request(&self, req: Request<Body>) -> ResponseFuture {
+            let identity = req
+                .headers()
+                .get(HEADER)
+                .map(|value| value.to_str().unwrap().to_owned())
+                .unwrap_or_default();
+            Box::new(future::ok(Response::new(identity.into())))
+        }
+    }
+
+    fn service() -> ProxyService<ValueToken, EchoIdentity> {
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("https://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:8080").unwrap(),
+            None,
+            Path::new("token"),
+        );
+        let config = Config::new(
+            settings.backend().clone(),
+            ValueToken(None),
+            tls_connector(&settings).unwrap(),
+        );
+        let client_auth = ClientAuthSettings::new("ca.pem".as_ref())
+            .with_identity_header(HEADER)
+            .with_rule(AccessRule::new(
+                "module-a",
+                vec!["/api/".to_owned()],
+                vec!["GET".to_owned()],
+            ));
+
+        ProxyService::new(Client::with_client(EchoIdentity, config)).with_client_auth(client_auth)
+    }
+
+    #[test]
+    fn it_responds_unavailable_without_token() {
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("https://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:8080").unwrap(),
+            None,
+            Path::new("token"),
+        );
+        let config = Config::new(
+            settings.backend().clone(),
+            FailingToken,
+            tls_connector(&settings).unwrap(),
+        )
+        .with_token_failure(TokenFailurePolicy::Reject);
+        let mut service = ProxyService::new(Client::with_client(EchoIdentity, config));
+
+        let res = service.call(Request::new(Body::empty())).wait().unwrap();
+
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    struct FailingToken;
+
+    impl TokenSource for FailingToken {
+        fn get(&self) -> TokenFuture {
+            Box::new(future::err(Error::from(ErrorKind::Token(
+                "unavailable".to_owned(),
+            ))))
+        }
+    }
+
+    fn identity(name: &str) -> Option<ClientIdentity> {
+        let cert = CertGenerator::new(name, CertKind::Client)
+            .generate()
+            .unwrap();
+        Some(ClientIdentity::from_cert(&certificate(cert.cert())))
+    }
+
+    fn call(
+        service: &mut ProxyService<ValueToken, EchoIdentity>,
+        method: Method,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HEADER, "CN=spoofed")
+            .body(Body::empty())
+            .unwrap();
+        let res = service.call(req).wait().unwrap();
+        let status = res.status();
+        let body = futures::Stream::concat2(res.into_body()).wait().unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn it_forwards_verified_identity() {
+        let mut service = service().with_identity(identity("module-a"));
+
+        let (status, body) = call(&mut service, Method::GET, "/api/values");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "CN=module-a");
+    }
+
+    #[test]
+    fn it_denies_requests_not_matching_rules() {
+        let mut service = service().with_identity(identity("module-a"));
+        assert_eq!(
+            call(&mut service, Method::POST, "/api/values").0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call(&mut service, Method::GET, "/other").0,
+            StatusCode::FORBIDDEN
+        );
+        for path in &[
+            "/api/../other",
+            "/api/%2e%2E/other",
+            "/api/.%2e/other",
+            "/api/..\\other",
+            "/api/./values",
+        ] {
+            assert_eq!(
+                call(&mut service, Method::GET, path).0,
+                StatusCode::FORBIDDEN,
+                "{}",
+                path
+            );
+        }
+
+        let mut service = service.with_identity(identity("module-b"));
+        assert_eq!(
+            call(&mut service, Method::GET, "/api/values").0,
+            StatusCode::FORBIDDEN
+        );
+
+        let mut service = service.with_identity(None);
+        assert_eq!(
+            call(&mut service, Method::GET, "/api/values").0,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
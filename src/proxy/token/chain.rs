@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future};
+use log::warn;
+
+use crate::proxy::token::blocking;
+use crate::proxy::{SharedToken, TokenFuture, TokenSource};
+use crate::{logging, Error};
+
+/// Tries token sources in order and returns the first token any of them
+/// provides. The outcome of the last source is returned when none does.
+pub struct ChainToken {
+    name: String,
+    sources: Arc<Vec<SharedToken>>,
+}
+
+impl ChainToken {
+    pub fn new(name: &str, sources: Vec<SharedToken>) -> Self {
+        ChainToken {
+            name: name.to_owned(),
+            sources: Arc::new(sources),
+        }
+    }
+}
+
+impl TokenSource for ChainToken {
+    fn get(&self) -> TokenFuture {
+        get_from(self.name.clone(), self.sources.clone(), 0)
+    }
+}
+
+type Create = dyn Fn() -> Result<SharedToken, Error> + Send + Sync;
+
+/// Token source that could not be created at startup, such as a file that is
+/// not mounted yet. Creation is retried on requests until it succeeds.
+pub struct LazyToken {
+    create: Arc<Create>,
+    source: Arc<Mutex<Option<SharedToken>>>,
+}
+
+impl LazyToken {
+    pub fn new<F>(create: F) -> Self
+    where
+        F: Fn() -> Result<SharedToken, Error> + Send + Sync + 'static,
+    {
+        LazyToken {
+            create: Arc::new(create),
+            source: Arc::default(),
+        }
+    }
+}
+
+impl TokenSource for LazyToken {
+    fn get(&self) -> TokenFuture {
+        let source = self.source.lock().expect("token lock poisoned").clone();
+        if let Some(source) = source {
+            return source.get();
+        }
+
+        let create = self.create.clone();
+        let created = self.source.clone();
+        let fut = blocking(move || create()).and_then(move |source| {
+            let source = created
+                .lock()
+                .expect("token lock poisoned")
+                .get_or_insert(source)
+                .clone();
+            source.get()
+        });
+
+        Box::new(fut)
+    }
+}
+
+fn get_from(name: String, sources: Arc<Vec<SharedToken>>, index: usize) -> TokenFuture {
+    let fut = sources[index].get().then(move |result| -> TokenFuture {
+        let last = index + 1 == sources.len();
+        match result {
+            Ok(Some(token)) => Box::new(future::ok(Some(token))),
+            result if last => Box::new(future::result(result)),
+            Ok(None) => get_from(name, sources, index + 1),
+            Err(err) => {
+                warn!(
+                    "Could not obtain token for {} from source {}, trying the next one",
+                    name,
+                    index + 1
+                );
+                logging::failure(&err);
+                get_from(name, sources, index + 1)
+            }
+        }
+    });
+
+    Box::new(fut)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{future, Future};
+
+    use crate::proxy::token::chain::ChainToken;
+    use crate::proxy::token::ValueToken;
+    use crate::proxy::{SharedToken, TokenFuture, TokenSource};
+    use crate::{Error, ErrorKind};
+
+    struct Failing(&'static str, Arc<Mutex<usize>>);
+
+    impl TokenSource for Failing {
+        fn get(&self) -> TokenFuture {
+            *self.1.lock().unwrap() += 1;
+            Box::new(future::err(Error::from(ErrorKind::Token(
+                self.0.to_owned(),
+            ))))
+        }
+    }
+
+    fn failing(message: &'static str) -> (SharedToken, Arc<Mutex<usize>>) {
+        let calls = Arc::new(Mutex::new(0));
+        (Arc::new(Failing(message, calls.clone())), calls)
+    }
+
+    fn value(token: Option<&str>) -> SharedToken {
+        Arc::new(ValueToken(token.map(ToOwned::to_owned)))
+    }
+
+    #[test]
+    fn it_falls_back_to_next_source() {
+        let (primary, _) = failing("primary");
+        let chain = ChainToken::new(
+            "management",
+            vec![primary, value(None), value(Some("token"))],
+        );
+
+        assert_eq!(chain.get().wait().unwrap(), Some("token".to_owned()));
+    }
+
+    #[test]
+    fn it_stops_at_first_token() {
+        let (secondary, calls) = failing("secondary");
+        let chain = ChainToken::new("management", vec![value(Some("token")), secondary]);
+
+        assert_eq!(chain.get().wait().unwrap(), Some("token".to_owned()));
+        assert_eq!(*calls.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn it_returns_outcome_of_last_source() {
+        let (primary, _) = failing("primary");
+        let (secondary, _) = failing("secondary");
+        let chain = ChainToken::new("management", vec![primary, secondary]);
+
+        assert_eq!(
+            chain.get().wait().err().unwrap().kind(),
+            &ErrorKind::Token("secondary".to_owned())
+        );
+
+        let (primary, _) = failing("primary");
+        let chain = ChainToken::new("management", vec![primary, value(None)]);
+        assert_eq!(chain.get().wait().unwrap(), None);
+    }
+}
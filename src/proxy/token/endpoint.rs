@@ -0,0 +1,200 @@
+use std::cmp;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::{Fail, ResultExt};
+use futures::future::{self, Shared};
+use futures::{Future, Stream};
+use hyper::{Body, Client as HyperClient, Request};
+use log::warn;
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::Deserialize;
+
+use crate::proxy::backend;
+use crate::proxy::{pem_certificates, HttpsConnector, Tls, TokenFuture};
+use crate::{Error, ErrorKind};
+
+const REFRESH_BEFORE: Duration = Duration::from_secs(60);
+
+/// Lifetime assumed for tokens issued without `expires_in`.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+pub type EndpointClient = HyperClient<HttpsConnector, Body>;
+
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    token: String,
+    refresh_at: Instant,
+    expires_at: Instant,
+}
+
+impl AccessToken {
+    pub fn new(token: String, expires_in: Option<u64>) -> Self {
+        let lifetime = expires_in.map_or(DEFAULT_LIFETIME, Duration::from_secs);
+        AccessToken::with_lifetime(token, lifetime, cmp::min(REFRESH_BEFORE, lifetime / 5))
+    }
+
+    pub fn with_lifetime(token: String, lifetime: Duration, refresh_before: Duration) -> Self {
+        let now = Instant::now();
+
+        AccessToken {
+            token,
+            refresh_at: now + lifetime.checked_sub(refresh_before).unwrap_or_default(),
+            expires_at: now + lifetime,
+        }
+    }
+}
+
+type Refresh = Shared<Box<dyn Future<Item = AccessToken, Error = ErrorKind> + Send>>;
+
+#[derive(Default)]
+struct State {
+    token: Option<AccessToken>,
+    refresh: Option<Refresh>,
+}
+
+/// Access token reused until shortly before it expires. Concurrent requests
+/// share a single refresh and keep using the previous token while it is
+/// still valid.
+#[derive(Clone, Default)]
+pub struct AccessTokenCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl AccessTokenCache {
+    pub fn get<F, R>(&self, endpoint: &str, request: F) -> TokenFuture
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = AccessToken, Error = Error> + Send + 'static,
+    {
+        let mut state = self.state.lock().expect("token lock poisoned");
+        let now = Instant::now();
+        if let Some(token) = state.token.as_ref().filter(|token| now < token.refresh_at) {
+            return Box::new(future::ok(Some(token.token.clone())));
+        }
+
+        // requests keep using a token that is due for refresh but has not
+        // expired yet while another request is refreshing it
+        let current = state
+            .token
+            .as_ref()
+            .filter(|token| now < token.expires_at)
+            .map(|token| token.token.clone());
+
+        let refresh = match (&state.refresh, current.clone()) {
+            (Some(_), Some(current)) => return Box::new(future::ok(Some(current))),
+            (Some(refresh), None) => refresh.clone(),
+            (None, _) => {
+                let shared = self.state.clone();
+                let refresh: Box<dyn Future<Item = _, Error = _> + Send> =
+                    Box::new(request().then(move |result| {
+                        let mut state = shared.lock().expect("token lock poisoned");
+                        state.refresh = None;
+
+                        let token = result.map_err(|err| err.kind().clone())?;
+                        state.token = Some(token.clone());
+                        Ok(token)
+                    }));
+
+                let refresh = refresh.shared();
+                state.refresh = Some(refresh.clone());
+                refresh
+            }
+        };
+
+        let endpoint = endpoint.to_owned();
+        let fut = refresh.then(move |result| match (result, current) {
+            (Ok(token), _) => Ok(Some(token.token.clone())),
+            (Err(err), Some(current)) => {
+                warn!(
+                    "Could not refresh token from {}, keeping previous one: {}",
+                    endpoint, *err
+                );
+                Ok(Some(current))
+            }
+            (Err(err), None) => Err(Error::from((*err).clone())),
+        });
+
+        Box::new(fut)
+    }
+}
+
+pub fn endpoint_client(ca: Option<&Path>) -> Result<EndpointClient, Error> {
+    let anchors = match ca {
+        Some(path) => {
+            let pem =
+                fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
+            let certs = pem_certificates(&pem);
+            if certs.is_empty() {
+                return Err(Error::from(ErrorKind::NoCertificates(
+                    path.display().to_string(),
+                )));
+            }
+            certs
+        }
+        None => Vec::new(),
+    };
+
+    let tls = Tls::new(backend::client_connector(&anchors)?);
+    Ok(HyperClient::builder().build(HttpsConnector::new(tls)))
+}
+
+pub fn send<R>(
+    client: &EndpointClient,
+    req: Request<Body>,
+) -> impl Future<Item = R, Error = Error> + Send
+where
+    R: DeserializeOwned + Send + 'static,
+{
+    let operation = format!("{} {}", req.method(), req.uri());
+
+    client
+        .request(req)
+        .and_then(|res| {
+            let status = res.status();
+            res.into_body().concat2().map(move |body| (status, body))
+        })
+        .then(move |result| {
+            let (status, body) = result.map_err(|err| {
+                let kind = ErrorKind::Token(format!("could not reach {}", operation));
+                Error::from(err.context(kind))
+            })?;
+
+            if !status.is_success() {
+                return Err(Error::from(ErrorKind::Token(format!(
+                    "{} responded with {}: {}",
+                    operation,
+                    status,
+                    String::from_utf8_lossy(&body)
+                ))));
+            }
+
+            let res = serde_json::from_slice(&body).context(ErrorKind::Token(format!(
+                "{} returned an invalid response",
+                operation
+            )))?;
+            Ok(res)
+        })
+}
+
+/// Deserializes a number of seconds, which some token endpoints send as a
+/// string.
+pub fn seconds<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Number(u64),
+        Text(String),
+    }
+
+    match Option::<Seconds>::deserialize(deserializer)? {
+        Some(Seconds::Number(seconds)) => Ok(Some(seconds)),
+        Some(Seconds::Text(seconds)) => seconds.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
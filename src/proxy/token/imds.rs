@@ -0,0 +1,155 @@
+use failure::ResultExt;
+use futures::{future, Future};
+use http::header::ACCEPT;
+use hyper::{Body, Request};
+use serde::Deserialize;
+
+use crate::proxy::token::endpoint::{self, AccessToken, AccessTokenCache, EndpointClient};
+use crate::proxy::{TokenFuture, TokenSource};
+use crate::{Error, ErrorKind, ManagedIdentitySettings};
+
+/// Access token of a managed identity issued by the instance metadata
+/// service. Tokens are reused until shortly before they expire and
+/// concurrent requests share a single refresh.
+pub struct ImdsToken {
+    client: EndpointClient,
+    settings: ManagedIdentitySettings,
+    cache: AccessTokenCache,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+
+    #[serde(default, deserialize_with = "endpoint::seconds")]
+    expires_in: Option<u64>,
+}
+
+impl ImdsToken {
+    pub fn new(settings: &ManagedIdentitySettings) -> Result<Self, Error> {
+        Ok(ImdsToken {
+            client: endpoint::endpoint_client(None)?,
+            settings: settings.clone(),
+            cache: AccessTokenCache::default(),
+        })
+    }
+
+    fn request(&self) -> impl Future<Item = AccessToken, Error = Error> + Send {
+        let mut url = self.settings.endpoint().clone();
+        url.query_pairs_mut()
+            .append_pair("api-version", self.settings.api_version())
+            .append_pair("resource", self.settings.resource());
+        if let Some(client_id) = self.settings.client_id() {
+            url.query_pairs_mut().append_pair("client_id", client_id);
+        }
+
+        let req = Request::get(url.as_str())
+            .header("Metadata", "true")
+            .header(ACCEPT, "application/json")
+            .body(Body::empty())
+            .context(ErrorKind::Token("invalid token request".to_owned()))
+            .map_err(Error::from);
+
+        let client = self.client.clone();
+        future::result(req)
+            .and_then(move |req| endpoint::send(&client, req))
+            .map(|res: TokenResponse| AccessToken::new(res.access_token, res.expires_in))
+    }
+}
+
+impl TokenSource for ImdsToken {
+    fn get(&self) -> TokenFuture {
+        self.cache
+            .get(self.settings.endpoint().as_str(), || self.request())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use serde_json::json;
+    use tokio::runtime::current_thread::Runtime;
+    use url::Url;
+
+    use crate::proxy::token::imds::ImdsToken;
+    use crate::proxy::token::mock::MockServer;
+    use crate::proxy::TokenSource;
+    use crate::{ErrorKind, ManagedIdentitySettings};
+
+    fn settings(server: &MockServer) -> ManagedIdentitySettings {
+        let endpoint =
+            Url::parse(&format!("{}metadata/identity/oauth2/token", server.url())).unwrap();
+        ManagedIdentitySettings::new("https://vault.azure.net").with_endpoint(endpoint)
+    }
+
+    fn issue(expires_in: &'static str) -> MockServer {
+        MockServer::start(move |_| {
+            let body = json!({
+                "access_token": "access",
+                "expires_in": expires_in,
+                "resource": "https://vault.azure.net",
+                "token_type": "Bearer",
+            });
+            (200, body.to_string())
+        })
+    }
+
+    #[test]
+    fn it_requests_token_of_managed_identity() {
+        let server = issue("3599");
+        let settings = settings(&server).with_client_id("4d7f6e3c");
+        let token = ImdsToken::new(&settings).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        assert_eq!(
+            runtime.block_on(token.get()).unwrap(),
+            Some("access".to_owned())
+        );
+        assert_eq!(
+            runtime.block_on(token.get()).unwrap(),
+            Some("access".to_owned())
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(
+            requests[0].uri,
+            "/metadata/identity/oauth2/token?api-version=2018-02-01&resource=https%3A%2F%2Fvault.azure.net&client_id=4d7f6e3c"
+        );
+        assert_eq!(requests[0].headers["metadata"], "true");
+    }
+
+    #[test]
+    fn it_refreshes_token_before_expiry() {
+        let server = issue("0");
+        let token = ImdsToken::new(&settings(&server)).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        let both = token.get().join(token.get());
+        runtime.block_on(both).unwrap();
+        runtime.block_on(token.get()).unwrap();
+
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn it_reports_metadata_service_errors() {
+        let server = MockServer::start(|_| (400, r#"{"error":"invalid_request"}"#.to_owned()));
+        let settings = settings(&server);
+        let token = ImdsToken::new(&settings).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        let err = runtime.block_on(token.get()).err().unwrap();
+
+        match err.kind() {
+            ErrorKind::Token(message) => assert!(
+                message
+                    .ends_with("responded with 400 Bad Request: {\"error\":\"invalid_request\"}"),
+                "{}",
+                message
+            ),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
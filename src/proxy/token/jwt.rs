@@ -0,0 +1,232 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::ResultExt;
+use futures::Future;
+use serde::Deserialize;
+
+use crate::proxy::token::endpoint::{AccessToken, AccessTokenCache};
+use crate::proxy::{TokenFuture, TokenSource};
+use crate::supervisor::Status;
+use crate::{Error, ErrorKind};
+
+const REFRESH_BEFORE: Duration = Duration::from_secs(5 * 60);
+
+/// Wraps a source of JWTs, reusing a token until shortly before its `exp`
+/// claim passes and never returning an expired one. Signatures are not
+/// verified, that is left to the backend.
+pub struct JwtToken<T> {
+    inner: T,
+    name: String,
+    status: Status,
+    cache: AccessTokenCache,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: i64,
+}
+
+impl<T> JwtToken<T>
+where
+    T: TokenSource,
+{
+    pub fn new(inner: T, name: &str, status: &Status) -> Self {
+        JwtToken {
+            inner,
+            name: name.to_owned(),
+            status: status.clone(),
+            cache: AccessTokenCache::default(),
+        }
+    }
+}
+
+impl<T> TokenSource for JwtToken<T>
+where
+    T: TokenSource,
+{
+    fn get(&self) -> TokenFuture {
+        self.cache.get(&self.name, || {
+            checked(self.inner.get(), self.name.clone(), self.status.clone())
+        })
+    }
+}
+
+/// Rejects tokens that are not JWTs or have expired and records the expiry
+/// of valid ones.
+fn checked(
+    token: TokenFuture,
+    name: String,
+    status: Status,
+) -> impl Future<Item = AccessToken, Error = Error> + Send {
+    token.and_then(move |token| {
+        let token = token.ok_or_else(|| {
+            ErrorKind::Token(format!("token source of {} provided no token", name))
+        })?;
+
+        let expires = expiry(&token)?;
+        let now = now();
+        if expires <= now {
+            return Err(Error::from(ErrorKind::Token(format!(
+                "token of {} expired {} seconds ago",
+                name,
+                now - expires
+            ))));
+        }
+
+        status.token_expiry(&name, expires);
+        let lifetime = Duration::from_secs((expires - now) as u64);
+        Ok(AccessToken::with_lifetime(token, lifetime, REFRESH_BEFORE))
+    })
+}
+
+fn expiry(token: &str) -> Result<i64, Error> {
+    let invalid = || ErrorKind::Token("token is not a JWT with an exp claim".to_owned());
+
+    let mut parts = token.trim().split('.');
+    let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(payload), Some(_), None) => payload,
+        _ => return Err(Error::from(invalid())),
+    };
+
+    // JWTs use unpadded base64url encoding
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).context(invalid())?;
+    let claims: Claims = serde_json::from_slice(&payload).context(invalid())?;
+
+    Ok(claims.exp)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+
+    use crate::proxy::token::jwt::{expiry, now, JwtToken};
+    use crate::proxy::{TokenFuture, TokenSource};
+    use crate::supervisor::Status;
+    use crate::{Error, ErrorKind};
+
+    pub fn jwt(expires: i64) -> String {
+        let encode = |json: String| base64::encode_config(json.as_bytes(), base64::URL_SAFE_NO_PAD);
+
+        format!(
+            "{}.{}.signature",
+            encode(r#"{"alg":"none"}"#.to_owned()),
+            encode(format!(r#"{{"sub":"edge-proxy","exp":{}}}"#, expires))
+        )
+    }
+
+    struct Tokens(Mutex<Vec<Option<String>>>, Arc<Mutex<usize>>);
+
+    impl Tokens {
+        fn new(tokens: Vec<Option<String>>) -> (Self, Arc<Mutex<usize>>) {
+            let calls = Arc::new(Mutex::new(0));
+            (Tokens(Mutex::new(tokens), calls.clone()), calls)
+        }
+    }
+
+    impl TokenSource for Tokens {
+        fn get(&self) -> TokenFuture {
+            *self.1.lock().unwrap() += 1;
+            let mut tokens = self.0.lock().unwrap();
+            let token = if tokens.len() > 1 {
+                tokens.remove(0)
+            } else {
+                tokens[0].clone()
+            };
+            let result = token.ok_or_else(|| Error::from(ErrorKind::Token("failed".to_owned())));
+            Box::new(future::result(result.map(Some)))
+        }
+    }
+
+    #[test]
+    fn it_reads_expiry_of_jwt() {
+        let token = jwt(1_600_000_000);
+        assert_eq!(expiry(&token).unwrap(), 1_600_000_000);
+        assert_eq!(expiry(&format!("{}\n", token)).unwrap(), 1_600_000_000);
+
+        let err = expiry("opaque").err().unwrap();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token("token is not a JWT with an exp claim".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_reuses_token_until_refresh_is_due() {
+        let expires = now() + 3600;
+        let (tokens, calls) = Tokens::new(vec![Some(jwt(expires)), Some(jwt(expires + 3600))]);
+        let status = Status::default();
+
+        let token = JwtToken::new(tokens, "management", &status);
+
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert_eq!(
+            status.service("management").unwrap().token_expiry(),
+            Some(expires)
+        );
+    }
+
+    #[test]
+    fn it_refreshes_token_before_expiry() {
+        let expires = now() + 60;
+        let (tokens, _) = Tokens::new(vec![Some(jwt(expires)), Some(jwt(expires + 3600))]);
+        let status = Status::default();
+
+        let token = JwtToken::new(tokens, "management", &status);
+
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires + 3600)));
+        assert_eq!(
+            status.service("management").unwrap().token_expiry(),
+            Some(expires + 3600)
+        );
+    }
+
+    #[test]
+    fn it_keeps_valid_token_when_source_fails() {
+        let expires = now() + 60;
+        let (tokens, _) = Tokens::new(vec![Some(jwt(expires)), None]);
+
+        let token = JwtToken::new(tokens, "management", &Status::default());
+
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+    }
+
+    #[test]
+    fn it_refuses_expired_tokens() {
+        let (tokens, _) = Tokens::new(vec![Some(jwt(now() - 10))]);
+        let token = JwtToken::new(tokens, "management", &Status::default());
+
+        let err = token.get().wait().err().unwrap();
+        match err.kind() {
+            ErrorKind::Token(message) => {
+                assert!(message.starts_with("token of management expired"))
+            }
+            kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let expires = now() + 1;
+        let (tokens, _) = Tokens::new(vec![Some(jwt(expires)), None]);
+        let token = JwtToken::new(tokens, "management", &Status::default());
+        assert_eq!(token.get().wait().unwrap(), Some(jwt(expires)));
+
+        thread::sleep(Duration::from_secs(2));
+        assert_eq!(
+            token.get().wait().err().unwrap().kind(),
+            &ErrorKind::Token("failed".to_owned())
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Local stand-in for HTTP token endpoints.
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::sync::oneshot::{self, Sender};
+use futures::{Future, Stream};
+use http::HeaderMap;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server};
+use tokio::runtime::current_thread::Runtime;
+
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub method: String,
+    pub uri: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+type Handler = dyn Fn(&Received) -> (u16, String) + Send + Sync;
+
+pub struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Received>>>,
+    shutdown: Option<Sender<()>>,
+}
+
+impl MockServer {
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Received) -> (u16, String) + Send + Sync + 'static,
+    {
+        let handler: Arc<Handler> = Arc::new(handler);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = oneshot::channel();
+        let (addr_tx, addr_rx) = mpsc::channel();
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            let new_service = move || {
+                let handler = handler.clone();
+                let received = received.clone();
+                service_fn(move |req| handle(req, &handler, &received))
+            };
+
+            let mut runtime = Runtime::new().unwrap();
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(new_service);
+            addr_tx.send(server.local_addr()).unwrap();
+
+            let server = server.with_graceful_shutdown(rx.then(|_| Ok::<_, ()>(())));
+            runtime.block_on(server).unwrap();
+        });
+
+        MockServer {
+            addr: addr_rx.recv().unwrap(),
+            requests,
+            shutdown: Some(tx),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<Received> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            tx.send(()).unwrap_or(());
+        }
+    }
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+fn handle(
+    req: Request<Body>,
+    handler: &Arc<Handler>,
+    requests: &Arc<Mutex<Vec<Received>>>,
+) -> ResponseFuture {
+    let (parts, body) = req.into_parts();
+    let handler = handler.clone();
+    let requests = requests.clone();
+
+    let fut = body.concat2().map(move |body| {
+        let received = Received {
+            method: parts.method.to_string(),
+            uri: parts
+                .uri
+                .path_and_query()
+                .map_or("", |path| path.as_str())
+                .to_owned(),
+            headers: parts.headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        };
+
+        let (status, body) = handler(&received);
+        requests.lock().unwrap().push(received);
+
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    });
+
+    Box::new(fut)
+}
@@ -0,0 +1,435 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use failure::ResultExt;
+use futures::{future, Async, Future};
+use log::warn;
+
+use crate::logging;
+use crate::proxy::token::endpoint::{AccessToken, AccessTokenCache};
+use crate::supervisor::Status;
+use crate::workload::WorkloadClient;
+use crate::{CommandTokenSettings, Error, ErrorKind, ServiceSettings, TokenSourceSettings};
+
+mod chain;
+mod endpoint;
+mod imds;
+mod jwt;
+#[cfg(test)]
+pub mod mock;
+mod oauth;
+mod sas;
+
+pub use chain::{ChainToken, LazyToken};
+pub use imds::ImdsToken;
+pub use jwt::JwtToken;
+pub use oauth::OAuthToken;
+pub use sas::{SasToken, WorkloadSasToken};
+
+/// Resolves to the token sent to the backend, or to `None` if the source has
+/// no token to send.
+pub type TokenFuture = Box<dyn Future<Item = Option<String>, Error = Error> + Send>;
+
+pub trait TokenSource {
+    fn get(&self) -> TokenFuture;
+}
+
+impl<T> TokenSource for Arc<T>
+where
+    T: TokenSource + ?Sized,
+{
+    fn get(&self) -> TokenFuture {
+        (**self).get()
+    }
+}
+
+pub type SharedToken = Arc<dyn TokenSource + Send + Sync>;
+
+/// Creates the token source configured for a service. Local sources are read
+/// once here, so that a missing token is reported before the service starts.
+pub fn token_source(
+    settings: &ServiceSettings,
+    status: &Status,
+    workload: Option<&WorkloadClient>,
+) -> Result<SharedToken, Error> {
+    let source = create(settings.token_source(), settings.name(), workload)?;
+
+    if settings.jwt() && settings.token_source() != TokenSourceSettings::None {
+        return Ok(Arc::new(JwtToken::new(source, settings.name(), status)));
+    }
+
+    Ok(source)
+}
+
+fn create(
+    settings: TokenSourceSettings,
+    name: &str,
+    workload: Option<&WorkloadClient>,
+) -> Result<SharedToken, Error> {
+    let source: SharedToken = match settings {
+        TokenSourceSettings::File(path) => Arc::new(FileToken::new(&path)?),
+        TokenSourceSettings::Env(name) => Arc::new(EnvToken::new(&name)?),
+        TokenSourceSettings::Static(token) => Arc::new(ValueToken(Some(token))),
+        TokenSourceSettings::None => Arc::new(ValueToken(None)),
+        TokenSourceSettings::Command(command) => Arc::new(CommandToken::new(&command)?),
+        TokenSourceSettings::OAuth2(oauth) => Arc::new(OAuthToken::new(&oauth)?),
+        TokenSourceSettings::Sas(sas) => Arc::new(SasToken::new(&sas)?),
+        TokenSourceSettings::ManagedIdentity(identity) => Arc::new(ImdsToken::new(&identity)?),
+        TokenSourceSettings::WorkloadSas(sas) => {
+            let client = workload.ok_or_else(|| {
+                ErrorKind::Token(format!(
+                    "workload_sas of {} requires workload settings",
+                    name
+                ))
+            })?;
+            Arc::new(WorkloadSasToken::new(client, &sas))
+        }
+        TokenSourceSettings::Chain(sources) => {
+            // a source unavailable at startup stays in the chain and is
+            // created once it becomes available, as long as another one can
+            // stand in for it meanwhile
+            let mut chain: Vec<SharedToken> = Vec::new();
+            let mut available = false;
+            let mut unavailable = None;
+            for source in sources {
+                match create(source.clone(), name, workload) {
+                    Ok(source) => {
+                        chain.push(source);
+                        available = true;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Token source of {} is unavailable, retrying on requests",
+                            name
+                        );
+                        logging::failure(&err);
+                        unavailable = Some(err);
+
+                        let name = name.to_owned();
+                        let workload = workload.cloned();
+                        chain.push(Arc::new(LazyToken::new(move || {
+                            create(source.clone(), &name, workload.as_ref())
+                        })));
+                    }
+                }
+            }
+
+            match unavailable {
+                Some(err) if !available => return Err(err),
+                _ => Arc::new(ChainToken::new(name, chain)),
+            }
+        }
+    };
+
+    Ok(source)
+}
+
+#[derive(Clone, Debug)]
+pub struct ValueToken(pub Option<String>);
+
+impl TokenSource for ValueToken {
+    fn get(&self) -> TokenFuture {
+        Box::new(future::ok(self.0.clone()))
+    }
+}
+
+/// Token read from a file on every request, so that rotated tokens such as
+/// projected service account tokens are picked up.
+#[derive(Clone, Debug)]
+pub struct FileToken {
+    path: PathBuf,
+}
+
+impl FileToken {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        read(path)?;
+
+        Ok(FileToken {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl TokenSource for FileToken {
+    fn get(&self) -> TokenFuture {
+        let path = self.path.clone();
+        Box::new(blocking(move || read(&path).map(Some)))
+    }
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    let token = fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
+    Ok(token)
+}
+
+#[derive(Clone, Debug)]
+pub struct EnvToken {
+    name: String,
+}
+
+impl EnvToken {
+    pub fn new(name: &str) -> Result<Self, Error> {
+        let token = EnvToken {
+            name: name.to_owned(),
+        };
+        token.read()?;
+
+        Ok(token)
+    }
+
+    fn read(&self) -> Result<String, Error> {
+        let token = env::var(&self.name).context(ErrorKind::Token(format!(
+            "environment variable {} is not set",
+            self.name
+        )))?;
+        Ok(token)
+    }
+}
+
+impl TokenSource for EnvToken {
+    fn get(&self) -> TokenFuture {
+        Box::new(future::result(self.read().map(Some)))
+    }
+}
+
+/// Token printed by an external command. The output is reused until shortly
+/// before the TTL passes and concurrent requests share a single run.
+pub struct CommandToken {
+    settings: CommandTokenSettings,
+    cache: AccessTokenCache,
+}
+
+impl CommandToken {
+    pub fn new(settings: &CommandTokenSettings) -> Result<Self, Error> {
+        let source = CommandToken {
+            settings: settings.clone(),
+            cache: AccessTokenCache::default(),
+        };
+        source.get().wait()?;
+
+        Ok(source)
+    }
+
+    fn request(&self) -> impl Future<Item = AccessToken, Error = Error> + Send {
+        let settings = self.settings.clone();
+        let ttl = settings.ttl().as_secs();
+        blocking(move || run(&settings)).map(move |token| AccessToken::new(token, Some(ttl)))
+    }
+}
+
+impl TokenSource for CommandToken {
+    fn get(&self) -> TokenFuture {
+        self.cache.get(self.settings.program(), || self.request())
+    }
+}
+
+fn run(settings: &CommandTokenSettings) -> Result<String, Error> {
+    let program = settings.program();
+    let output = Command::new(program)
+        .args(settings.args())
+        .output()
+        .context(ErrorKind::Token(format!("could not run {}", program)))?;
+
+    if !output.status.success() {
+        return Err(Error::from(ErrorKind::Token(format!(
+            "{} {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+
+    let token = String::from_utf8(output.stdout).context(ErrorKind::Token(format!(
+        "{} printed an invalid token",
+        program
+    )))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(Error::from(ErrorKind::Token(format!(
+            "{} printed no token",
+            program
+        ))));
+    }
+
+    Ok(token.to_owned())
+}
+
+/// Runs blocking I/O on the thread pool, so that it does not stall the
+/// connections served by the reactor thread. Outside a thread pool, such as
+/// in the probe, the function runs in place.
+pub(crate) fn blocking<F, T>(f: F) -> impl Future<Item = T, Error = Error> + Send
+where
+    F: FnOnce() -> Result<T, Error> + Send,
+    T: Send,
+{
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        let result = tokio_threadpool::blocking(|| f.take().expect("polled after completion")());
+        match result {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => f.take().expect("polled after completion")().map(Async::Ready),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use futures::Future;
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+    use url::Url;
+
+    use crate::proxy::token::{token_source, CommandToken, EnvToken, FileToken, TokenSource};
+    use crate::supervisor::Status;
+    use crate::{CommandTokenSettings, ErrorKind, ServiceSettings, TokenSourceSettings};
+
+    fn counter(dir: &TempDir, ttl: Duration) -> CommandTokenSettings {
+        let script = format!(
+            "echo run >> {0}/runs; echo token-$(wc -l < {0}/runs)",
+            dir.path().display()
+        );
+        CommandTokenSettings::new("sh", vec!["-c".to_owned(), script], ttl)
+    }
+
+    #[test]
+    fn it_rereads_token_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "first").unwrap();
+
+        let token = FileToken::new(&path).unwrap();
+        assert_eq!(token.get().wait().unwrap(), Some("first".to_owned()));
+
+        fs::write(&path, "second").unwrap();
+        assert_eq!(token.get().wait().unwrap(), Some("second".to_owned()));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            token.get().wait().err().unwrap().kind(),
+            &ErrorKind::File(path.display().to_string())
+        );
+    }
+
+    #[test]
+    fn it_reads_token_from_environment() {
+        env::set_var("EDGE_PROXY_TEST_TOKEN", "token");
+        let token = EnvToken::new("EDGE_PROXY_TEST_TOKEN").unwrap();
+        assert_eq!(token.get().wait().unwrap(), Some("token".to_owned()));
+
+        let err = EnvToken::new("EDGE_PROXY_TEST_MISSING").err().unwrap();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token("environment variable EDGE_PROXY_TEST_MISSING is not set".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_caches_command_output_until_ttl_passes() {
+        let dir = TempDir::new().unwrap();
+
+        let cached = CommandToken::new(&counter(&dir, Duration::from_secs(300))).unwrap();
+        assert_eq!(cached.get().wait().unwrap(), Some("token-1".to_owned()));
+        assert_eq!(cached.get().wait().unwrap(), Some("token-1".to_owned()));
+
+        let expired = CommandToken::new(&counter(&dir, Duration::from_secs(0))).unwrap();
+        assert_eq!(expired.get().wait().unwrap(), Some("token-3".to_owned()));
+        assert_eq!(expired.get().wait().unwrap(), Some("token-4".to_owned()));
+    }
+
+    #[test]
+    fn it_shares_concurrent_command_runs() {
+        let dir = TempDir::new().unwrap();
+        let token = CommandToken::new(&counter(&dir, Duration::from_secs(0))).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        let both = runtime.block_on(token.get().join(token.get())).unwrap();
+
+        assert_eq!(
+            both,
+            (Some("token-2".to_owned()), Some("token-2".to_owned()))
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("runs"))
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn it_fails_when_command_fails() {
+        let settings = CommandTokenSettings::new(
+            "sh",
+            vec!["-c".to_owned(), "echo denied >&2; exit 3".to_owned()],
+            Duration::from_secs(300),
+        );
+
+        let err = CommandToken::new(&settings).err().unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token("sh exit status: 3: denied".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_creates_configured_token_source() {
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            Path::new("missing"),
+        );
+        assert_eq!(
+            token_source(&settings, &Status::default(), None)
+                .err()
+                .unwrap()
+                .kind(),
+            &ErrorKind::File("missing".to_owned())
+        );
+
+        let settings = settings.with_token_source(TokenSourceSettings::Static("token".to_owned()));
+        assert_eq!(
+            token_source(&settings, &Status::default(), None)
+                .unwrap()
+                .get()
+                .wait()
+                .unwrap(),
+            Some("token".to_owned())
+        );
+
+        let dir = TempDir::new().unwrap();
+        let primary = dir.path().join("token");
+        let settings = settings.with_token_source(TokenSourceSettings::Chain(vec![
+            TokenSourceSettings::File(primary.clone()),
+            TokenSourceSettings::Static("fallback".to_owned()),
+        ]));
+        let token = token_source(&settings, &Status::default(), None).unwrap();
+        assert_eq!(token.get().wait().unwrap(), Some("fallback".to_owned()));
+
+        fs::write(&primary, "primary").unwrap();
+        assert_eq!(token.get().wait().unwrap(), Some("primary".to_owned()));
+
+        let settings = settings.with_token_source(TokenSourceSettings::Chain(vec![
+            TokenSourceSettings::File(primary.with_extension("missing")),
+        ]));
+        assert_eq!(
+            token_source(&settings, &Status::default(), None)
+                .err()
+                .unwrap()
+                .kind(),
+            &ErrorKind::File(primary.with_extension("missing").display().to_string())
+        );
+    }
+}
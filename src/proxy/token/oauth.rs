@@ -0,0 +1,210 @@
+use std::fs;
+
+use failure::ResultExt;
+use futures::Future;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request};
+use serde::Deserialize;
+use url::form_urlencoded;
+
+use crate::proxy::token::blocking;
+use crate::proxy::token::endpoint::{self, AccessToken, AccessTokenCache, EndpointClient};
+use crate::proxy::{TokenFuture, TokenSource};
+use crate::{Error, ErrorKind, OAuthSettings};
+
+/// Access token obtained from an OAuth2 authorization server with the client
+/// credentials grant. Tokens are reused until shortly before they expire and
+/// concurrent requests share a single refresh.
+pub struct OAuthToken {
+    client: EndpointClient,
+    settings: OAuthSettings,
+    cache: AccessTokenCache,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+
+    #[serde(default, deserialize_with = "endpoint::seconds")]
+    expires_in: Option<u64>,
+}
+
+impl OAuthToken {
+    pub fn new(settings: &OAuthSettings) -> Result<Self, Error> {
+        read_secret(settings)?;
+
+        Ok(OAuthToken {
+            client: endpoint::endpoint_client(settings.ca())?,
+            settings: settings.clone(),
+            cache: AccessTokenCache::default(),
+        })
+    }
+
+    fn request(&self) -> impl Future<Item = AccessToken, Error = Error> + Send {
+        let settings = self.settings.clone();
+        let req = blocking(move || {
+            let secret = read_secret(&settings)?;
+
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "client_credentials")
+                .append_pair("client_id", settings.client_id())
+                .append_pair("client_secret", &secret);
+            if let Some(scope) = settings.scope() {
+                form.append_pair("scope", scope);
+            }
+
+            let req = Request::post(settings.token_endpoint().as_str())
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(ACCEPT, "application/json")
+                .body(Body::from(form.finish()))
+                .context(ErrorKind::Token("invalid token request".to_owned()))?;
+            Ok(req)
+        });
+
+        let client = self.client.clone();
+        req.and_then(move |req| endpoint::send(&client, req))
+            .map(|res: TokenResponse| AccessToken::new(res.access_token, res.expires_in))
+    }
+}
+
+impl TokenSource for OAuthToken {
+    fn get(&self) -> TokenFuture {
+        self.cache
+            .get(self.settings.token_endpoint().as_str(), || self.request())
+    }
+}
+
+fn read_secret(settings: &OAuthSettings) -> Result<String, Error> {
+    let path = settings.client_secret();
+    let secret = fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
+
+    Ok(secret.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::Future;
+    use serde_json::json;
+    use tempfile::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+    use url::Url;
+
+    use crate::proxy::token::mock::MockServer;
+    use crate::proxy::token::OAuthToken;
+    use crate::proxy::TokenSource;
+    use crate::{ErrorKind, OAuthSettings};
+
+    fn settings(dir: &TempDir, server: &MockServer) -> OAuthSettings {
+        let secret = dir.path().join("secret");
+        fs::write(&secret, "s3cr3t&\n").unwrap();
+
+        let endpoint = Url::parse(&format!("{}oauth2/token", server.url())).unwrap();
+        OAuthSettings::new(endpoint, "edge-proxy", &secret).with_scope("api://backend/.default")
+    }
+
+    fn issue(expires_in: u64) -> MockServer {
+        MockServer::start(move |_| {
+            let body = json!({
+                "access_token": "access",
+                "token_type": "Bearer",
+                "expires_in": expires_in,
+            });
+            (200, body.to_string())
+        })
+    }
+
+    #[test]
+    fn it_requests_token_with_client_credentials() {
+        let dir = TempDir::new().unwrap();
+        let server = issue(3600);
+        let token = OAuthToken::new(&settings(&dir, &server)).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        assert_eq!(
+            runtime.block_on(token.get()).unwrap(),
+            Some("access".to_owned())
+        );
+        assert_eq!(
+            runtime.block_on(token.get()).unwrap(),
+            Some("access".to_owned())
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].uri, "/oauth2/token");
+        assert_eq!(
+            requests[0].headers["content-type"],
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(
+            requests[0].body,
+            "grant_type=client_credentials&client_id=edge-proxy&client_secret=s3cr3t%26&scope=api%3A%2F%2Fbackend%2F.default"
+        );
+    }
+
+    #[test]
+    fn it_shares_concurrent_refreshes() {
+        let dir = TempDir::new().unwrap();
+        let server = issue(3600);
+        let token = OAuthToken::new(&settings(&dir, &server)).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        let both = token.get().join(token.get());
+
+        assert_eq!(
+            runtime.block_on(both).unwrap(),
+            (Some("access".to_owned()), Some("access".to_owned()))
+        );
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn it_refreshes_token_before_expiry() {
+        let dir = TempDir::new().unwrap();
+        let server = issue(0);
+        let token = OAuthToken::new(&settings(&dir, &server)).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        runtime.block_on(token.get()).unwrap();
+        runtime.block_on(token.get()).unwrap();
+
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn it_reports_token_endpoint_errors() {
+        let dir = TempDir::new().unwrap();
+        let server = MockServer::start(|_| (401, r#"{"error":"invalid_client"}"#.to_owned()));
+        let settings = settings(&dir, &server);
+        let token = OAuthToken::new(&settings).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+
+        let err = runtime.block_on(token.get()).err().unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token(format!(
+                "POST {} responded with 401 Unauthorized: {{\"error\":\"invalid_client\"}}",
+                settings.token_endpoint()
+            ))
+        );
+    }
+
+    #[test]
+    fn it_requires_client_secret() {
+        let dir = TempDir::new().unwrap();
+        let server = issue(3600);
+        let settings = settings(&dir, &server);
+        fs::remove_file(settings.client_secret()).unwrap();
+
+        let err = OAuthToken::new(&settings).err().unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::File(settings.client_secret().display().to_string())
+        );
+    }
+}
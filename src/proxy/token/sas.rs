@@ -0,0 +1,233 @@
+use std::cmp;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::ResultExt;
+use futures::Future;
+use ring::hmac;
+use url::form_urlencoded;
+
+use crate::proxy::token::blocking;
+use crate::proxy::token::endpoint::{AccessToken, AccessTokenCache};
+use crate::proxy::{TokenFuture, TokenSource};
+use crate::workload::WorkloadClient;
+use crate::{Error, ErrorKind, SasSettings, WorkloadSasSettings};
+
+const REFRESH_BEFORE: Duration = Duration::from_secs(5 * 60);
+
+/// Shared access signature for a resource, computed with HMAC-SHA256 from a
+/// key file. A signature is reused until shortly before it expires.
+pub struct SasToken {
+    settings: SasSettings,
+    cache: AccessTokenCache,
+}
+
+impl SasToken {
+    pub fn new(settings: &SasSettings) -> Result<Self, Error> {
+        let source = SasToken {
+            settings: settings.clone(),
+            cache: AccessTokenCache::default(),
+        };
+        source.get().wait()?;
+
+        Ok(source)
+    }
+
+    fn request(&self) -> impl Future<Item = AccessToken, Error = Error> + Send {
+        let settings = self.settings.clone();
+        let ttl = settings.ttl();
+        blocking(move || sign(&settings, now() + ttl.as_secs())).map(move |token| {
+            AccessToken::with_lifetime(token, ttl, cmp::min(REFRESH_BEFORE, ttl / 5))
+        })
+    }
+}
+
+impl TokenSource for SasToken {
+    fn get(&self) -> TokenFuture {
+        self.cache
+            .get(self.settings.resource_uri(), || self.request())
+    }
+}
+
+fn sign(settings: &SasSettings, expires: u64) -> Result<String, Error> {
+    let path = settings.key();
+    let key = fs::read_to_string(path).context(ErrorKind::File(path.display().to_string()))?;
+    let key = base64::decode(key.trim()).context(ErrorKind::Token(format!(
+        "{} does not hold a base64 encoded key",
+        path.display()
+    )))?;
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &key);
+    let resource = settings.resource_uri();
+    let signature = hmac::sign(&key, string_to_sign(resource, expires).as_bytes());
+
+    Ok(signature_token(
+        resource,
+        signature.as_ref(),
+        expires,
+        settings.policy(),
+    ))
+}
+
+/// Shared access signature for a resource, signed by the workload API with
+/// the key of the module identity. A signature is reused until shortly
+/// before it expires and concurrent requests share a single refresh.
+pub struct WorkloadSasToken {
+    client: WorkloadClient,
+    settings: WorkloadSasSettings,
+    cache: AccessTokenCache,
+}
+
+impl WorkloadSasToken {
+    pub fn new(client: &WorkloadClient, settings: &WorkloadSasSettings) -> Self {
+        WorkloadSasToken {
+            client: client.clone(),
+            settings: settings.clone(),
+            cache: AccessTokenCache::default(),
+        }
+    }
+
+    fn request(&self) -> impl Future<Item = AccessToken, Error = Error> + Send {
+        let ttl = self.settings.ttl();
+        let expires = now() + ttl.as_secs();
+        let resource = self.settings.resource_uri().to_owned();
+
+        self.client
+            .sign(
+                self.settings.key_id(),
+                string_to_sign(&resource, expires).as_bytes(),
+            )
+            .map(move |signature| {
+                let token = signature_token(&resource, &signature, expires, None);
+                AccessToken::with_lifetime(token, ttl, cmp::min(REFRESH_BEFORE, ttl / 5))
+            })
+    }
+}
+
+impl TokenSource for WorkloadSasToken {
+    fn get(&self) -> TokenFuture {
+        self.cache
+            .get(self.settings.resource_uri(), || self.request())
+    }
+}
+
+/// Returns the URL encoded resource and the expiry in seconds since the Unix
+/// epoch, separated by a newline, which is what the signature is computed
+/// over.
+fn string_to_sign(resource: &str, expires: u64) -> String {
+    let resource: String = form_urlencoded::byte_serialize(resource.as_bytes()).collect();
+    format!("{}\n{}", resource, expires)
+}
+
+fn signature_token(resource: &str, signature: &[u8], expires: u64, policy: Option<&str>) -> String {
+    let mut token = form_urlencoded::Serializer::new(String::new());
+    token
+        .append_pair("sr", resource)
+        .append_pair("sig", &base64::encode(signature))
+        .append_pair("se", &expires.to_string());
+    if let Some(policy) = policy {
+        token.append_pair("skn", policy);
+    }
+
+    token.finish()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use futures::Future;
+    use tempfile::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::proxy::token::sas::{sign, SasToken, WorkloadSasToken};
+    use crate::proxy::TokenSource;
+    use crate::workload::mock::MockWorkload;
+    use crate::{ErrorKind, SasSettings, WorkloadSasSettings};
+
+    fn settings(dir: &TempDir, ttl: Duration) -> SasSettings {
+        let key = dir.path().join("key");
+        fs::write(&key, "c2VjcmV0LWtleQ==\n").unwrap();
+
+        SasSettings::new("myhub.azure-devices.net/devices/edge", &key, ttl)
+    }
+
+    #[test]
+    fn it_signs_resource_with_key() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings(&dir, Duration::from_secs(3600));
+
+        assert_eq!(
+            sign(&settings, 1_600_000_000).unwrap(),
+            "sr=myhub.azure-devices.net%2Fdevices%2Fedge&sig=APeMbA7x5qxMDCCdcJ1ZIy4mD9ZeEz1gNmSEHAZdzpE%3D&se=1600000000"
+        );
+
+        assert_eq!(
+            sign(&settings.with_policy("iothubowner"), 1_600_000_000).unwrap(),
+            "sr=myhub.azure-devices.net%2Fdevices%2Fedge&sig=APeMbA7x5qxMDCCdcJ1ZIy4mD9ZeEz1gNmSEHAZdzpE%3D&se=1600000000&skn=iothubowner"
+        );
+    }
+
+    #[test]
+    fn it_reuses_signature_until_refresh_is_due() {
+        let dir = TempDir::new().unwrap();
+
+        let cached = SasToken::new(&settings(&dir, Duration::from_secs(3600))).unwrap();
+        let first = cached.get().wait().unwrap();
+        fs::write(dir.path().join("key"), "b3RoZXIta2V5").unwrap();
+        assert_eq!(cached.get().wait().unwrap(), first);
+
+        let expired = SasToken::new(&settings(&dir, Duration::from_secs(0))).unwrap();
+        let first = expired.get().wait().unwrap();
+        fs::write(dir.path().join("key"), "b3RoZXIta2V5").unwrap();
+        assert_ne!(expired.get().wait().unwrap(), first);
+    }
+
+    #[test]
+    fn it_signs_resource_with_workload_api() {
+        let workload = MockWorkload::start();
+        let dir = TempDir::new().unwrap();
+        let key = dir.path().join("key");
+        fs::write(&key, base64::encode(&workload.key())).unwrap();
+        let ttl = Duration::from_secs(3600);
+        let resource = "myhub.azure-devices.net/devices/edge/modules/edge-proxy";
+        let mut runtime = Runtime::new().unwrap();
+
+        let settings = WorkloadSasSettings::new(resource, ttl);
+        let token = WorkloadSasToken::new(&workload.client(), &settings);
+        let (signed, concurrent) = runtime.block_on(token.get().join(token.get())).unwrap();
+        let signed = signed.unwrap();
+        assert_eq!(concurrent.unwrap(), signed);
+        assert_eq!(runtime.block_on(token.get()).unwrap().unwrap(), signed);
+        assert_eq!(workload.requests().len(), 1);
+
+        let expires = signed.rsplit("se=").next().unwrap().parse().unwrap();
+        let local = SasSettings::new(resource, &key, ttl);
+        assert_eq!(sign(&local, expires).unwrap(), signed);
+    }
+
+    #[test]
+    fn it_requires_base64_key() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings(&dir, Duration::from_secs(3600));
+        fs::write(settings.key(), "not base64!").unwrap();
+
+        let err = SasToken::new(&settings).err().unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token(format!(
+                "{} does not hold a base64 encoded key",
+                settings.key().display()
+            ))
+        );
+    }
+}
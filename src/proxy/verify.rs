@@ -0,0 +1,108 @@
+use ring::digest::{digest, SHA256};
+
+use crate::x509::Certificate;
+use crate::{Error, ErrorKind};
+
+const PIN_PREFIX: &str = "sha256/";
+
+/// Check applied to the certificate chain of the backend, leaf first, after a
+/// successful handshake and before any request is sent over the connection.
+pub trait Verifier: Send + Sync {
+    fn verify(&self, chain: &[Certificate]) -> Result<(), Error>;
+}
+
+pub struct PinVerifier {
+    pins: Vec<String>,
+}
+
+impl PinVerifier {
+    pub fn new(pins: &[String]) -> Self {
+        let pins = pins
+            .iter()
+            .map(|pin| pin.trim_start_matches(PIN_PREFIX).to_owned())
+            .collect();
+
+        PinVerifier { pins }
+    }
+}
+
+impl Verifier for PinVerifier {
+    fn verify(&self, chain: &[Certificate]) -> Result<(), Error> {
+        let cert = chain.first().ok_or(ErrorKind::PeerCertificate)?;
+        let pin = spki_pin(cert);
+
+        if self.pins.contains(&pin) {
+            Ok(())
+        } else {
+            Err(Error::from(ErrorKind::PinMismatch(format!(
+                "{}{}",
+                PIN_PREFIX, pin
+            ))))
+        }
+    }
+}
+
+pub fn spki_pin(cert: &Certificate) -> String {
+    base64::encode(digest(&SHA256, cert.public_key_info()).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::sha::sha256;
+
+    use crate::cert::{self, CertGenerator, CertKind};
+    use crate::proxy::verify::{spki_pin, PinVerifier, Verifier};
+    use crate::x509::Certificate;
+    use crate::ErrorKind;
+
+    fn parse(cert: &cert::Certificate) -> Certificate {
+        Certificate::from_der(&cert.cert().to_der().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn it_pins_digest_of_public_key_info() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .generate()
+            .unwrap();
+        let spki = cert
+            .cert()
+            .public_key()
+            .unwrap()
+            .public_key_to_der()
+            .unwrap();
+
+        assert_eq!(spki_pin(&parse(&cert)), base64::encode(&sha256(&spki)));
+    }
+
+    #[test]
+    fn it_accepts_certificate_with_matching_pin() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .generate()
+            .unwrap();
+        let chain = [parse(&cert)];
+        let pin = spki_pin(&chain[0]);
+
+        let verifier = PinVerifier::new(&["other".to_owned(), format!("sha256/{}", pin)]);
+        assert!(verifier.verify(&chain).is_ok());
+
+        let verifier = PinVerifier::new(&[pin]);
+        assert!(verifier.verify(&chain).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_certificate_and_reports_observed_pin() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .generate()
+            .unwrap();
+        let chain = [parse(&cert)];
+        let pin = spki_pin(&chain[0]);
+
+        let verifier = PinVerifier::new(&["sha256/other".to_owned()]);
+        let err = verifier.verify(&chain).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::PinMismatch(format!("sha256/{}", pin))
+        );
+    }
+}
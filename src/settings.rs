@@ -1,132 +1,1487 @@
+use std::convert::TryFrom;
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use config::{Config, ConfigError, File, FileFormat};
 use failure::Fail;
+use http::header::{HeaderName, AUTHORIZATION};
+use http::Method;
+use log::warn;
 use serde::Deserialize;
+use url::percent_encoding::percent_decode;
 use url::Url;
-use url_serde;
 
+use crate::tls;
 use crate::{Error, ErrorKind};
 
 pub const DEFAULTS: &str = include_str!("../config/default.yaml");
 
 const TOKEN_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
 
+const WORKLOAD_URI: &str = "unix:///var/run/iotedge/workload.sock";
+
+const WORKLOAD_API_VERSION: &str = "2019-01-30";
+
+const IMDS_TOKEN_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+const IMDS_API_VERSION: &str = "2018-02-01";
+
+const PIN_PREFIX: &str = "sha256/";
+
+const PIN_LENGTH: usize = 32;
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
     services: Vec<ServiceSettings>,
     api: Option<ApiSettings>,
+    startup: StartupSettings,
+    expiry: ExpirySettings,
+
+    #[serde(default)]
+    tls: TlsSettings,
+
+    workload: Option<WorkloadSettings>,
+}
+
+impl Settings {
+    pub fn new(path: Option<&Path>) -> Result<Settings, Error> {
+        let mut config = Config::default();
+        config.merge(File::from_str(DEFAULTS, FileFormat::Yaml))?;
+
+        if let Some(path) = path {
+            config.merge(File::from(path))?;
+        }
+
+        let settings = convert(config)?;
+        Ok(settings)
+    }
+
+    pub fn services(&self) -> &Vec<ServiceSettings> {
+        &self.services
+    }
+
+    pub fn api(&self) -> Option<&ApiSettings> {
+        self.api.as_ref()
+    }
+
+    pub fn startup(&self) -> &StartupSettings {
+        &self.startup
+    }
+
+    pub fn expiry(&self) -> &ExpirySettings {
+        &self.expiry
+    }
+
+    pub fn tls(&self) -> &TlsSettings {
+        &self.tls
+    }
+
+    pub fn workload(&self) -> Option<&WorkloadSettings> {
+        self.workload.as_ref()
+    }
+}
+
+fn convert(config: Config) -> Result<Settings, Error> {
+    let mut settings: Settings = config.try_into()?;
+
+    if let Some(workload) = &settings.workload {
+        workload.validate()?;
+    }
+
+    // service level TLS settings take precedence over global ones
+    for service in &mut settings.services {
+        service.tls = service.tls.merge(&settings.tls);
+        service.validate()?;
+        service.validate_workload(settings.workload.as_ref())?;
+    }
+
+    Ok(settings)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServiceSettings {
+    name: String,
+
+    #[serde(with = "url_serde")]
+    entrypoint: Url,
+
+    #[serde(with = "url_serde")]
+    backend: Url,
+
+    certificate: Option<PathBuf>,
+
+    #[serde(default)]
+    certificates: Vec<PathBuf>,
+
+    #[serde(default = "default_system_roots")]
+    system_roots: bool,
+
+    #[serde(default)]
+    pins: Vec<String>,
+
+    tls_server_name: Option<String>,
+
+    #[serde(default)]
+    danger_accept_invalid_hostnames: bool,
+
+    #[serde(default)]
+    crls: Vec<PathBuf>,
+
+    #[serde(default)]
+    tls: TlsSettings,
+
+    server_certificate: Option<PathBuf>,
+
+    server_key: Option<PathBuf>,
+
+    client_auth: Option<ClientAuthSettings>,
+
+    workload_certificate: Option<WorkloadCertificateSettings>,
+
+    #[serde(default)]
+    workload_trust_bundle: bool,
+
+    #[serde(default = "default_token")]
+    token: PathBuf,
+
+    token_source: Option<TokenSourceSettings>,
+
+    #[serde(default)]
+    jwt: bool,
+
+    #[serde(default)]
+    credential: CredentialSettings,
+
+    #[serde(default)]
+    token_failure: TokenFailurePolicy,
+
+    basic_auth: Option<BasicAuthSettings>,
+
+    #[serde(default)]
+    extra_credentials: Vec<ExtraCredentialSettings>,
+}
+
+fn default_token() -> PathBuf {
+    Path::new(TOKEN_FILE).to_path_buf()
+}
+
+fn default_system_roots() -> bool {
+    true
+}
+
+impl ServiceSettings {
+    pub fn new(
+        name: String,
+        entrypoint: Url,
+        backend: Url,
+        cert: Option<&Path>,
+        token: &Path,
+    ) -> Self {
+        ServiceSettings {
+            name,
+            entrypoint,
+            backend,
+            certificate: cert.map(|cert| cert.to_path_buf()),
+            certificates: Vec::new(),
+            system_roots: true,
+            pins: Vec::new(),
+            tls_server_name: None,
+            danger_accept_invalid_hostnames: false,
+            crls: Vec::new(),
+            tls: TlsSettings::default(),
+            server_certificate: None,
+            server_key: None,
+            client_auth: None,
+            workload_certificate: None,
+            workload_trust_bundle: false,
+            token: token.to_path_buf(),
+            token_source: None,
+            jwt: false,
+            credential: CredentialSettings::default(),
+            token_failure: TokenFailurePolicy::default(),
+            basic_auth: None,
+            extra_credentials: Vec::new(),
+        }
+    }
+
+    pub fn with_certificates(mut self, certificates: Vec<PathBuf>) -> Self {
+        self.certificates = certificates;
+        self
+    }
+
+    pub fn with_system_roots(mut self, system_roots: bool) -> Self {
+        self.system_roots = system_roots;
+        self
+    }
+
+    pub fn with_pins(mut self, pins: Vec<String>) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    pub fn with_tls_server_name(mut self, tls_server_name: Option<String>) -> Self {
+        self.tls_server_name = tls_server_name;
+        self
+    }
+
+    pub fn with_danger_accept_invalid_hostnames(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_hostnames = accept;
+        self
+    }
+
+    pub fn with_crls(mut self, crls: Vec<PathBuf>) -> Self {
+        self.crls = crls;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsSettings) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuthSettings) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    pub fn with_workload_certificate(mut self, certificate: WorkloadCertificateSettings) -> Self {
+        self.workload_certificate = Some(certificate);
+        self
+    }
+
+    pub fn with_workload_trust_bundle(mut self, trust_bundle: bool) -> Self {
+        self.workload_trust_bundle = trust_bundle;
+        self
+    }
+
+    pub fn with_token_source(mut self, token_source: TokenSourceSettings) -> Self {
+        self.token_source = Some(token_source);
+        self
+    }
+
+    pub fn with_jwt(mut self, jwt: bool) -> Self {
+        self.jwt = jwt;
+        self
+    }
+
+    pub fn with_credential(mut self, credential: CredentialSettings) -> Self {
+        self.credential = credential;
+        self
+    }
+
+    pub fn with_token_failure(mut self, token_failure: TokenFailurePolicy) -> Self {
+        self.token_failure = token_failure;
+        self
+    }
+
+    pub fn with_basic_auth(mut self, basic_auth: BasicAuthSettings) -> Self {
+        self.basic_auth = Some(basic_auth);
+        self
+    }
+
+    pub fn with_extra_credential(mut self, credential: ExtraCredentialSettings) -> Self {
+        self.extra_credentials.push(credential);
+        self
+    }
+
+    pub fn with_server_identity(mut self, cert: &Path, key: &Path) -> Self {
+        self.server_certificate = Some(cert.to_path_buf());
+        self.server_key = Some(key.to_path_buf());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn entrypoint(&self) -> &Url {
+        &self.entrypoint
+    }
+
+    pub fn backend(&self) -> &Url {
+        &self.backend
+    }
+
+    pub fn certificate(&self) -> Option<&Path> {
+        self.certificate.as_ref().map(|path| path.as_ref())
+    }
+
+    /// Returns files, PEM bundles and directories of trusted CA certificates,
+    /// including the single `certificate` if configured.
+    pub fn certificates(&self) -> Vec<&Path> {
+        self.certificate
+            .iter()
+            .chain(self.certificates.iter())
+            .map(|path| path.as_ref())
+            .collect()
+    }
+
+    pub fn system_roots(&self) -> bool {
+        self.system_roots
+    }
+
+    /// Returns SPKI SHA-256 pins of the backend certificate. When any pin is
+    /// configured the certificate is accepted by its public key instead of
+    /// being validated against trusted CAs.
+    pub fn pins(&self) -> &[String] {
+        &self.pins
+    }
+
+    /// Returns the name sent as SNI and expected in the backend certificate
+    /// when it differs from the host of the backend URL.
+    pub fn tls_server_name(&self) -> Option<&str> {
+        self.tls_server_name.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Returns whether the backend certificate is accepted regardless of the
+    /// names it was issued for. The chain is still validated, but any
+    /// certificate issued by a trusted CA is accepted, so prefer
+    /// `tls_server_name` whenever the expected name is known.
+    pub fn danger_accept_invalid_hostnames(&self) -> bool {
+        self.danger_accept_invalid_hostnames
+    }
+
+    /// Returns PEM or DER encoded certificate revocation lists checked against
+    /// backend certificates and client certificates presented to the
+    /// entrypoint.
+    pub fn crls(&self) -> &[PathBuf] {
+        &self.crls
+    }
+
+    pub fn tls(&self) -> &TlsSettings {
+        &self.tls
+    }
+
+    pub fn server_certificate(&self) -> Option<&Path> {
+        self.server_certificate.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn server_key(&self) -> Option<&Path> {
+        self.server_key.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Returns the settings requiring clients of a TLS entrypoint to present
+    /// a certificate.
+    pub fn client_auth(&self) -> Option<&ClientAuthSettings> {
+        self.client_auth.as_ref()
+    }
+
+    /// Returns the settings of a server certificate requested from the
+    /// workload API instead of being read from `server_certificate` and
+    /// `server_key`.
+    pub fn workload_certificate(&self) -> Option<&WorkloadCertificateSettings> {
+        self.workload_certificate.as_ref()
+    }
+
+    /// Returns whether the trust bundle of the edge device, fetched from the
+    /// workload API, is trusted in addition to the configured certificates.
+    pub fn workload_trust_bundle(&self) -> bool {
+        self.workload_trust_bundle
+    }
+
+    pub fn token(&self) -> &Path {
+        &self.token
+    }
+
+    /// Returns where the token sent to the backend comes from if set, no
+    /// token with basic authentication and the token file otherwise.
+    pub fn token_source(&self) -> TokenSourceSettings {
+        match (&self.token_source, &self.basic_auth) {
+            (Some(token_source), _) => token_source.clone(),
+            (None, Some(_)) => TokenSourceSettings::None,
+            (None, None) => TokenSourceSettings::File(self.token.clone()),
+        }
+    }
+
+    /// Returns whether tokens are JWTs, which are reused until shortly before
+    /// their `exp` claim and never sent once expired.
+    pub fn jwt(&self) -> bool {
+        self.jwt
+    }
+
+    pub fn credential(&self) -> &CredentialSettings {
+        &self.credential
+    }
+
+    pub fn token_failure(&self) -> TokenFailurePolicy {
+        self.token_failure
+    }
+
+    pub fn basic_auth(&self) -> Option<&BasicAuthSettings> {
+        self.basic_auth.as_ref()
+    }
+
+    pub fn extra_credentials(&self) -> &[ExtraCredentialSettings] {
+        &self.extra_credentials
+    }
+
+    /// Returns the scheme preceding the token in the credential header if
+    /// not set explicitly, `SharedAccessSignature` for SAS tokens and
+    /// `Bearer` otherwise in the `Authorization` header. The token is sent on
+    /// its own in other headers, query parameters and cookies, or when the
+    /// scheme is empty.
+    pub fn auth_scheme(&self) -> &str {
+        if let Some(scheme) = self.credential.scheme() {
+            return scheme;
+        }
+
+        match (self.credential.target(), &self.token_source) {
+            (CredentialTarget::Header(header), _)
+                if !header.eq_ignore_ascii_case(AUTHORIZATION.as_str()) =>
+            {
+                ""
+            }
+            (CredentialTarget::Header(_), Some(source)) if source.is_sas() => {
+                "SharedAccessSignature"
+            }
+            (CredentialTarget::Header(_), _) => "Bearer",
+            (_, _) => "",
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        match self.entrypoint().scheme() {
+            "http" => (),
+            "https" => {
+                let files = self.server_certificate().is_some() && self.server_key().is_some();
+                if files == self.workload_certificate().is_some() {
+                    return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                        "{} requires either server_certificate and server_key or workload_certificate",
+                        self.entrypoint()
+                    ))));
+                }
+            }
+            _ => {
+                return Err(Error::from(ErrorKind::UnsupportedSchema(
+                    self.entrypoint().as_str().to_owned(),
+                )))
+            }
+        }
+
+        if self.backend().scheme() != "https" {
+            return Err(Error::from(ErrorKind::UnsupportedSchema(
+                self.backend().as_str().to_owned(),
+            )));
+        }
+
+        if self.workload_certificate().is_some() && self.entrypoint().scheme() != "https" {
+            return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                "workload_certificate requires an https entrypoint, but got {}",
+                self.entrypoint()
+            ))));
+        }
+
+        for pin in self.pins() {
+            let digest = pin
+                .strip_prefix(PIN_PREFIX)
+                .and_then(|digest| base64::decode(digest).ok())
+                .filter(|digest| digest.len() == PIN_LENGTH);
+            if digest.is_none() {
+                return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                    "pin {} of {} is not sha256/ followed by a base64 encoded SHA-256 digest",
+                    pin,
+                    self.name()
+                ))));
+            }
+        }
+
+        self.tls.validate()?;
+
+        self.credential.validate()?;
+        self.validate_basic_auth()?;
+        for credential in self.extra_credentials() {
+            credential.validate()?;
+        }
+
+        if let Some(token_source) = &self.token_source {
+            token_source.validate()?;
+        }
+
+        if let Some(client_auth) = self.client_auth() {
+            if self.entrypoint().scheme() != "https" {
+                return Err(Error::from(ErrorKind::InvalidClientAuth(format!(
+                    "{} is not an https entrypoint",
+                    self.entrypoint()
+                ))));
+            }
+
+            client_auth.validate()?;
+        }
+
+        if self.danger_accept_invalid_hostnames() {
+            warn!(
+                "Hostname verification of backend certificates is disabled for {}",
+                self.name()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn validate_basic_auth(&self) -> Result<(), Error> {
+        if self.basic_auth.is_none() || self.token_source() == TokenSourceSettings::None {
+            return Ok(());
+        }
+
+        match self.credential.target() {
+            CredentialTarget::Header(header)
+                if header.eq_ignore_ascii_case(AUTHORIZATION.as_str()) =>
+            {
+                Err(Error::from(ErrorKind::InvalidCredential(format!(
+                    "basic_auth of {} requires the token to be sent in another header, a query parameter or a cookie",
+                    self.name()
+                ))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn validate_workload(
+        &self,
+        workload: Option<&WorkloadSettings>,
+    ) -> Result<(), Error> {
+        if workload.is_some() {
+            return Ok(());
+        }
+
+        if self.workload_certificate().is_some() {
+            return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                "workload_certificate of {} requires workload settings",
+                self.name()
+            ))));
+        }
+
+        if self.workload_trust_bundle() {
+            return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                "workload_trust_bundle of {} requires workload settings",
+                self.name()
+            ))));
+        }
+
+        if self.token_source().uses_workload() {
+            return Err(Error::from(ErrorKind::Token(format!(
+                "workload_sas of {} requires workload settings",
+                self.name()
+            ))));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the token is sent to the backend, and what happens to credentials
+/// sent by the module itself. The token is sent in the `Authorization`
+/// header unless another header, a query parameter or a cookie is set.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct CredentialSettings {
+    header: Option<String>,
+    query: Option<String>,
+    cookie: Option<String>,
+    scheme: Option<String>,
+
+    #[serde(default)]
+    incoming: IncomingCredential,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CredentialTarget<'a> {
+    Header(&'a str),
+    Query(&'a str),
+    Cookie(&'a str),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IncomingCredential {
+    /// Removed before the request is forwarded.
+    Strip,
+
+    /// Forwarded as is, unless the token replaces it.
+    #[default]
+    Preserve,
+
+    /// Forwarded in the header of the given name.
+    Forward(String),
+}
+
+impl CredentialSettings {
+    pub fn header(header: &str) -> Self {
+        CredentialSettings {
+            header: Some(header.to_owned()),
+            ..CredentialSettings::default()
+        }
+    }
+
+    pub fn query(parameter: &str) -> Self {
+        CredentialSettings {
+            query: Some(parameter.to_owned()),
+            ..CredentialSettings::default()
+        }
+    }
+
+    pub fn cookie(cookie: &str) -> Self {
+        CredentialSettings {
+            cookie: Some(cookie.to_owned()),
+            ..CredentialSettings::default()
+        }
+    }
+
+    pub fn with_scheme(mut self, scheme: &str) -> Self {
+        self.scheme = Some(scheme.to_owned());
+        self
+    }
+
+    pub fn with_incoming(mut self, incoming: IncomingCredential) -> Self {
+        self.incoming = incoming;
+        self
+    }
+
+    pub fn target(&self) -> CredentialTarget<'_> {
+        match (&self.header, &self.query, &self.cookie) {
+            (Some(header), _, _) => CredentialTarget::Header(header),
+            (None, Some(parameter), _) => CredentialTarget::Query(parameter),
+            (None, None, Some(cookie)) => CredentialTarget::Cookie(cookie),
+            (None, None, None) => CredentialTarget::Header("Authorization"),
+        }
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn incoming(&self) -> &IncomingCredential {
+        &self.incoming
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let targets = [&self.header, &self.query, &self.cookie];
+        if targets.iter().filter(|target| target.is_some()).count() > 1 {
+            return Err(Error::from(ErrorKind::InvalidCredential(
+                "only one of header, query and cookie can be set".to_owned(),
+            )));
+        }
+
+        match self.target() {
+            CredentialTarget::Header(header) => {
+                HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                    ErrorKind::InvalidCredential(format!("invalid header {}", header))
+                })?;
+            }
+            _ if self.scheme.is_some() => {
+                return Err(Error::from(ErrorKind::InvalidCredential(
+                    "scheme applies only to headers".to_owned(),
+                )))
+            }
+            _ => (),
+        }
+
+        if let IncomingCredential::Forward(header) = &self.incoming {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| ErrorKind::InvalidCredential(format!("invalid header {}", header)))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// User name and password sent to the backend with HTTP basic
+/// authentication. Both files are read on every request, so that rotated
+/// credentials are picked up.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BasicAuthSettings {
+    username: PathBuf,
+    password: PathBuf,
+}
+
+impl BasicAuthSettings {
+    pub fn new(username: &Path, password: &Path) -> Self {
+        BasicAuthSettings {
+            username: username.to_path_buf(),
+            password: password.to_path_buf(),
+        }
+    }
+
+    pub fn username(&self) -> &Path {
+        &self.username
+    }
+
+    pub fn password(&self) -> &Path {
+        &self.password
+    }
+}
+
+/// Header sent to the backend with a value read from a file on every
+/// request.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ExtraCredentialSettings {
+    header: String,
+    file: PathBuf,
+}
+
+impl ExtraCredentialSettings {
+    pub fn new(header: &str, file: &Path) -> Self {
+        ExtraCredentialSettings {
+            header: header.to_owned(),
+            file: file.to_path_buf(),
+        }
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        HeaderName::from_bytes(self.header.as_bytes())
+            .map_err(|_| ErrorKind::InvalidCredential(format!("invalid header {}", self.header)))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientAuthSettings {
+    ca: PathBuf,
+    identity_header: Option<String>,
+
+    #[serde(default)]
+    rules: Vec<AccessRule>,
+}
+
+impl ClientAuthSettings {
+    pub fn new(ca: &Path) -> Self {
+        ClientAuthSettings {
+            ca: ca.to_path_buf(),
+            identity_header: None,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn with_identity_header(mut self, header: &str) -> Self {
+        self.identity_header = Some(header.to_owned());
+        self
+    }
+
+    pub fn with_rule(mut self, rule: AccessRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn ca(&self) -> &Path {
+        &self.ca
+    }
+
+    pub fn identity_header(&self) -> Option<&str> {
+        self.identity_header.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Returns the rules a request has to match one of. Every verified
+    /// client is allowed when no rules are configured.
+    pub fn rules(&self) -> &[AccessRule] {
+        &self.rules
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(header) = self.identity_header() {
+            HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                ErrorKind::InvalidClientAuth(format!("invalid identity header {}", header))
+            })?;
+        }
+
+        for rule in self.rules() {
+            for method in &rule.methods {
+                Method::from_bytes(method.as_bytes()).map_err(|_| {
+                    ErrorKind::InvalidClientAuth(format!("invalid method {}", method))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Allows a client to send requests with the given methods to paths starting
+/// with one of the given prefixes. Empty lists allow any method or path.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessRule {
+    client: String,
+
+    #[serde(default)]
+    paths: Vec<String>,
+
+    #[serde(default)]
+    methods: Vec<String>,
+}
+
+impl AccessRule {
+    pub fn new(client: &str, paths: Vec<String>, methods: Vec<String>) -> Self {
+        AccessRule {
+            client: client.to_owned(),
+            paths,
+            methods,
+        }
+    }
+
+    /// Returns the client name matched against the common name and subject
+    /// alternative names of the client certificate.
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    pub fn methods(&self) -> &[String] {
+        &self.methods
+    }
+
+    /// Paths match whole segments of a prefix once percent decoded. Encoded
+    /// separators are never allowed, as backends may decode them as ones.
+    pub fn allows(&self, names: &[String], method: &Method, path: &str) -> bool {
+        names.iter().any(|name| name == &self.client)
+            && (self.paths.is_empty() || self.allows_path(path))
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(method.as_str())))
+    }
+
+    fn allows_path(&self, path: &str) -> bool {
+        let lowercase = path.to_ascii_lowercase();
+        if lowercase.contains("%2f") || lowercase.contains("%5c") {
+            return false;
+        }
+
+        let path = match percent_decode(path.as_bytes()).decode_utf8() {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+
+        self.paths.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path == prefix
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiSettings {
+    #[serde(with = "url_serde")]
+    entrypoint: Url,
+}
+
+impl ApiSettings {
+    pub fn new(entrypoint: Url) -> Self {
+        ApiSettings { entrypoint }
+    }
+
+    pub fn entrypoint(&self) -> &Url {
+        &self.entrypoint
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StartupSettings {
+    wait_for_dependencies: bool,
+    timeout: u64,
+}
+
+impl StartupSettings {
+    pub fn new(wait_for_dependencies: bool, timeout: Duration) -> Self {
+        StartupSettings {
+            wait_for_dependencies,
+            timeout: timeout.as_secs(),
+        }
+    }
+
+    pub fn wait_for_dependencies(&self) -> bool {
+        self.wait_for_dependencies
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Days before a certificate expires at which a warning is logged.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExpirySettings {
+    thresholds: Vec<u64>,
+}
+
+impl ExpirySettings {
+    pub fn new(thresholds: Vec<u64>) -> Self {
+        ExpirySettings { thresholds }
+    }
+
+    pub fn thresholds(&self) -> &[u64] {
+        &self.thresholds
+    }
+}
+
+impl Default for ExpirySettings {
+    fn default() -> Self {
+        ExpirySettings::new(vec![30, 7, 1])
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkloadSettings {
+    #[serde(with = "url_serde", default = "default_workload_uri")]
+    uri: Url,
+
+    module_id: String,
+
+    generation_id: String,
+
+    #[serde(default = "default_workload_api_version")]
+    api_version: String,
+}
+
+fn default_workload_uri() -> Url {
+    Url::parse(WORKLOAD_URI).expect("valid workload uri")
+}
+
+fn default_workload_api_version() -> String {
+    WORKLOAD_API_VERSION.to_owned()
+}
+
+impl WorkloadSettings {
+    pub fn new(uri: Url, module_id: &str, generation_id: &str) -> Self {
+        WorkloadSettings {
+            uri,
+            module_id: module_id.to_owned(),
+            generation_id: generation_id.to_owned(),
+            api_version: WORKLOAD_API_VERSION.to_owned(),
+        }
+    }
+
+    pub fn uri(&self) -> &Url {
+        &self.uri
+    }
+
+    pub fn module_id(&self) -> &str {
+        &self.module_id
+    }
+
+    pub fn generation_id(&self) -> &str {
+        &self.generation_id
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.uri.scheme() != "unix" {
+            return Err(Error::from(ErrorKind::UnsupportedSchema(
+                self.uri.as_str().to_owned(),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WorkloadCertificateSettings {
+    common_name: Option<String>,
+
+    #[serde(default = "default_validity_days")]
+    validity_days: u64,
+}
+
+fn default_validity_days() -> u64 {
+    90
+}
+
+impl WorkloadCertificateSettings {
+    pub fn new(common_name: Option<String>, validity_days: u64) -> Self {
+        WorkloadCertificateSettings {
+            common_name,
+            validity_days,
+        }
+    }
+
+    /// Returns the common name requested for the certificate, the host of the
+    /// entrypoint if not set.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_ref().map(AsRef::as_ref)
+    }
+
+    /// Returns the requested validity. The workload API may issue a
+    /// certificate expiring earlier, which is renewed once four fifths of its
+    /// actual lifetime have passed.
+    pub fn validity(&self) -> Duration {
+        Duration::from_secs(self.validity_days * 24 * 60 * 60)
+    }
+}
+
+impl Default for WorkloadCertificateSettings {
+    fn default() -> Self {
+        WorkloadCertificateSettings::new(None, default_validity_days())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSourceSettings {
+    /// File read on every request, so that rotated tokens are picked up.
+    File(PathBuf),
+
+    /// Environment variable of the proxy process.
+    Env(String),
+
+    /// Token configured inline.
+    Static(String),
+
+    /// Standard output of an external command.
+    Command(CommandTokenSettings),
+
+    /// Access token obtained with the OAuth2 client credentials grant.
+    #[serde(rename = "oauth2")]
+    OAuth2(OAuthSettings),
+
+    /// Shared access signature computed from a key, as used by IoT Hub.
+    Sas(SasSettings),
+
+    /// Shared access signature signed by the workload API with the key of
+    /// the module identity.
+    WorkloadSas(WorkloadSasSettings),
+
+    /// Access token of a managed identity issued by the instance metadata
+    /// service.
+    ManagedIdentity(ManagedIdentitySettings),
+
+    /// No token is sent.
+    None,
+
+    /// Sources tried in order until one of them provides a token.
+    Chain(Vec<TokenSourceSettings>),
+}
+
+impl TokenSourceSettings {
+    pub fn is_sas(&self) -> bool {
+        match self {
+            TokenSourceSettings::Sas(_) | TokenSourceSettings::WorkloadSas(_) => true,
+            TokenSourceSettings::Chain(sources) => {
+                !sources.is_empty() && sources.iter().all(TokenSourceSettings::is_sas)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn uses_workload(&self) -> bool {
+        match self {
+            TokenSourceSettings::WorkloadSas(_) => true,
+            TokenSourceSettings::Chain(sources) => {
+                sources.iter().any(TokenSourceSettings::uses_workload)
+            }
+            _ => false,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match self {
+            TokenSourceSettings::OAuth2(oauth) => oauth.validate(),
+            TokenSourceSettings::ManagedIdentity(identity) => identity.validate(),
+            TokenSourceSettings::Chain(sources) if sources.is_empty() => Err(Error::from(
+                ErrorKind::Token("chain of token sources is empty".to_owned()),
+            )),
+            TokenSourceSettings::Chain(sources) => {
+                sources.iter().try_for_each(TokenSourceSettings::validate)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenFailurePolicy {
+    /// Forwarded without a token.
+    #[default]
+    Forward,
+
+    /// Rejected with 503 Service Unavailable.
+    Reject,
+}
+
+/// External command printing a token, which is reused until its TTL passes.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CommandTokenSettings {
+    program: String,
+
+    #[serde(default)]
+    args: Vec<String>,
+
+    #[serde(default = "default_command_ttl")]
+    ttl: u64,
+}
+
+fn default_command_ttl() -> u64 {
+    300
+}
+
+impl CommandTokenSettings {
+    pub fn new(program: &str, args: Vec<String>, ttl: Duration) -> Self {
+        CommandTokenSettings {
+            program: program.to_owned(),
+            args,
+            ttl: ttl.as_secs(),
+        }
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct OAuthSettings {
+    #[serde(with = "url_serde")]
+    token_endpoint: Url,
+
+    client_id: String,
+
+    client_secret: PathBuf,
+
+    scope: Option<String>,
+
+    ca: Option<PathBuf>,
+}
+
+impl OAuthSettings {
+    pub fn new(token_endpoint: Url, client_id: &str, client_secret: &Path) -> Self {
+        OAuthSettings {
+            token_endpoint,
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_path_buf(),
+            scope: None,
+            ca: None,
+        }
+    }
+
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = Some(scope.to_owned());
+        self
+    }
+
+    pub fn with_ca(mut self, ca: &Path) -> Self {
+        self.ca = Some(ca.to_path_buf());
+        self
+    }
+
+    pub fn token_endpoint(&self) -> &Url {
+        &self.token_endpoint
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Returns the file holding the client secret, which is read again for
+    /// every token request.
+    pub fn client_secret(&self) -> &Path {
+        &self.client_secret
+    }
+
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn ca(&self) -> Option<&Path> {
+        self.ca.as_ref().map(AsRef::as_ref)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match self.token_endpoint.scheme() {
+            "http" | "https" => Ok(()),
+            _ => Err(Error::from(ErrorKind::UnsupportedSchema(
+                self.token_endpoint.as_str().to_owned(),
+            ))),
+        }
+    }
 }
 
-impl Settings {
-    pub fn new(path: Option<&Path>) -> Result<Settings, Error> {
-        let mut config = Config::default();
-        config.merge(File::from_str(DEFAULTS, FileFormat::Yaml))?;
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SasSettings {
+    resource_uri: String,
 
-        if let Some(path) = path {
-            config.merge(File::from(path))?;
+    key: PathBuf,
+
+    policy: Option<String>,
+
+    #[serde(default = "default_sas_ttl")]
+    ttl: u64,
+}
+
+fn default_sas_ttl() -> u64 {
+    3600
+}
+
+impl SasSettings {
+    pub fn new(resource_uri: &str, key: &Path, ttl: Duration) -> Self {
+        SasSettings {
+            resource_uri: resource_uri.to_owned(),
+            key: key.to_path_buf(),
+            policy: None,
+            ttl: ttl.as_secs(),
         }
+    }
 
-        let settings = convert(config)?;
-        Ok(settings)
+    pub fn with_policy(mut self, policy: &str) -> Self {
+        self.policy = Some(policy.to_owned());
+        self
     }
 
-    pub fn services(&self) -> &Vec<ServiceSettings> {
-        &self.services
+    /// Returns the resource the signatures grant access to, such as
+    /// `myhub.azure-devices.net/devices/mydevice`.
+    pub fn resource_uri(&self) -> &str {
+        &self.resource_uri
     }
 
-    pub fn api(&self) -> Option<&ApiSettings> {
-        self.api.as_ref()
+    /// Returns the file holding the base64 encoded key, which is read again
+    /// whenever a signature is computed.
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+
+    /// Returns the name of the shared access policy the key belongs to, if
+    /// it is not a device key.
+    pub fn policy(&self) -> Option<&str> {
+        self.policy.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
     }
 }
 
-fn convert(config: Config) -> Result<Settings, Error> {
-    let settings: Settings = config.try_into()?;
+/// Resource of shared access signatures signed by the workload API, which
+/// keeps the key to itself.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WorkloadSasSettings {
+    resource_uri: String,
 
-    for settings in settings.services() {
-        if settings.entrypoint().scheme() != "http" {
-            return Err(Error::from(ErrorKind::UnsupportedSchema(
-                settings.entrypoint().as_str().to_owned(),
-            )));
-        }
+    #[serde(default = "default_workload_key_id")]
+    key_id: String,
 
-        if settings.backend().scheme() != "https" {
-            return Err(Error::from(ErrorKind::UnsupportedSchema(
-                settings.backend().as_str().to_owned(),
-            )));
+    #[serde(default = "default_sas_ttl")]
+    ttl: u64,
+}
+
+fn default_workload_key_id() -> String {
+    "primary".to_owned()
+}
+
+impl WorkloadSasSettings {
+    pub fn new(resource_uri: &str, ttl: Duration) -> Self {
+        WorkloadSasSettings {
+            resource_uri: resource_uri.to_owned(),
+            key_id: default_workload_key_id(),
+            ttl: ttl.as_secs(),
         }
     }
 
-    Ok(settings)
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = key_id.to_owned();
+        self
+    }
+
+    pub fn resource_uri(&self) -> &str {
+        &self.resource_uri
+    }
+
+    /// Returns the key of the module identity used for signing, `primary` if
+    /// not set.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct ServiceSettings {
-    name: String,
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ManagedIdentitySettings {
+    #[serde(with = "url_serde", default = "default_imds_endpoint")]
+    endpoint: Url,
 
-    #[serde(with = "url_serde")]
-    entrypoint: Url,
+    resource: String,
 
-    #[serde(with = "url_serde")]
-    backend: Url,
+    client_id: Option<String>,
 
-    certificate: Option<PathBuf>,
+    #[serde(default = "default_imds_api_version")]
+    api_version: String,
+}
 
-    #[serde(default = "default_token")]
-    token: PathBuf,
+fn default_imds_endpoint() -> Url {
+    Url::parse(IMDS_TOKEN_ENDPOINT).expect("valid instance metadata endpoint")
 }
 
-fn default_token() -> PathBuf {
-    Path::new(TOKEN_FILE).to_path_buf()
+fn default_imds_api_version() -> String {
+    IMDS_API_VERSION.to_owned()
 }
 
-impl ServiceSettings {
+impl ManagedIdentitySettings {
+    pub fn new(resource: &str) -> Self {
+        ManagedIdentitySettings {
+            endpoint: default_imds_endpoint(),
+            resource: resource.to_owned(),
+            client_id: None,
+            api_version: default_imds_api_version(),
+        }
+    }
+
+    pub fn with_endpoint(mut self, endpoint: Url) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_owned());
+        self
+    }
+
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
+    pub fn resource(&self) -> &str {
+        &self.resource
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match self.endpoint.scheme() {
+            "http" | "https" => Ok(()),
+            _ => Err(Error::from(ErrorKind::UnsupportedSchema(
+                self.endpoint.as_str().to_owned(),
+            ))),
+        }
+    }
+}
+
+/// TLS protocol policy applied to backend connections and TLS entrypoints.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TlsSettings {
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+    ciphers: Option<Vec<String>>,
+}
+
+impl TlsSettings {
     pub fn new(
-        name: String,
-        entrypoint: Url,
-        backend: Url,
-        cert: Option<&Path>,
-        token: &Path,
+        min_version: Option<TlsVersion>,
+        max_version: Option<TlsVersion>,
+        ciphers: Option<Vec<String>>,
     ) -> Self {
-        ServiceSettings {
-            name,
-            entrypoint,
-            backend,
-            certificate: cert.map(|cert| cert.to_path_buf()),
-            token: token.to_path_buf(),
+        TlsSettings {
+            min_version,
+            max_version,
+            ciphers,
         }
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
+    pub fn min_version(&self) -> Option<TlsVersion> {
+        self.min_version
     }
 
-    pub fn entrypoint(&self) -> &Url {
-        &self.entrypoint
+    pub fn max_version(&self) -> Option<TlsVersion> {
+        self.max_version
     }
 
-    pub fn backend(&self) -> &Url {
-        &self.backend
+    /// Returns allowed cipher suites. TLS 1.3 suites use IANA names such as
+    /// `TLS_AES_128_GCM_SHA256`, older ones OpenSSL names such as
+    /// `ECDHE-RSA-AES128-GCM-SHA256`.
+    pub fn ciphers(&self) -> Option<&[String]> {
+        self.ciphers.as_ref().map(AsRef::as_ref)
     }
 
-    pub fn certificate(&self) -> Option<&Path> {
-        self.certificate.as_ref().map(|path| path.as_ref())
+    fn merge(&self, defaults: &TlsSettings) -> TlsSettings {
+        TlsSettings {
+            min_version: self.min_version.or(defaults.min_version),
+            max_version: self.max_version.or(defaults.max_version),
+            ciphers: self.ciphers.clone().or_else(|| defaults.ciphers.clone()),
+        }
     }
 
-    pub fn token(&self) -> &Path {
-        &self.token
+    fn validate(&self) -> Result<(), Error> {
+        if let (Some(min), Some(max)) = (self.min_version, self.max_version) {
+            if min > max {
+                return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                    "min_version {} is greater than max_version {}",
+                    min, max
+                ))));
+            }
+        }
+
+        if let Some(ciphers) = self.ciphers() {
+            tls::validate_ciphers(ciphers)?;
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct ApiSettings {
-    #[serde(with = "url_serde")]
-    entrypoint: Url,
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, PartialOrd)]
+#[serde(try_from = "String")]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+    Tls13,
 }
 
-impl ApiSettings {
-    pub fn entrypoint(&self) -> &Url {
-        &self.entrypoint
+impl TryFrom<String> for TlsVersion {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.trim_start_matches("TLSv") {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("unsupported TLS version {:?}", value)),
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        };
+        f.write_str(version)
     }
 }
 
@@ -138,26 +1493,37 @@ impl From<ConfigError> for Error {
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
+    use http::Method;
     use url::Url;
 
     use crate::settings::TOKEN_FILE;
-    use crate::{ErrorKind, Settings};
+    use crate::{
+        AccessRule, BasicAuthSettings, CommandTokenSettings, CredentialSettings, CredentialTarget,
+        ErrorKind, ExtraCredentialSettings, IncomingCredential, ManagedIdentitySettings,
+        OAuthSettings, SasSettings, Settings, TlsVersion, TokenFailurePolicy, TokenSourceSettings,
+        WorkloadSasSettings,
+    };
 
     #[test]
     fn it_loads_defaults() {
         let settings = Settings::new(None).unwrap();
 
         assert!(settings.services().is_empty());
-        assert!(settings.api().is_none())
+        assert!(settings.api().is_none());
+        assert!(!settings.startup().wait_for_dependencies());
+        assert_eq!(settings.startup().timeout(), Duration::from_secs(300));
+        assert_eq!(settings.expiry().thresholds(), &[30, 7, 1]);
+        assert!(settings.workload().is_none());
     }
 
     #[test]
     fn it_overrides_defaults() {
         let settings = Settings::new(Some(Path::new("test/sample.yaml"))).unwrap();
 
-        assert_eq!(settings.services().len(), 3);
+        assert_eq!(settings.services().len(), 7);
 
         assert_eq!(settings.services()[0].name(), "management");
         assert_eq!(
@@ -173,6 +1539,18 @@ mod tests {
             Path::new("management.pem")
         );
         assert_eq!(settings.services()[0].token(), Path::new(TOKEN_FILE));
+        assert_eq!(
+            settings.services()[0].token_source(),
+            TokenSourceSettings::File(PathBuf::from(TOKEN_FILE))
+        );
+        assert!(settings.services()[0].system_roots());
+        assert_eq!(
+            settings.services()[0].pins(),
+            &["sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_owned()]
+        );
+        assert!(settings.services()[0].crls().is_empty());
+        assert!(!settings.services()[0].workload_trust_bundle());
+        assert!(settings.services()[0].jwt());
 
         assert_eq!(settings.services()[1].name(), "workload");
         assert_eq!(
@@ -187,21 +1565,195 @@ mod tests {
             settings.services()[1].certificate().unwrap(),
             Path::new("workload.pem")
         );
+        assert_eq!(
+            settings.services()[1].certificates(),
+            vec![
+                Path::new("workload.pem"),
+                Path::new("bundle.pem"),
+                Path::new("/etc/ssl/edge")
+            ]
+        );
+        assert!(!settings.services()[1].system_roots());
+        assert!(settings.services()[1].workload_trust_bundle());
+        assert!(!settings.services()[1].jwt());
+        assert_eq!(
+            settings.services()[1].token_source(),
+            TokenSourceSettings::OAuth2(
+                OAuthSettings::new(
+                    Url::parse("https://login.example.com/tenant/oauth2/v2.0/token").unwrap(),
+                    "edge-proxy",
+                    Path::new("client.secret")
+                )
+                .with_scope("api://iotedged/.default")
+            )
+        );
+        assert_eq!(settings.services()[1].tls_server_name(), Some("iotedged"));
+        assert!(!settings.services()[1].danger_accept_invalid_hostnames());
+        assert!(settings.services()[2].danger_accept_invalid_hostnames());
+        assert_eq!(
+            settings.services()[2].crls(),
+            &[
+                PathBuf::from("clients.crl"),
+                PathBuf::from("iotedged.crl.pem")
+            ]
+        );
         assert_eq!(settings.services()[2].name(), "no cert provided");
+        assert_eq!(
+            settings.services()[2].token_source(),
+            TokenSourceSettings::Command(CommandTokenSettings::new(
+                "/usr/local/bin/get-token",
+                vec!["--audience".to_owned(), "iotedged".to_owned()],
+                Duration::from_secs(600)
+            ))
+        );
         assert_eq!(
             settings.services()[2].entrypoint(),
-            &Url::parse("http://localhost:3002").unwrap()
+            &Url::parse("https://localhost:3002").unwrap()
+        );
+        assert_eq!(
+            settings.services()[2].server_certificate(),
+            Some(Path::new("server.pem"))
+        );
+        assert_eq!(
+            settings.services()[2].server_key(),
+            Some(Path::new("server.key.pem"))
         );
+        assert!(settings.services()[0].client_auth().is_none());
+        let client_auth = settings.services()[2].client_auth().unwrap();
+        assert_eq!(client_auth.ca(), Path::new("clients.pem"));
+        assert_eq!(client_auth.identity_header(), Some("x-client-identity"));
+        assert_eq!(client_auth.rules().len(), 2);
+        assert_eq!(client_auth.rules()[0].client(), "module-a");
+        assert_eq!(client_auth.rules()[0].paths(), &["/api/".to_owned()]);
+        assert_eq!(client_auth.rules()[0].methods(), &["GET".to_owned()]);
+        assert_eq!(client_auth.rules()[1].client(), "module-b");
+        assert!(client_auth.rules()[1].paths().is_empty());
         assert_eq!(
             settings.services()[2].backend(),
             &Url::parse("https://iotedged:35002").unwrap()
         );
+        assert_eq!(
+            settings.services()[3].token_source(),
+            TokenSourceSettings::Sas(SasSettings::new(
+                "myhub.azure-devices.net/devices/edge",
+                Path::new("device.key"),
+                Duration::from_secs(900)
+            ))
+        );
+        assert_eq!(
+            settings.services()[3].auth_scheme(),
+            "SharedAccessSignature"
+        );
+        assert_eq!(
+            settings.services()[4].token_source(),
+            TokenSourceSettings::WorkloadSas(WorkloadSasSettings::new(
+                "myhub.azure-devices.net/devices/edge/modules/edge-proxy",
+                Duration::from_secs(3600)
+            ))
+        );
+        assert_eq!(
+            settings.services()[4].auth_scheme(),
+            "SharedAccessSignature"
+        );
+        assert_eq!(
+            settings.services()[5].token_source(),
+            TokenSourceSettings::Chain(vec![
+                TokenSourceSettings::ManagedIdentity(
+                    ManagedIdentitySettings::new("https://vault.azure.net")
+                        .with_client_id("4d7f6e3c")
+                ),
+                TokenSourceSettings::File(PathBuf::from("vault.token")),
+            ])
+        );
+        assert_eq!(settings.services()[5].auth_scheme(), "Bearer");
+        assert_eq!(
+            settings.services()[5].token_failure(),
+            TokenFailurePolicy::Reject
+        );
+        assert_eq!(
+            settings.services()[0].token_failure(),
+            TokenFailurePolicy::Forward
+        );
+        assert_eq!(settings.services()[0].auth_scheme(), "Bearer");
+        assert_eq!(
+            settings.services()[0]
+                .clone()
+                .with_credential(CredentialSettings::default().with_scheme(""))
+                .auth_scheme(),
+            ""
+        );
+        assert_eq!(
+            settings.services()[2].credential(),
+            &CredentialSettings::header("x-api-key").with_incoming(IncomingCredential::Forward(
+                "x-module-authorization".to_owned()
+            ))
+        );
+        assert_eq!(settings.services()[2].auth_scheme(), "");
+        assert_eq!(
+            settings.services()[6].basic_auth(),
+            Some(&BasicAuthSettings::new(
+                Path::new("legacy.username"),
+                Path::new("legacy.password")
+            ))
+        );
+        assert_eq!(
+            settings.services()[6].extra_credentials(),
+            &[ExtraCredentialSettings::new(
+                "x-functions-key",
+                Path::new("functions.key")
+            )]
+        );
+        assert_eq!(
+            settings.services()[6].token_source(),
+            TokenSourceSettings::None
+        );
+        assert!(settings.services()[0].basic_auth().is_none());
+        assert_eq!(
+            settings.services()[0].credential().target(),
+            CredentialTarget::Header("Authorization")
+        );
 
         assert_eq!(
             settings.api().unwrap().entrypoint(),
             &Url::parse("http://example:443").unwrap()
         );
         assert_eq!(settings.services()[1].token(), Path::new("token"));
+
+        assert!(settings.startup().wait_for_dependencies());
+        assert_eq!(settings.startup().timeout(), Duration::from_secs(60));
+        assert_eq!(settings.expiry().thresholds(), &[14, 3]);
+
+        let workload = settings.workload().unwrap();
+        assert_eq!(
+            workload.uri().as_str(),
+            "unix:///var/run/iotedge/workload.sock"
+        );
+        assert_eq!(workload.module_id(), "edge-proxy");
+        assert_eq!(workload.generation_id(), "637016347436376523");
+        assert_eq!(workload.api_version(), "2019-01-30");
+
+        assert_eq!(settings.tls().min_version(), Some(TlsVersion::Tls12));
+        assert_eq!(
+            settings.services()[0].tls().min_version(),
+            Some(TlsVersion::Tls12)
+        );
+        assert_eq!(settings.services()[0].tls().max_version(), None);
+        assert_eq!(
+            settings.services()[0].tls().ciphers(),
+            Some(&["ECDHE-RSA-AES128-GCM-SHA256".to_owned()][..])
+        );
+        assert_eq!(
+            settings.services()[1].tls().min_version(),
+            Some(TlsVersion::Tls12)
+        );
+        assert_eq!(
+            settings.services()[1].tls().max_version(),
+            Some(TlsVersion::Tls13)
+        );
+        assert_eq!(
+            settings.services()[1].tls().ciphers(),
+            Some(&["TLS_AES_256_GCM_SHA384".to_owned()][..])
+        );
     }
 
     #[test]
@@ -219,12 +1771,157 @@ mod tests {
     }
 
     #[test]
-    fn it_allows_only_http_for_entrypoint() {
+    fn it_allows_only_http_or_https_for_entrypoint() {
         let err = Settings::new(Some(Path::new("test/unsupported.entrypoint.yaml"))).unwrap_err();
 
         assert_eq!(
             err.kind(),
-            &ErrorKind::UnsupportedSchema("https://localhost:3000/".to_owned())
+            &ErrorKind::UnsupportedSchema("tcp://localhost:3000/".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_requires_server_certificate_for_https_entrypoint() {
+        let err = Settings::new(Some(Path::new("test/tls.entrypoint.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "https://localhost:3000/ requires either server_certificate and server_key or workload_certificate"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_requires_workload_settings_for_workload_certificate() {
+        let err = Settings::new(Some(Path::new("test/workload.certificate.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "workload_certificate of management requires workload settings".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_requires_workload_settings_for_workload_trust_bundle() {
+        let err = Settings::new(Some(Path::new("test/workload.trust_bundle.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "workload_trust_bundle of management requires workload settings".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_requires_workload_settings_for_workload_sas() {
+        let err = Settings::new(Some(Path::new("test/workload.sas.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token("workload_sas of iothub requires workload settings".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_fails_to_load_empty_token_source_chain() {
+        let err = Settings::new(Some(Path::new("test/invalid.chain.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Token("chain of token sources is empty".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_fails_to_load_settings_with_ambiguous_credential() {
+        let err = Settings::new(Some(Path::new("test/invalid.credential.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidCredential(
+                "only one of header, query and cookie can be set".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_fails_to_load_basic_auth_with_token_in_authorization_header() {
+        let err = Settings::new(Some(Path::new("test/invalid.basic_auth.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidCredential(
+                "basic_auth of management requires the token to be sent in another header, a query parameter or a cookie".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_requires_https_entrypoint_for_client_auth() {
+        let err = Settings::new(Some(Path::new("test/client_auth.http.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidClientAuth(
+                "http://localhost:3000/ is not an https entrypoint".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_fails_to_load_settings_with_inverted_tls_versions() {
+        let err = Settings::new(Some(Path::new("test/invalid.tls.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "min_version 1.3 is greater than max_version 1.2".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_matches_rule_paths_on_whole_segments() {
+        let names = ["module-a".to_owned()];
+        let rule = AccessRule::new("module-a", vec!["/api".to_owned()], Vec::new());
+
+        for path in &["/api", "/api/", "/api/values", "/%61pi/values"] {
+            assert!(rule.allows(&names, &Method::GET, path), "{}", path);
+        }
+        for path in &["/api-admin", "/apivalues", "/", "/other/api"] {
+            assert!(!rule.allows(&names, &Method::GET, path), "{}", path);
+        }
+
+        let rule = AccessRule::new("module-a", vec!["/api/".to_owned()], Vec::new());
+        assert!(rule.allows(&names, &Method::GET, "/api/values"));
+        assert!(!rule.allows(&names, &Method::GET, "/api-admin"));
+    }
+
+    #[test]
+    fn it_denies_encoded_separators_in_rule_paths() {
+        let names = ["module-a".to_owned()];
+        let rule = AccessRule::new("module-a", vec!["/api".to_owned()], Vec::new());
+
+        for path in &["/api%2Fvalues", "/api/values%2f..%2Fadmin", "/api%5Cvalues"] {
+            assert!(!rule.allows(&names, &Method::GET, path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_pin() {
+        let err = Settings::new(Some(Path::new("test/invalid.pin.yaml"))).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "pin sha256/AAAA of management is not sha256/ followed by a base64 encoded SHA-256 digest"
+                    .to_owned()
+            )
         );
     }
 
@@ -0,0 +1,436 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot::Receiver;
+use futures::Future;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::timer::Delay;
+
+use crate::CertificateExpiry;
+use crate::{logging, Error};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time a server has to run before a failure is no longer considered part of
+/// a series of failed restarts.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+const DEPENDENCIES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type Shutdown = future::Shared<Receiver<()>>;
+
+pub type ServerFuture = Box<dyn Future<Item = (), Error = Error> + Send>;
+
+/// Resolves once a server accepts connections, which may be after it was
+/// bound if it waits for certificates.
+pub type Serving = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+pub type Started = (SocketAddr, ServerFuture, Serving);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Starting,
+    Waiting,
+    Running,
+    Failed,
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceStatus {
+    name: String,
+    state: ServiceState,
+    address: Option<SocketAddr>,
+    error: Option<String>,
+    failures: u32,
+    certificates: Vec<CertificateExpiry>,
+    token_expiry: Option<i64>,
+}
+
+impl ServiceStatus {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ServiceState {
+        self.state
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(AsRef::as_ref)
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn certificates(&self) -> &[CertificateExpiry] {
+        &self.certificates
+    }
+
+    /// Returns the expiration time of the token last sent to the backend as
+    /// seconds since the Unix epoch, if it is known.
+    pub fn token_expiry(&self) -> Option<i64> {
+        self.token_expiry
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Status(Arc<Mutex<Vec<ServiceStatus>>>);
+
+impl Status {
+    pub fn services(&self) -> Vec<ServiceStatus> {
+        self.0.lock().expect("status lock poisoned").clone()
+    }
+
+    pub fn service(&self, name: &str) -> Option<ServiceStatus> {
+        self.services()
+            .into_iter()
+            .find(|status| status.name == name)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.services()
+            .iter()
+            .all(|status| status.state == ServiceState::Running)
+    }
+
+    fn update<F>(&self, name: &str, f: F)
+    where
+        F: FnOnce(&mut ServiceStatus),
+    {
+        let mut services = self.0.lock().expect("status lock poisoned");
+        let index = match services.iter().position(|status| status.name == name) {
+            Some(index) => index,
+            None => {
+                services.push(ServiceStatus {
+                    name: name.to_owned(),
+                    state: ServiceState::Starting,
+                    address: None,
+                    error: None,
+                    failures: 0,
+                    certificates: Vec::new(),
+                    token_expiry: None,
+                });
+                services.len() - 1
+            }
+        };
+
+        f(&mut services[index]);
+    }
+
+    pub fn certificate(&self, name: &str, expiry: CertificateExpiry) {
+        self.update(name, |status| {
+            status
+                .certificates
+                .retain(|current| current.source() != expiry.source());
+            status.certificates.push(expiry);
+        });
+    }
+
+    pub fn token_expiry(&self, name: &str, expiry: i64) {
+        self.update(name, |status| status.token_expiry = Some(expiry));
+    }
+
+    fn waiting(&self, name: &str, err: &Error) {
+        self.update(name, |status| {
+            status.state = ServiceState::Waiting;
+            status.error = Some(err.to_string());
+        });
+    }
+
+    fn bound(&self, name: &str, addr: SocketAddr) {
+        self.update(name, |status| {
+            status.state = ServiceState::Starting;
+            status.address = Some(addr);
+            status.error = None;
+        });
+    }
+
+    fn running(&self, name: &str) {
+        self.update(name, |status| status.state = ServiceState::Running);
+    }
+
+    fn failed(&self, name: &str, err: &Error) {
+        self.update(name, |status| {
+            status.state = ServiceState::Failed;
+            status.address = None;
+            status.error = Some(err.to_string());
+            status.failures += 1;
+        });
+    }
+
+    fn stopped(&self, name: &str) {
+        self.update(name, |status| {
+            status.state = ServiceState::Stopped;
+            status.address = None;
+        });
+    }
+}
+
+/// Keeps a server running until shutdown, restarting it with exponential
+/// backoff whenever it fails to start or stops with an error. The backoff
+/// starts over once a server has run for a while.
+///
+/// The first attempt is made synchronously so that bound addresses are known
+/// as soon as this function returns.
+pub fn supervise<F>(
+    name: String,
+    status: Status,
+    shutdown: Shutdown,
+    start: F,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: Fn(Shutdown) -> Result<Started, Error> + Send + 'static,
+{
+    let first = watch(&name, &status, start(shutdown.clone()));
+
+    future::loop_fn((Some(first), INITIAL_BACKOFF), move |(stopped, backoff)| {
+        let name = name.clone();
+        let status = status.clone();
+        let shutdown = shutdown.clone();
+
+        let stopped = stopped.unwrap_or_else(|| watch(&name, &status, start(shutdown.clone())));
+
+        stopped.and_then(move |ran| {
+            let backoff = match ran {
+                Some(ran) => backoff_after(backoff, ran),
+                None => {
+                    status.stopped(&name);
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+            };
+
+            info!("Restarting server {} in {:?}", name, backoff);
+            let next = (None, (backoff * 2).min(MAX_BACKOFF));
+            let delay = Delay::new(Instant::now() + backoff);
+
+            Either::B(delay.select2(shutdown).then(move |result| match result {
+                Ok(Either::B(_)) | Err(Either::B(_)) => {
+                    status.stopped(&name);
+                    Ok(Loop::Break(()))
+                }
+                _ => Ok(Loop::Continue(next)),
+            }))
+        })
+    })
+}
+
+/// Polls `check` until it succeeds, the timeout elapses or shutdown is
+/// requested, reporting the server as waiting in the meantime. Resolves to
+/// `true` if shutdown was requested.
+pub fn wait_for_dependencies<F>(
+    name: String,
+    status: Status,
+    shutdown: Shutdown,
+    timeout: Duration,
+    check: F,
+) -> impl Future<Item = bool, Error = ()> + Send
+where
+    F: Fn() -> Result<(), Error> + Send + 'static,
+{
+    let deadline = Instant::now() + timeout;
+
+    future::loop_fn((), move |_| {
+        let err = match check() {
+            Ok(()) => return Either::A(future::ok(Loop::Break(false))),
+            Err(err) => err,
+        };
+
+        if Instant::now() >= deadline {
+            warn!("Timed out waiting for dependencies of server {}", name);
+            logging::failure(&err);
+            return Either::A(future::ok(Loop::Break(false)));
+        }
+
+        if status.service(&name).map(|status| status.state) != Some(ServiceState::Waiting) {
+            info!("Waiting for dependencies of server {}: {}", name, err);
+        }
+        status.waiting(&name, &err);
+
+        let delay = Delay::new(Instant::now() + DEPENDENCIES_POLL_INTERVAL);
+        let status = status.clone();
+        let name = name.clone();
+        Either::B(
+            delay
+                .select2(shutdown.clone())
+                .then(move |result| match result {
+                    Ok(Either::B(_)) | Err(Either::B(_)) => {
+                        status.stopped(&name);
+                        Ok(Loop::Break(true))
+                    }
+                    _ => Ok(Loop::Continue(())),
+                }),
+        )
+    })
+}
+
+fn backoff_after(backoff: Duration, ran: Duration) -> Duration {
+    if ran >= HEALTHY_RUN {
+        INITIAL_BACKOFF
+    } else {
+        backoff
+    }
+}
+
+/// Records the outcome of a start attempt and resolves to `None` once the
+/// server has been shut down gracefully, or to the time it ran if it has to
+/// be restarted.
+fn watch(
+    name: &str,
+    status: &Status,
+    attempt: Result<Started, Error>,
+) -> Box<dyn Future<Item = Option<Duration>, Error = ()> + Send> {
+    match attempt {
+        Ok((addr, server, serving)) => {
+            status.bound(name, addr);
+
+            let running = {
+                let status = status.clone();
+                let name = name.to_owned();
+                serving.then(move |result| {
+                    if result.is_ok() {
+                        status.running(&name);
+                    }
+                    Ok(())
+                })
+            };
+
+            let started = Instant::now();
+            let status = status.clone();
+            let name = name.to_owned();
+            Box::new(server.join(running).then(move |result| match result {
+                Ok(_) => Ok(None),
+                Err(err) => {
+                    status.failed(&name, &err);
+                    warn!("Server {} stopped with an error", name);
+                    logging::failure(&err);
+                    Ok(Some(started.elapsed()))
+                }
+            }))
+        }
+        Err(err) => {
+            status.failed(name, &err);
+            warn!("Could not start server {}", name);
+            logging::failure(&err);
+            Box::new(future::ok(Some(Duration::from_secs(0))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::sync::oneshot;
+    use futures::{future, Future};
+    use tokio::runtime::Runtime;
+
+    use crate::supervisor::{
+        backoff_after, supervise, ServiceState, Status, HEALTHY_RUN, INITIAL_BACKOFF, MAX_BACKOFF,
+    };
+    use crate::{Error, ErrorKind};
+
+    #[test]
+    fn it_retries_failed_server_until_it_starts() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let status = Status::default();
+        let (tx, rx) = oneshot::channel();
+
+        let counter = attempts.clone();
+        let task = supervise(
+            "service".to_owned(),
+            status.clone(),
+            rx.shared(),
+            move |shutdown| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(Error::from(ErrorKind::Generic));
+                }
+
+                let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+                let server = shutdown.then(|_| Ok(()));
+                Ok((addr, Box::new(server) as _, Box::new(future::ok(())) as _))
+            },
+        );
+
+        let service = status.service("service").unwrap();
+        assert_eq!(service.state(), ServiceState::Failed);
+        assert_eq!(service.failures(), 1);
+
+        let mut runtime = Runtime::new().unwrap();
+        let (done_tx, done_rx) = oneshot::channel();
+        runtime.spawn(task.then(|_| done_tx.send(()).map_err(|_| ())));
+
+        while status.service("service").unwrap().state() != ServiceState::Running {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        tx.send(()).unwrap();
+        runtime.block_on(done_rx).unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            status.service("service").unwrap().state(),
+            ServiceState::Stopped
+        );
+    }
+
+    #[test]
+    fn it_reports_running_once_server_is_serving() {
+        let status = Status::default();
+        let (tx, rx) = oneshot::channel();
+        let (serving_tx, serving_rx) = oneshot::channel();
+        let serving = Mutex::new(Some(serving_rx));
+
+        let task = supervise(
+            "service".to_owned(),
+            status.clone(),
+            rx.shared(),
+            move |shutdown| {
+                let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+                let server = shutdown.then(|_| Ok(()));
+                let serving = serving.lock().unwrap().take().unwrap().map_err(|_| ());
+                Ok((addr, Box::new(server) as _, Box::new(serving) as _))
+            },
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        let (done_tx, done_rx) = oneshot::channel();
+        runtime.spawn(task.then(|_| done_tx.send(()).map_err(|_| ())));
+
+        thread::sleep(Duration::from_millis(100));
+        let service = status.service("service").unwrap();
+        assert_eq!(service.state(), ServiceState::Starting);
+        assert_eq!(service.address(), Some("127.0.0.1:3000".parse().unwrap()));
+        assert!(!status.is_ready());
+
+        serving_tx.send(()).unwrap();
+        while status.service("service").unwrap().state() != ServiceState::Running {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        tx.send(()).unwrap();
+        runtime.block_on(done_rx).unwrap();
+    }
+
+    #[test]
+    fn it_resets_backoff_after_healthy_run() {
+        assert_eq!(
+            backoff_after(MAX_BACKOFF, Duration::from_secs(0)),
+            MAX_BACKOFF
+        );
+        assert_eq!(backoff_after(MAX_BACKOFF, HEALTHY_RUN / 2), MAX_BACKOFF);
+        assert_eq!(backoff_after(MAX_BACKOFF, HEALTHY_RUN), INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,424 @@
+//! TLS entrypoints, served with the same implementation as backend
+//! connections: OpenSSL by default and rustls with the `rustls` feature.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use failure::{Fail, ResultExt};
+use futures::{Future, Stream};
+use log::{debug, warn};
+use tokio::net::TcpListener;
+
+use crate::x509::{pem_blocks, Certificate};
+use crate::{Error, ErrorKind, ServiceSettings};
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod openssl;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub(crate) use self::openssl::apply;
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use self::openssl::{
+    accept, acceptor, peer_certificate, private_key, validate_ciphers, PrivateKey, TlsAcceptor,
+    TlsStream,
+};
+#[cfg(feature = "rustls")]
+pub(crate) use self::rustls::protocols;
+#[cfg(feature = "rustls")]
+pub use self::rustls::{
+    accept, acceptor, peer_certificate, private_key, validate_ciphers, PrivateKey, TlsAcceptor,
+    TlsStream,
+};
+
+const MAX_HANDSHAKES: usize = 64;
+
+#[derive(Clone)]
+pub struct ServerIdentity {
+    cert: Certificate,
+    chain: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl ServerIdentity {
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let mut certs = Vec::new();
+        for der in pem_blocks(&String::from_utf8_lossy(chain), "CERTIFICATE")? {
+            certs.push(Certificate::from_der(&der)?);
+        }
+
+        let mut chain = certs.into_iter();
+        let cert = chain
+            .next()
+            .ok_or_else(|| ErrorKind::NoCertificates("server certificate chain".to_owned()))?;
+
+        Ok(ServerIdentity {
+            cert,
+            chain: chain.collect(),
+            key: private_key(key)?,
+        })
+    }
+
+    pub fn load(settings: &ServiceSettings) -> Result<Self, Error> {
+        let (cert, key) = match (settings.server_certificate(), settings.server_key()) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => {
+                return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                    "{} requires server_certificate and server_key",
+                    settings.entrypoint()
+                ))))
+            }
+        };
+
+        let read =
+            |path: &Path| fs::read(path).context(ErrorKind::File(path.display().to_string()));
+        let identity = ServerIdentity::from_pem(&read(cert)?, &read(key)?)
+            .context(ErrorKind::File(cert.display().to_string()))?;
+
+        Ok(identity)
+    }
+
+    pub fn cert(&self) -> &Certificate {
+        &self.cert
+    }
+
+    pub fn chain(&self) -> &[Certificate] {
+        &self.chain
+    }
+
+    pub fn key(&self) -> &PrivateKey {
+        &self.key
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    subject: String,
+    names: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_stream(stream: &TlsStream) -> Option<Self> {
+        let der = peer_certificate(stream)?;
+        match Certificate::from_der(&der) {
+            Ok(cert) => Some(ClientIdentity::from_cert(&cert)),
+            Err(err) => {
+                warn!("Could not read client certificate: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn from_cert(cert: &Certificate) -> Self {
+        let mut names = cert
+            .subject()
+            .common_names()
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        names.extend(cert.alt_names().iter().cloned());
+
+        ClientIdentity {
+            subject: cert.subject().to_string(),
+            names,
+        }
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.subject)
+    }
+}
+
+/// Acceptor shared between a TLS entrypoint and the certificate renewal, so
+/// that a renewed certificate applies to subsequent handshakes.
+#[derive(Clone)]
+pub struct Acceptor(Arc<RwLock<TlsAcceptor>>);
+
+impl Acceptor {
+    pub fn new(acceptor: TlsAcceptor) -> Self {
+        Acceptor(Arc::new(RwLock::new(acceptor)))
+    }
+
+    pub fn get(&self) -> TlsAcceptor {
+        self.0.read().expect("acceptor lock poisoned").clone()
+    }
+
+    pub fn replace(&self, acceptor: TlsAcceptor) {
+        *self.0.write().expect("acceptor lock poisoned") = acceptor;
+    }
+}
+
+pub type Incoming = Box<dyn Stream<Item = TlsStream, Error = Error> + Send>;
+
+/// Returns the stream of connections established on a TLS entrypoint. Failed
+/// handshakes are logged and dropped without affecting other connections.
+pub fn incoming(listener: TcpListener, acceptor: Acceptor) -> Incoming {
+    let incoming = listener
+        .incoming()
+        .map_err(|err| Error::from(err.context(ErrorKind::Io)))
+        .map(move |tcp| {
+            let peer = tcp.peer_addr().ok();
+            accept(&acceptor.get(), tcp).then(move |result| match result {
+                Ok(stream) => Ok(Some(stream)),
+                Err(err) => {
+                    debug!("TLS handshake with {:?} failed: {}", peer, err);
+                    Ok(None)
+                }
+            })
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|stream| stream);
+
+    Box::new(incoming)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode, SslVersion};
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind, Certificate};
+    use crate::proxy::revocation::tests::crl;
+    use crate::proxy::Crls;
+    use crate::settings::{TlsSettings, TlsVersion};
+    #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+    use crate::tls::openssl::tests::accept;
+    #[cfg(feature = "rustls")]
+    use crate::tls::rustls::tests::accept;
+    use crate::tls::{acceptor, validate_ciphers, ClientIdentity, ServerIdentity, TlsAcceptor};
+    use crate::{x509, ClientAuthSettings, ErrorKind, ServiceSettings};
+
+    /// Returns the certificate an acceptor presents in a handshake.
+    pub fn presented(acceptor: &TlsAcceptor) -> x509::Certificate {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let acceptor = acceptor.clone();
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let _ = accept(&acceptor, tcp);
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let tcp = TcpStream::connect(addr).unwrap();
+        let stream = connector.build().connect("localhost", tcp).unwrap();
+        let cert = stream.ssl().peer_certificate().unwrap();
+
+        drop(stream);
+        server.join().unwrap();
+        x509::Certificate::from_der(&cert.to_der().unwrap()).unwrap()
+    }
+
+    fn settings(dir: &TempDir, tls: TlsSettings) -> ServiceSettings {
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        let server = CertGenerator::new("localhost", CertKind::Server)
+            .generate()
+            .unwrap();
+        server.write_cert(&cert).unwrap();
+        server.write_key(&key).unwrap();
+
+        ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("https://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            &dir.path().join("token"),
+        )
+        .with_tls(tls)
+        .with_server_identity(&cert, &key)
+    }
+
+    fn handshake(settings: &ServiceSettings, max_version: SslVersion) -> Option<String> {
+        let acceptor = acceptor(
+            settings,
+            &ServerIdentity::load(settings).unwrap(),
+            &Crls::default(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let _ = accept(&acceptor, tcp);
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_max_proto_version(Some(max_version)).unwrap();
+        let tcp = TcpStream::connect(addr).unwrap();
+        let version = connector
+            .build()
+            .connect("localhost", tcp)
+            .ok()
+            .map(|stream| stream.ssl().version_str().to_owned());
+
+        server.join().unwrap();
+        version
+    }
+
+    #[test]
+    fn it_enforces_minimum_protocol_version() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings(&dir, TlsSettings::new(Some(TlsVersion::Tls13), None, None));
+
+        assert_eq!(handshake(&settings, SslVersion::TLS1_2), None);
+        assert_eq!(
+            handshake(&settings, SslVersion::TLS1_3),
+            Some("TLSv1.3".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_enforces_maximum_protocol_version() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings(&dir, TlsSettings::new(None, Some(TlsVersion::Tls12), None));
+
+        assert_eq!(
+            handshake(&settings, SslVersion::TLS1_3),
+            Some("TLSv1.2".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_requires_client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let ca_path = dir.path().join("ca.pem");
+        ca.write_cert(&ca_path).unwrap();
+
+        let settings = settings(&dir, TlsSettings::default())
+            .with_client_auth(ClientAuthSettings::new(&ca_path));
+        let acceptor = acceptor(
+            &settings,
+            &ServerIdentity::load(&settings).unwrap(),
+            &Crls::default(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut identities = Vec::new();
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().unwrap();
+                let identity = accept(&acceptor, tcp).ok().and_then(|der| {
+                    let cert = x509::Certificate::from_der(&der?).unwrap();
+                    Some(ClientIdentity::from_cert(&cert))
+                });
+                identities.push(identity);
+            }
+            identities
+        });
+
+        let connect = |client: Option<&Certificate>| {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            if let Some(client) = client {
+                connector.set_certificate(client.cert()).unwrap();
+                connector.set_private_key(client.key()).unwrap();
+            }
+            let tcp = TcpStream::connect(addr).unwrap();
+            // TLS 1.3 reports a rejected client certificate on the first read
+            if let Ok(mut stream) = connector.build().connect("localhost", tcp) {
+                let _ = stream.read(&mut [0; 1]);
+            }
+        };
+
+        let client = CertGenerator::new("module-a", CertKind::Client)
+            .san("module-a.edge")
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        connect(None);
+        connect(Some(&client));
+
+        let identities = server.join().unwrap();
+        assert_eq!(identities[0], None);
+
+        let identity = identities[1].as_ref().unwrap();
+        assert_eq!(identity.subject(), "CN=module-a");
+        assert_eq!(identity.names(), &["module-a", "module-a.edge"]);
+    }
+
+    #[test]
+    fn it_rejects_revoked_client_certificate() {
+        let dir = TempDir::new().unwrap();
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let ca_path = dir.path().join("ca.pem");
+        ca.write_cert(&ca_path).unwrap();
+        let client = CertGenerator::new("module-a", CertKind::Client)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+
+        let settings = settings(&dir, TlsSettings::default())
+            .with_client_auth(ClientAuthSettings::new(&ca_path));
+        let crls = Crls::default();
+        let acceptor =
+            acceptor(&settings, &ServerIdentity::load(&settings).unwrap(), &crls).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut accepted = Vec::new();
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().unwrap();
+                accepted.push(accept(&acceptor, tcp).is_ok());
+            }
+            accepted
+        });
+
+        let connect = || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            connector.set_certificate(client.cert()).unwrap();
+            connector.set_private_key(client.key()).unwrap();
+            let tcp = TcpStream::connect(addr).unwrap();
+            if let Ok(mut stream) = connector.build().connect("localhost", tcp) {
+                let _ = stream.read(&mut [0; 1]);
+            }
+        };
+
+        connect();
+        // the acceptor picks up lists replaced after it was built
+        crls.replace(vec![crl(&ca, &[&client])]);
+        connect();
+
+        assert_eq!(server.join().unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn it_rejects_unknown_cipher() {
+        let ciphers = vec![
+            "TLS_AES_128_GCM_SHA256".to_owned(),
+            "ECDHE-RSA-AES128-GCM-SHA256".to_owned(),
+        ];
+        assert!(validate_ciphers(&ciphers).is_ok());
+
+        let err = validate_ciphers(&["TLS_NOPE".to_owned()]).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings("unknown cipher suite TLS_NOPE".to_owned())
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::io;
+
+use failure::ResultExt;
+use futures::Future;
+use log::warn;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    SslAcceptor, SslContext, SslContextBuilder, SslMethod, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{X509Name, X509VerifyResult, X509};
+use tokio::net::TcpStream;
+use tokio_openssl::{SslAcceptorExt, SslStream};
+
+use crate::proxy::Crls;
+use crate::settings::{TlsSettings, TlsVersion};
+use crate::tls::ServerIdentity;
+use crate::{Error, ErrorKind, ServiceSettings};
+
+const TLS13_PREFIX: &str = "TLS_";
+
+pub type TlsAcceptor = SslAcceptor;
+
+pub type TlsStream = SslStream<TcpStream>;
+
+pub type PrivateKey = PKey<Private>;
+
+impl From<TlsVersion> for SslVersion {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls10 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// Checks that every cipher suite is known to OpenSSL. TLS 1.3 suites and
+/// older cipher lists are configured separately, so both kinds are checked
+/// one by one to report the offending name.
+pub fn validate_ciphers(ciphers: &[String]) -> Result<(), Error> {
+    for cipher in ciphers {
+        let mut context = SslContext::builder(SslMethod::tls())?;
+        let result = if cipher.starts_with(TLS13_PREFIX) {
+            context.set_ciphersuites(cipher)
+        } else {
+            context.set_cipher_list(cipher)
+        };
+
+        if result.is_err() {
+            return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                "unknown cipher suite {}",
+                cipher
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn private_key(pem: &[u8]) -> Result<PrivateKey, Error> {
+    Ok(PKey::private_key_from_pem(pem)?)
+}
+
+/// Creates an acceptor for a TLS entrypoint presenting the given server
+/// certificate and applying the protocol policy of the service. Client
+/// certificates are checked against the shared revocation lists on every
+/// handshake.
+pub fn acceptor(
+    settings: &ServiceSettings,
+    identity: &ServerIdentity,
+    crls: &Crls,
+) -> Result<TlsAcceptor, Error> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_certificate(X509::from_der(identity.cert().der())?.as_ref())?;
+    for cert in identity.chain() {
+        acceptor.add_extra_chain_cert(X509::from_der(cert.der())?)?;
+    }
+    acceptor.set_private_key(identity.key())?;
+    acceptor.check_private_key()?;
+
+    if let Some(client_auth) = settings.client_auth() {
+        let ca = client_auth.ca();
+        acceptor
+            .set_ca_file(ca)
+            .context(ErrorKind::File(ca.display().to_string()))?;
+        acceptor.set_client_ca_list(
+            X509Name::load_client_ca_file(ca).context(ErrorKind::File(ca.display().to_string()))?,
+        );
+        let crls = crls.clone();
+        acceptor.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |verified, context| {
+                if !verified {
+                    return false;
+                }
+
+                if let Err(err) = crls.check_context(context) {
+                    warn!("Rejected client certificate: {}", err);
+                    context.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+                    return false;
+                }
+
+                true
+            },
+        );
+    }
+
+    apply(&mut acceptor, settings.tls())?;
+
+    Ok(acceptor.build())
+}
+
+pub(crate) fn apply(context: &mut SslContextBuilder, tls: &TlsSettings) -> Result<(), Error> {
+    if let Some(version) = tls.min_version() {
+        context.set_min_proto_version(Some(version.into()))?;
+    }
+
+    if let Some(version) = tls.max_version() {
+        context.set_max_proto_version(Some(version.into()))?;
+    }
+
+    if let Some(ciphers) = tls.ciphers() {
+        let (suites, list): (Vec<&str>, Vec<&str>) = ciphers
+            .iter()
+            .map(AsRef::as_ref)
+            .partition(|cipher: &&str| cipher.starts_with(TLS13_PREFIX));
+
+        // suites of a protocol version without any configured ones keep
+        // their defaults, use min_version and max_version to disable it
+        if !suites.is_empty() {
+            context.set_ciphersuites(&suites.join(":"))?;
+        }
+        if !list.is_empty() {
+            context.set_cipher_list(&list.join(":"))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn accept(
+    acceptor: &TlsAcceptor,
+    tcp: TcpStream,
+) -> impl Future<Item = TlsStream, Error = io::Error> + Send {
+    acceptor
+        .accept_async(tcp)
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+/// DER encoded certificate the client authenticated with.
+pub fn peer_certificate(stream: &TlsStream) -> Option<Vec<u8>> {
+    stream.get_ref().ssl().peer_certificate()?.to_der().ok()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io;
+    use std::net::TcpStream;
+
+    use crate::tls::openssl::TlsAcceptor;
+
+    /// Completes a handshake on a blocking stream, returning the DER encoded
+    /// client certificate if there was one.
+    pub fn accept(acceptor: &TlsAcceptor, tcp: TcpStream) -> io::Result<Option<Vec<u8>>> {
+        let stream = acceptor
+            .accept(tcp)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+
+        Ok(stream
+            .ssl()
+            .peer_certificate()
+            .map(|cert| cert.to_der().unwrap()))
+    }
+}
@@ -0,0 +1,412 @@
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+use failure::{Fail, ResultExt};
+use futures::Future;
+use log::warn;
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, CipherSuite, ClientCertVerified, ClientCertVerifier,
+    DistinguishedNames, NoClientAuth, ProtocolVersion, RootCertStore, ServerConfig, Session,
+    SupportedCipherSuite, TLSError, ALL_CIPHERSUITES,
+};
+use tokio::net::TcpStream;
+
+use crate::proxy::Crls;
+use crate::settings::{TlsSettings, TlsVersion};
+use crate::tls::ServerIdentity;
+use crate::x509::{pem_blocks, Certificate as X509Certificate};
+use crate::{Error, ErrorKind, ServiceSettings};
+
+const TLS13_PREFIX: &str = "TLS_";
+
+pub type TlsAcceptor = Arc<ServerConfig>;
+
+pub type TlsStream = tokio_rustls::server::TlsStream<TcpStream>;
+
+pub type PrivateKey = rustls::PrivateKey;
+
+const VERSIONS: [(TlsVersion, ProtocolVersion); 2] = [
+    (TlsVersion::Tls13, ProtocolVersion::TLSv1_3),
+    (TlsVersion::Tls12, ProtocolVersion::TLSv1_2),
+];
+
+/// Cipher suites of rustls by their OpenSSL names, which the settings use.
+const CIPHERS: [(&str, CipherSuite); 9] = [
+    (
+        "TLS_AES_128_GCM_SHA256",
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    (
+        "TLS_AES_256_GCM_SHA384",
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        "TLS_CHACHA20_POLY1305_SHA256",
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "ECDHE-ECDSA-AES128-GCM-SHA256",
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    (
+        "ECDHE-ECDSA-AES256-GCM-SHA384",
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    (
+        "ECDHE-ECDSA-CHACHA20-POLY1305",
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "ECDHE-RSA-AES128-GCM-SHA256",
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+    (
+        "ECDHE-RSA-AES256-GCM-SHA384",
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    (
+        "ECDHE-RSA-CHACHA20-POLY1305",
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+];
+
+/// Checks that rustls implements every cipher suite, so that settings
+/// naming one it lacks are rejected like names unknown to OpenSSL.
+pub fn validate_ciphers(ciphers: &[String]) -> Result<(), Error> {
+    for cipher in ciphers {
+        if ciphersuite(cipher).is_none() {
+            return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+                "unknown cipher suite {}",
+                cipher
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a PKCS #8 or RSA private key, rustls supports no other encodings.
+pub fn private_key(pem: &[u8]) -> Result<PrivateKey, Error> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..]).map_err(|_| ErrorKind::Rustls)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..]).map_err(|_| ErrorKind::Rustls)?;
+    }
+
+    let key = keys.into_iter().next().ok_or(ErrorKind::Rustls)?;
+    rustls::sign::any_supported_type(&key).map_err(|_| ErrorKind::Rustls)?;
+    Ok(key)
+}
+
+/// Creates an acceptor for a TLS entrypoint presenting the given server
+/// certificate and applying the protocol policy of the service. Client
+/// certificates are checked against the shared revocation lists on every
+/// handshake.
+pub fn acceptor(
+    settings: &ServiceSettings,
+    identity: &ServerIdentity,
+    crls: &Crls,
+) -> Result<TlsAcceptor, Error> {
+    let verifier = match settings.client_auth() {
+        Some(client_auth) => {
+            let ca = client_auth.ca();
+            let file = || ErrorKind::File(ca.display().to_string());
+            let pem = fs::read(ca).context(file())?;
+
+            let mut roots = RootCertStore::empty();
+            let mut anchors = Vec::new();
+            for der in pem_blocks(&String::from_utf8_lossy(&pem), "CERTIFICATE").context(file())? {
+                roots
+                    .add(&Certificate(der.clone()))
+                    .map_err(|err| Error::from(err.context(file())))?;
+                anchors.push(X509Certificate::from_der(&der).context(file())?);
+            }
+            if anchors.is_empty() {
+                return Err(Error::from(ErrorKind::NoCertificates(
+                    ca.display().to_string(),
+                )));
+            }
+
+            Arc::new(RevocationVerifier {
+                inner: AllowAnyAuthenticatedClient::new(roots),
+                crls: crls.clone(),
+                anchors,
+            })
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    let (versions, suites) = protocols(settings.tls())?;
+    config.versions = versions;
+    config.ciphersuites = suites;
+
+    let chain = Some(identity.cert())
+        .into_iter()
+        .chain(identity.chain())
+        .map(|cert| Certificate(cert.der().to_vec()))
+        .collect();
+    config
+        .set_single_cert(chain, identity.key().clone())
+        .map_err(|err| Error::from(err.context(ErrorKind::Rustls)))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Returns the protocol versions and cipher suites a TLS policy allows, for
+/// entrypoints and backend connections alike.
+pub(crate) fn protocols(
+    tls: &TlsSettings,
+) -> Result<(Vec<ProtocolVersion>, Vec<&'static SupportedCipherSuite>), Error> {
+    let versions = versions(tls)?;
+
+    match tls.ciphers() {
+        Some(ciphers) => select_ciphers(&versions, ciphers),
+        None => Ok((versions, ALL_CIPHERSUITES.to_vec())),
+    }
+}
+
+/// rustls implements only TLS 1.2 and 1.3, so a policy that allows neither of
+/// them is rejected.
+fn versions(tls: &TlsSettings) -> Result<Vec<ProtocolVersion>, Error> {
+    let min = tls.min_version().unwrap_or(TlsVersion::Tls12);
+    let max = tls.max_version().unwrap_or(TlsVersion::Tls13);
+
+    let versions = VERSIONS
+        .iter()
+        .filter(|(version, _)| min <= *version && *version <= max)
+        .map(|(_, protocol)| *protocol)
+        .collect::<Vec<_>>();
+
+    if versions.is_empty() {
+        return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+            "rustls supports only TLS 1.2 and 1.3, but max_version is {}",
+            max
+        ))));
+    }
+
+    Ok(versions)
+}
+
+/// Restricts the versions to the configured cipher suites that rustls
+/// implements, skipping the others with a warning. Like with OpenSSL, a
+/// protocol version without any configured suites keeps its defaults, while
+/// one whose configured suites are all unsupported is disabled.
+fn select_ciphers(
+    versions: &[ProtocolVersion],
+    ciphers: &[String],
+) -> Result<(Vec<ProtocolVersion>, Vec<&'static SupportedCipherSuite>), Error> {
+    let mut suites = Vec::new();
+    let mut enabled = Vec::new();
+
+    for version in versions {
+        let configured = ciphers
+            .iter()
+            .filter(|cipher| {
+                cipher.starts_with(TLS13_PREFIX) == (*version == ProtocolVersion::TLSv1_3)
+            })
+            .collect::<Vec<_>>();
+
+        let usable = if configured.is_empty() {
+            ALL_CIPHERSUITES
+                .iter()
+                .copied()
+                .filter(|suite| suite.usable_for_version(*version))
+                .collect::<Vec<_>>()
+        } else {
+            configured
+                .into_iter()
+                .filter_map(|cipher| {
+                    let suite = ciphersuite(cipher);
+                    if suite.is_none() {
+                        warn!("Cipher suite {} is not supported by rustls", cipher);
+                    }
+                    suite
+                })
+                .collect()
+        };
+
+        if !usable.is_empty() {
+            enabled.push(*version);
+            suites.extend(usable);
+        }
+    }
+
+    if enabled.is_empty() {
+        return Err(Error::from(ErrorKind::InvalidTlsSettings(format!(
+            "none of the cipher suites {} is supported by rustls",
+            ciphers.join(", ")
+        ))));
+    }
+
+    Ok((enabled, suites))
+}
+
+fn ciphersuite(name: &str) -> Option<&'static SupportedCipherSuite> {
+    let (_, suite) = CIPHERS.iter().find(|(cipher, _)| *cipher == name)?;
+    ALL_CIPHERSUITES
+        .iter()
+        .copied()
+        .find(|supported| supported.suite == *suite)
+}
+
+pub fn accept(
+    acceptor: &TlsAcceptor,
+    tcp: TcpStream,
+) -> impl Future<Item = TlsStream, Error = io::Error> + Send {
+    tokio_rustls::TlsAcceptor::from(acceptor.clone()).accept(tcp)
+}
+
+/// DER encoded certificate the client authenticated with.
+pub fn peer_certificate(stream: &TlsStream) -> Option<Vec<u8>> {
+    let (_, session) = stream.get_ref();
+    let cert = session.get_peer_certificates()?.into_iter().next()?;
+    Some(cert.0)
+}
+
+/// Verifies client certificates like the wrapped verifier, then checks them
+/// against the revocation lists.
+struct RevocationVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    crls: Crls,
+    anchors: Vec<X509Certificate>,
+}
+
+impl RevocationVerifier {
+    fn check(&self, presented_certs: &[Certificate]) -> Result<(), Error> {
+        let mut chain = Vec::new();
+        for cert in presented_certs {
+            chain.push(X509Certificate::from_der(&cert.0)?);
+        }
+
+        self.crls.check_presented(chain, &self.anchors)
+    }
+}
+
+impl ClientCertVerifier for RevocationVerifier {
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs)?;
+
+        if let Err(err) = self.check(presented_certs) {
+            warn!("Rejected client certificate: {}", err);
+            return Err(TLSError::General(err.to_string()));
+        }
+
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io;
+    use std::net::TcpStream;
+
+    use rustls::{CipherSuite, ProtocolVersion, ServerSession, Session, SupportedCipherSuite};
+
+    use crate::settings::{TlsSettings, TlsVersion};
+    use crate::tls::rustls::{select_ciphers, versions, TlsAcceptor};
+    use crate::ErrorKind;
+
+    /// Completes a handshake on a blocking stream, returning the DER encoded
+    /// client certificate if there was one.
+    pub fn accept(acceptor: &TlsAcceptor, mut tcp: TcpStream) -> io::Result<Option<Vec<u8>>> {
+        let mut session = ServerSession::new(acceptor);
+        while session.is_handshaking() {
+            session.complete_io(&mut tcp)?;
+        }
+        while session.wants_write() {
+            session.write_tls(&mut tcp)?;
+        }
+
+        Ok(session
+            .get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+            .map(|cert| cert.0))
+    }
+
+    #[test]
+    fn it_limits_protocol_versions() {
+        let tls = TlsSettings::new(Some(TlsVersion::Tls10), Some(TlsVersion::Tls12), None);
+        assert_eq!(versions(&tls).unwrap(), vec![ProtocolVersion::TLSv1_2]);
+
+        let tls = TlsSettings::new(None, None, None);
+        assert_eq!(
+            versions(&tls).unwrap(),
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+        );
+    }
+
+    fn suites(suites: &[&SupportedCipherSuite]) -> Vec<CipherSuite> {
+        suites.iter().map(|suite| suite.suite).collect()
+    }
+
+    fn ciphers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    const ALL: [ProtocolVersion; 2] = [ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+
+    #[test]
+    fn it_keeps_default_suites_of_unconfigured_versions() {
+        let (versions, selected) =
+            select_ciphers(&ALL, &ciphers(&["ECDHE-RSA-AES128-GCM-SHA256"])).unwrap();
+
+        assert_eq!(versions, ALL);
+        assert_eq!(
+            suites(&selected),
+            vec![
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS13_AES_256_GCM_SHA384,
+                CipherSuite::TLS13_AES_128_GCM_SHA256,
+                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_disables_versions_without_supported_suites() {
+        let names = ciphers(&["TLS_AES_128_CCM_SHA256", "ECDHE-ECDSA-AES256-GCM-SHA384"]);
+        let (versions, selected) = select_ciphers(&ALL, &names).unwrap();
+
+        assert_eq!(versions, vec![ProtocolVersion::TLSv1_2]);
+        assert_eq!(
+            suites(&selected),
+            vec![CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384]
+        );
+    }
+
+    #[test]
+    fn it_rejects_ciphers_unsupported_by_rustls() {
+        let names = ciphers(&["AES128-SHA", "DHE-RSA-AES256-SHA"]);
+        let err = select_ciphers(&[ProtocolVersion::TLSv1_2], &names).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "none of the cipher suites AES128-SHA, DHE-RSA-AES256-SHA is supported by rustls"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn it_rejects_versions_unsupported_by_rustls() {
+        let tls = TlsSettings::new(None, Some(TlsVersion::Tls11), None);
+        let err = versions(&tls).unwrap_err();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvalidTlsSettings(
+                "rustls supports only TLS 1.2 and 1.3, but max_version is 1.1".to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::{Future, Stream};
+use log::{info, warn};
+use tokio::timer::Interval;
+
+use crate::proxy::{pem_certificates, tls_connector_with, Tls};
+use crate::workload::WorkloadClient;
+use crate::{logging, Error, ErrorKind, ServiceSettings};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps the trust bundle of the edge device among the certificates trusted
+/// by the backend connector of a service.
+#[derive(Clone)]
+pub struct TrustBundle {
+    client: WorkloadClient,
+    settings: ServiceSettings,
+    tls: Tls,
+}
+
+impl TrustBundle {
+    pub fn new(client: WorkloadClient, settings: &ServiceSettings, tls: &Tls) -> Self {
+        TrustBundle {
+            client,
+            settings: settings.clone(),
+            tls: tls.clone(),
+        }
+    }
+
+    /// Fetches the bundle and rebuilds the connector if it changed. Resolves
+    /// to whether the connector was replaced.
+    pub fn refresh(&self) -> impl Future<Item = bool, Error = Error> + Send {
+        let settings = self.settings.clone();
+        let tls = self.tls.clone();
+
+        self.client.trust_bundle().and_then(move |pem| {
+            let certs = pem_certificates(&pem);
+            if certs.is_empty() {
+                return Err(Error::from(ErrorKind::NoCertificates(
+                    "workload trust bundle".to_owned(),
+                )));
+            }
+
+            tls.replace_trust_bundle(certs, |certs| tls_connector_with(&settings, certs))
+        })
+    }
+
+    pub fn watch(self) -> impl Future<Item = (), Error = Error> {
+        Interval::new(Instant::now() + REFRESH_INTERVAL, REFRESH_INTERVAL)
+            .map_err(|err| Error::from(err.context(ErrorKind::Tokio)))
+            .for_each(move |_| {
+                let name = self.settings.name().to_owned();
+                self.refresh().then(move |result| {
+                    match result {
+                        Ok(true) => info!("Updated trust bundle for {}", name),
+                        Ok(false) => (),
+                        Err(err) => {
+                            warn!(
+                                "Could not refresh trust bundle for {}, keeping previous one",
+                                name
+                            );
+                            logging::failure(&err);
+                        }
+                    }
+
+                    Ok(())
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::runtime::current_thread::Runtime;
+    use url::Url;
+
+    use crate::proxy::{pem_certificates, tls_connector_with, Tls};
+    use crate::workload::mock::MockWorkload;
+    use crate::workload::TrustBundle;
+    use crate::{ErrorKind, ServiceSettings};
+
+    fn settings() -> ServiceSettings {
+        ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("http://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_workload_trust_bundle(true)
+    }
+
+    #[test]
+    fn it_replaces_connector_when_bundle_changes() {
+        let workload = MockWorkload::start();
+        let settings = settings();
+        let tls = Tls::new(tls_connector_with(&settings, &[]).unwrap());
+        let bundle = TrustBundle::new(workload.client(), &settings, &tls);
+        let mut runtime = Runtime::new().unwrap();
+
+        assert!(runtime.block_on(bundle.refresh()).unwrap());
+        assert_eq!(
+            tls.trust_bundle(),
+            pem_certificates(&String::from_utf8(workload.ca().cert().to_pem().unwrap()).unwrap())
+        );
+
+        assert!(!runtime.block_on(bundle.refresh()).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_empty_bundle() {
+        let workload = MockWorkload::start().with_trust_bundle(&[]);
+        let settings = settings();
+        let tls = Tls::new(tls_connector_with(&settings, &[]).unwrap());
+        let bundle = TrustBundle::new(workload.client(), &settings, &tls);
+        let mut runtime = Runtime::new().unwrap();
+
+        let err = runtime.block_on(bundle.refresh()).err().unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::NoCertificates("workload trust bundle".to_owned())
+        );
+        assert!(tls.trust_bundle().is_empty());
+    }
+}
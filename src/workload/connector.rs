@@ -0,0 +1,34 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use futures::Future;
+use hyper::client::connect::{Connect, Connected, Destination};
+use tokio::net::UnixStream;
+
+/// Connects to the workload API over its Unix socket regardless of the host
+/// of the requested URI.
+#[derive(Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new(path: &Path) -> Self {
+        UnixConnector {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+pub type Connecting = Box<dyn Future<Item = (UnixStream, Connected), Error = io::Error> + Send>;
+
+impl Connect for UnixConnector {
+    type Transport = UnixStream;
+    type Error = io::Error;
+    type Future = Connecting;
+
+    fn connect(&self, _: Destination) -> Self::Future {
+        let fut = UnixStream::connect(&self.path).map(|stream| (stream, Connected::new()));
+        Box::new(fut)
+    }
+}
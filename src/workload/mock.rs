@@ -0,0 +1,206 @@
+//! Local stand-in for the workload API of iotedged listening on a Unix socket.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::sync::oneshot::{self, Sender};
+use futures::{Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use ring::hmac;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::net::UnixListener;
+use tokio::runtime::current_thread::Runtime;
+use url::Url;
+
+use crate::cert::{CertGenerator, CertKind, Certificate};
+use crate::workload::WorkloadClient;
+use crate::WorkloadSettings;
+
+struct State {
+    status: StatusCode,
+    days: u32,
+    bundle: String,
+    key: Vec<u8>,
+    requests: Vec<String>,
+}
+
+pub struct MockWorkload {
+    _dir: TempDir,
+    ca: Certificate,
+    settings: WorkloadSettings,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<Sender<()>>,
+}
+
+impl MockWorkload {
+    /// Starts serving on a socket in a temporary directory. Certificates are
+    /// issued by a generated CA for the requested common name, which is also
+    /// the only certificate of the trust bundle.
+    pub fn start() -> Self {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("workload.sock");
+        let ca = CertGenerator::new("workload ca", CertKind::Ca)
+            .generate()
+            .unwrap();
+        let state = Arc::new(Mutex::new(State {
+            status: StatusCode::CREATED,
+            days: 90,
+            bundle: pem(&[&ca]),
+            key: b"workload key".to_vec(),
+            requests: Vec::new(),
+        }));
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let (tx, rx) = oneshot::channel();
+        let issuer = ca.clone();
+        let shared = state.clone();
+        thread::spawn(move || {
+            let new_service = move || {
+                let issuer = issuer.clone();
+                let state = shared.clone();
+                service_fn(move |req| handle(req, &issuer, &state))
+            };
+            let server = Server::builder(listener.incoming())
+                .serve(new_service)
+                .with_graceful_shutdown(rx.then(|_| Ok::<_, ()>(())));
+
+            let mut runtime = Runtime::new().unwrap();
+            runtime.block_on(server).unwrap();
+        });
+
+        let uri = Url::parse(&format!("unix://{}", path.display())).unwrap();
+        MockWorkload {
+            _dir: dir,
+            ca,
+            settings: WorkloadSettings::new(uri, "edge-proxy", "1"),
+            state,
+            shutdown: Some(tx),
+        }
+    }
+
+    pub fn with_status(self, status: u16) -> Self {
+        self.state.lock().unwrap().status = StatusCode::from_u16(status).unwrap();
+        self
+    }
+
+    pub fn with_days(self, days: u32) -> Self {
+        self.state.lock().unwrap().days = days;
+        self
+    }
+
+    pub fn key(&self) -> Vec<u8> {
+        self.state.lock().unwrap().key.clone()
+    }
+
+    pub fn with_trust_bundle(self, certs: &[&Certificate]) -> Self {
+        self.state.lock().unwrap().bundle = pem(certs);
+        self
+    }
+
+    pub fn ca(&self) -> &Certificate {
+        &self.ca
+    }
+
+    pub fn settings(&self) -> &WorkloadSettings {
+        &self.settings
+    }
+
+    pub fn client(&self) -> WorkloadClient {
+        WorkloadClient::new(&self.settings)
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockWorkload {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            tx.send(()).unwrap_or(());
+        }
+    }
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+fn handle(req: Request<Body>, issuer: &Certificate, state: &Arc<Mutex<State>>) -> ResponseFuture {
+    let (status, days, bundle, key) = {
+        let mut state = state.lock().unwrap();
+        let uri = req.uri();
+        state.requests.push(
+            uri.path_and_query()
+                .map_or("", |path| path.as_str())
+                .to_owned(),
+        );
+        (
+            state.status,
+            state.days,
+            state.bundle.clone(),
+            state.key.clone(),
+        )
+    };
+
+    if !status.is_success() {
+        return Box::new(futures::future::ok(response(status, "failure".into())));
+    }
+
+    if req.uri().path() == "/trust-bundle" {
+        let body = json!({ "certificate": bundle });
+        return Box::new(futures::future::ok(response(
+            StatusCode::OK,
+            body.to_string().into(),
+        )));
+    }
+
+    if req.uri().path().ends_with("/sign") {
+        let fut = req.into_body().concat2().map(move |body| {
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let data = base64::decode(body["data"].as_str().unwrap()).unwrap();
+            let key = hmac::Key::new(hmac::HMAC_SHA256, &key);
+            let digest = base64::encode(hmac::sign(&key, &data).as_ref());
+
+            let body = json!({ "digest": digest });
+            response(StatusCode::OK, body.to_string().into())
+        });
+        return Box::new(fut);
+    }
+
+    let issuer = issuer.clone();
+    let fut = req.into_body().concat2().map(move |body| {
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let common_name = body["commonName"].as_str().unwrap();
+        let cert = CertGenerator::new(common_name, CertKind::Server)
+            .issuer(&issuer)
+            .days(days)
+            .generate()
+            .unwrap();
+
+        let mut chain = cert.cert().to_pem().unwrap();
+        chain.extend(issuer.cert().to_pem().unwrap());
+        let key = cert.key().private_key_to_pem_pkcs8().unwrap();
+        let body = json!({
+            "privateKey": { "type": "key", "bytes": String::from_utf8(key).unwrap() },
+            "certificate": String::from_utf8(chain).unwrap(),
+            "expiration": body["expiration"],
+        });
+
+        response(status, body.to_string().into())
+    });
+
+    Box::new(fut)
+}
+
+fn pem(certs: &[&Certificate]) -> String {
+    let pem = certs
+        .iter()
+        .flat_map(|cert| cert.cert().to_pem().unwrap())
+        .collect();
+    String::from_utf8(pem).unwrap()
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    Response::builder().status(status).body(body).unwrap()
+}
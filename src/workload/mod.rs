@@ -0,0 +1,373 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::{Fail, ResultExt};
+use futures::{future, Future, Stream};
+use http::header::CONTENT_TYPE;
+use hyper::{Body, Client as HyperClient, Request, Uri};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::tls::ServerIdentity;
+use crate::{Error, ErrorKind, WorkloadSettings};
+
+mod bundle;
+mod connector;
+#[cfg(test)]
+pub mod mock;
+mod renewal;
+
+pub use bundle::TrustBundle;
+pub use connector::UnixConnector;
+pub use renewal::CertificateRenewal;
+
+/// Base of request URIs. Requests are always sent to the workload socket, so
+/// the host is never resolved.
+const BASE_URI: &str = "http://workload";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone)]
+pub struct WorkloadClient {
+    client: HyperClient<UnixConnector, Body>,
+    settings: WorkloadSettings,
+}
+
+impl WorkloadClient {
+    pub fn new(settings: &WorkloadSettings) -> Self {
+        let connector = UnixConnector::new(Path::new(settings.uri().path()));
+
+        WorkloadClient {
+            client: HyperClient::builder().build(connector),
+            settings: settings.clone(),
+        }
+    }
+
+    pub fn server_certificate(
+        &self,
+        common_name: &str,
+        expiration: SystemTime,
+    ) -> impl Future<Item = ServerIdentity, Error = Error> + Send {
+        let path = [
+            "modules",
+            self.settings.module_id(),
+            "genid",
+            self.settings.generation_id(),
+            "certificate",
+            "server",
+        ];
+        let body = ServerCertificateRequest {
+            common_name: common_name.to_owned(),
+            expiration: rfc3339(expiration),
+        };
+
+        self.post(&path, &body)
+            .and_then(|res: CertificateResponse| res.into_identity())
+    }
+
+    pub fn trust_bundle(&self) -> impl Future<Item = String, Error = Error> + Send {
+        self.get(&["trust-bundle"])
+            .map(|res: TrustBundleResponse| res.certificate)
+    }
+
+    pub fn sign(
+        &self,
+        key_id: &str,
+        data: &[u8],
+    ) -> impl Future<Item = Vec<u8>, Error = Error> + Send {
+        let path = [
+            "modules",
+            self.settings.module_id(),
+            "genid",
+            self.settings.generation_id(),
+            "sign",
+        ];
+        let body = SignRequest {
+            key_id: key_id.to_owned(),
+            algo: "HMACSHA256".to_owned(),
+            data: base64::encode(data),
+        };
+
+        self.post(&path, &body).and_then(|res: SignResponse| {
+            let digest = base64::decode(&res.digest).context(ErrorKind::Workload(
+                "sign returned an invalid digest".to_owned(),
+            ))?;
+            Ok(digest)
+        })
+    }
+
+    fn get<R>(&self, path: &[&str]) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        R: DeserializeOwned + Send + 'static,
+    {
+        let req = self.uri(path).and_then(|uri| {
+            let req = Request::get(uri)
+                .body(Body::empty())
+                .context(ErrorKind::Workload("invalid request".to_owned()))?;
+            Ok(req)
+        });
+
+        self.send(req)
+    }
+
+    fn post<T, R>(&self, path: &[&str], body: &T) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        T: Serialize,
+        R: DeserializeOwned + Send + 'static,
+    {
+        let req = serde_json::to_vec(body)
+            .context(ErrorKind::Workload(
+                "could not serialize request".to_owned(),
+            ))
+            .map_err(Error::from)
+            .and_then(|body| {
+                let req = Request::post(self.uri(path)?)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .context(ErrorKind::Workload("invalid request".to_owned()))?;
+                Ok(req)
+            });
+
+        self.send(req)
+    }
+
+    fn send<R>(
+        &self,
+        req: Result<Request<Body>, Error>,
+    ) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        R: DeserializeOwned + Send + 'static,
+    {
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        let operation = format!("{} {}", req.method(), req.uri().path());
+        let socket = self.settings.uri().to_string();
+        let fut =
+            self.client
+                .request(req)
+                .and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                })
+                .map_err(move |err| {
+                    let kind = ErrorKind::Workload(format!("could not reach {}", socket));
+                    Error::from(err.context(kind))
+                })
+                .and_then(move |(status, body)| {
+                    if !status.is_success() {
+                        return Err(Error::from(ErrorKind::Workload(format!(
+                            "{} responded with {}: {}",
+                            operation,
+                            status,
+                            String::from_utf8_lossy(&body)
+                        ))));
+                    }
+
+                    let res = serde_json::from_slice(&body).context(ErrorKind::Workload(
+                        format!("{} returned an invalid response", operation),
+                    ))?;
+                    Ok(res)
+                });
+
+        Box::new(fut)
+    }
+
+    fn uri(&self, path: &[&str]) -> Result<Uri, Error> {
+        let mut url = Url::parse(BASE_URI)?;
+        url.path_segments_mut()
+            .map_err(|_| ErrorKind::InvalidUrl(BASE_URI.to_owned()))?
+            .extend(path);
+        url.query_pairs_mut()
+            .append_pair("api-version", self.settings.api_version());
+
+        Ok(url.as_str().parse()?)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerCertificateRequest {
+    common_name: String,
+    expiration: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateResponse {
+    private_key: PrivateKey,
+    certificate: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignRequest {
+    key_id: String,
+    algo: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct TrustBundleResponse {
+    certificate: String,
+}
+
+#[derive(Deserialize)]
+struct PrivateKey {
+    #[serde(rename = "type")]
+    kind: String,
+    bytes: Option<String>,
+}
+
+impl CertificateResponse {
+    fn into_identity(self) -> Result<ServerIdentity, Error> {
+        match (self.private_key.kind.as_str(), self.private_key.bytes) {
+            ("key", Some(key)) => {
+                ServerIdentity::from_pem(self.certificate.as_bytes(), key.as_bytes())
+            }
+            (kind, _) => Err(Error::from(ErrorKind::Workload(format!(
+                "private key of type {} is not supported",
+                kind
+            )))),
+        }
+    }
+}
+
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let (days, secs) = (secs / SECONDS_PER_DAY, secs % SECONDS_PER_DAY);
+
+    // civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use ring::hmac;
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::workload::mock::MockWorkload;
+    use crate::workload::rfc3339;
+    use crate::ErrorKind;
+
+    #[test]
+    fn it_formats_rfc3339_timestamps() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert_eq!(rfc3339(at(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(at(1_000_000_000)), "2001-09-09T01:46:40Z");
+        assert_eq!(rfc3339(at(1_582_934_399)), "2020-02-28T23:59:59Z");
+        assert_eq!(rfc3339(at(1_582_934_400)), "2020-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn it_requests_server_certificate() {
+        let workload = MockWorkload::start();
+        let mut runtime = Runtime::new().unwrap();
+
+        let identity = runtime
+            .block_on(
+                workload
+                    .client()
+                    .server_certificate("localhost", SystemTime::now()),
+            )
+            .unwrap();
+
+        assert_eq!(identity.cert().subject().to_string(), "CN=localhost");
+        assert_eq!(identity.chain().len(), 1);
+        assert_eq!(
+            workload.requests(),
+            vec!["/modules/edge-proxy/genid/1/certificate/server?api-version=2019-01-30"]
+        );
+    }
+
+    #[test]
+    fn it_requests_trust_bundle() {
+        let workload = MockWorkload::start();
+        let mut runtime = Runtime::new().unwrap();
+
+        let bundle = runtime.block_on(workload.client().trust_bundle()).unwrap();
+
+        assert_eq!(
+            bundle.as_bytes(),
+            &workload.ca().cert().to_pem().unwrap()[..]
+        );
+        assert_eq!(
+            workload.requests(),
+            vec!["/trust-bundle?api-version=2019-01-30"]
+        );
+    }
+
+    #[test]
+    fn it_requests_signature() {
+        let workload = MockWorkload::start();
+        let mut runtime = Runtime::new().unwrap();
+
+        let digest = runtime
+            .block_on(workload.client().sign("primary", b"data"))
+            .unwrap();
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &workload.key());
+        assert_eq!(digest, hmac::sign(&key, b"data").as_ref());
+        assert_eq!(
+            workload.requests(),
+            vec!["/modules/edge-proxy/genid/1/sign?api-version=2019-01-30"]
+        );
+    }
+
+    #[test]
+    fn it_reports_workload_errors() {
+        let workload = MockWorkload::start().with_status(500);
+        let mut runtime = Runtime::new().unwrap();
+
+        let err = runtime
+            .block_on(
+                workload
+                    .client()
+                    .server_certificate("localhost", SystemTime::now()),
+            )
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Workload(
+                "POST /modules/edge-proxy/genid/1/certificate/server responded with 500 Internal Server Error: failure"
+                    .to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::Fail;
+use futures::future::{self, Loop};
+use futures::Future;
+use log::{info, warn};
+use tokio::timer::Delay;
+
+use crate::proxy::Crls;
+use crate::tls::{self, Acceptor, ServerIdentity};
+use crate::workload::WorkloadClient;
+use crate::x509::Certificate;
+use crate::{logging, Error, ErrorKind, ServiceSettings, WorkloadCertificateSettings};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the server certificate of a TLS entrypoint issued by the workload
+/// API, requesting a new one once four fifths of its lifetime have passed.
+#[derive(Clone)]
+pub struct CertificateRenewal {
+    client: WorkloadClient,
+    settings: ServiceSettings,
+    certificate: WorkloadCertificateSettings,
+    crls: Crls,
+}
+
+impl CertificateRenewal {
+    pub fn new(client: WorkloadClient, settings: &ServiceSettings, crls: &Crls) -> Self {
+        CertificateRenewal {
+            client,
+            settings: settings.clone(),
+            certificate: settings.workload_certificate().cloned().unwrap_or_default(),
+            crls: crls.clone(),
+        }
+    }
+
+    /// Requests a certificate for the configured common name, or for the host
+    /// of the entrypoint if none is configured.
+    pub fn fetch(&self) -> impl Future<Item = ServerIdentity, Error = Error> + Send {
+        let common_name = self
+            .certificate
+            .common_name()
+            .or_else(|| self.settings.entrypoint().host_str())
+            .unwrap_or_default()
+            .to_owned();
+
+        self.client.server_certificate(
+            &common_name,
+            SystemTime::now() + self.certificate.validity(),
+        )
+    }
+
+    /// Renews the certificate presented by the acceptor before it expires.
+    /// Failed attempts are logged and retried while the previous certificate
+    /// stays in place.
+    pub fn watch(
+        self,
+        identity: &ServerIdentity,
+        acceptor: Acceptor,
+    ) -> impl Future<Item = (), Error = Error> {
+        let first = renew_in(identity.cert());
+
+        future::loop_fn(first, move |delay| {
+            let renewal = self.clone();
+            let acceptor = acceptor.clone();
+
+            Delay::new(Instant::now() + delay)
+                .map_err(|err| Error::from(err.context(ErrorKind::Tokio)))
+                .and_then(move |_| {
+                    renewal.fetch().then(move |result| {
+                        let next = result
+                            .and_then(|identity| renewal.install(&identity, &acceptor))
+                            .unwrap_or_else(|err| {
+                                warn!(
+                                    "Could not renew server certificate for {}, retrying in {:?}",
+                                    renewal.settings.name(),
+                                    RETRY_INTERVAL
+                                );
+                                logging::failure(&err);
+                                RETRY_INTERVAL
+                            });
+
+                        Ok(Loop::Continue(next))
+                    })
+                })
+        })
+    }
+
+    fn install(&self, identity: &ServerIdentity, acceptor: &Acceptor) -> Result<Duration, Error> {
+        acceptor.replace(tls::acceptor(&self.settings, identity, &self.crls)?);
+        info!("Renewed server certificate for {}", self.settings.name());
+
+        Ok(renew_in(identity.cert()))
+    }
+}
+
+pub fn renew_in(cert: &Certificate) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or_default();
+
+    let lifetime = cert.not_after() - cert.not_before();
+    let remaining = cert.not_after() - now;
+    let renew = remaining - lifetime / 5;
+
+    Duration::from_secs(renew.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use futures::future::{self, Loop};
+    use futures::Future;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Delay;
+    use url::Url;
+
+    use crate::cert::{CertGenerator, CertKind};
+    use crate::proxy::revocation::tests::certificate;
+    use crate::proxy::Crls;
+    use crate::tls::tests::presented;
+    use crate::tls::{self, Acceptor};
+    use crate::workload::mock::MockWorkload;
+    use crate::workload::renewal::{renew_in, CertificateRenewal};
+    use crate::{ServiceSettings, WorkloadCertificateSettings};
+
+    #[test]
+    fn it_renews_after_four_fifths_of_lifetime() {
+        let cert = CertGenerator::new("localhost", CertKind::Server)
+            .days(10)
+            .generate()
+            .unwrap();
+
+        let renew = renew_in(&certificate(cert.cert()));
+        let eight_days = Duration::from_secs(8 * 24 * 60 * 60);
+        assert!(renew <= eight_days);
+        assert!(renew > eight_days - Duration::from_secs(60));
+
+        let expired = CertGenerator::new("localhost", CertKind::Server)
+            .days(0)
+            .generate()
+            .unwrap();
+        assert_eq!(
+            renew_in(&certificate(expired.cert())),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn it_replaces_certificate_when_due() {
+        // certificates expiring right away are due for renewal immediately
+        let workload = MockWorkload::start().with_days(0);
+        let settings = ServiceSettings::new(
+            "management".to_owned(),
+            Url::parse("https://localhost:3000").unwrap(),
+            Url::parse("https://iotedged:30000").unwrap(),
+            None,
+            Path::new("token"),
+        )
+        .with_workload_certificate(WorkloadCertificateSettings::default());
+        let crls = Crls::default();
+        let renewal = CertificateRenewal::new(workload.client(), &settings, &crls);
+        let mut runtime = Runtime::new().unwrap();
+
+        let identity = runtime.block_on(renewal.fetch()).unwrap();
+        let acceptor = Acceptor::new(tls::acceptor(&settings, &identity, &crls).unwrap());
+        let current = |acceptor: &Acceptor| presented(&acceptor.get()).der().to_vec();
+        let first = current(&acceptor);
+
+        // poll until the certificate was replaced, generating keys can take a
+        // while on a busy machine
+        let replaced = {
+            let acceptor = acceptor.clone();
+            let first = first.clone();
+            future::loop_fn((), move |_| {
+                let done = current(&acceptor) != first;
+                Delay::new(Instant::now() + Duration::from_millis(20)).map(move |_| {
+                    if done {
+                        Loop::Break(())
+                    } else {
+                        Loop::Continue(())
+                    }
+                })
+            })
+        };
+        let timeout = Delay::new(Instant::now() + Duration::from_secs(10));
+        let watch = renewal.watch(&identity, acceptor.clone());
+        runtime
+            .block_on(
+                watch
+                    .map_err(|_| ())
+                    .select2(replaced.select2(timeout).map_err(|_| ()))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            )
+            .unwrap();
+
+        assert!(workload.requests().len() > 1);
+        assert_ne!(current(&acceptor), first);
+    }
+}
@@ -0,0 +1,655 @@
+//! Reads the parts of X.509 certificates and revocation lists the proxy
+//! inspects, so that pinning, expiry tracking and revocation checks behave the
+//! same with either TLS backend. Only DER is parsed, signatures are checked
+//! with ring.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use ring::signature::{self, VerificationAlgorithm};
+
+use crate::{Error, ErrorKind};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const BMP_STRING: u8 = 0x1e;
+const VERSION: u8 = 0xa0;
+const CRL_EXTENSIONS: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+const SAN_EMAIL: u8 = 0x81;
+const SAN_DNS: u8 = 0x82;
+const SAN_URI: u8 = 0x86;
+const SAN_IP: u8 = 0x87;
+
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Short names OpenSSL uses for attributes of distinguished names.
+const ATTRIBUTES: [(&[u8], &str); 14] = [
+    (COMMON_NAME, "CN"),
+    (&[0x55, 0x04, 0x04], "SN"),
+    (&[0x55, 0x04, 0x05], "serialNumber"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "street"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x55, 0x04, 0x0c], "title"),
+    (&[0x55, 0x04, 0x2a], "GN"),
+    (
+        &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+        "emailAddress",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01],
+        "UID",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+];
+
+const RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const RSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const RSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Distinguished name, compared by its encoding.
+#[derive(Clone, Debug)]
+pub struct Name {
+    der: Vec<u8>,
+    entries: Vec<(String, String)>,
+}
+
+impl Name {
+    fn parse(der: &[u8]) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        let mut rdns = Reader::new(Reader::new(der).read(SEQUENCE)?);
+        while !rdns.is_empty() {
+            let mut attributes = Reader::new(rdns.read(SET)?);
+            while !attributes.is_empty() {
+                let mut attribute = Reader::new(attributes.read(SEQUENCE)?);
+                let oid = attribute.read(OID)?;
+                let (tag, value, _) = attribute.read_any()?;
+
+                let key = ATTRIBUTES
+                    .iter()
+                    .find(|(attribute, _)| *attribute == oid)
+                    .map_or_else(|| dotted(oid), |(_, key)| (*key).to_owned());
+                entries.push((key, text(tag, value)));
+            }
+        }
+
+        Ok(Name {
+            der: der.to_vec(),
+            entries,
+        })
+    }
+
+    /// DER encoding, which is how certificates refer to their issuer.
+    #[cfg(not(feature = "openssl"))]
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn common_names(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key == "CN")
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.der == other.der
+    }
+}
+
+/// Formats the name like OpenSSL's short form, e.g. `CN=iotedged,O=Edge`.
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (key, value)) in self.entries.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    der: Vec<u8>,
+    serial: Vec<u8>,
+    issuer: Name,
+    subject: Name,
+    not_before: i64,
+    not_after: i64,
+    public_key_info: Vec<u8>,
+    alt_names: Vec<String>,
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let mut cert = Reader::new(Reader::new(der).read(SEQUENCE)?);
+        let mut tbs = Reader::new(cert.read(SEQUENCE)?);
+
+        tbs.read_optional(VERSION)?;
+        let serial = tbs.read(INTEGER)?.to_vec();
+        tbs.read(SEQUENCE)?;
+        let issuer = Name::parse(tbs.read_element(SEQUENCE)?)?;
+        let mut validity = Reader::new(tbs.read(SEQUENCE)?);
+        let not_before = validity.read_time()?;
+        let not_after = validity.read_time()?;
+        let subject = Name::parse(tbs.read_element(SEQUENCE)?)?;
+        let public_key_info = tbs.read_element(SEQUENCE)?.to_vec();
+
+        let mut alt_names = Vec::new();
+        while !tbs.is_empty() {
+            let (tag, value, _) = tbs.read_any()?;
+            if tag == EXTENSIONS {
+                alt_names = subject_alt_names(value)?;
+            }
+        }
+
+        Ok(Certificate {
+            der: der.to_vec(),
+            serial,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            public_key_info,
+            alt_names,
+        })
+    }
+
+    /// Parses the first certificate of a PEM document.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, Error> {
+        let der = pem_blocks(&String::from_utf8_lossy(pem), "CERTIFICATE")?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ErrorKind::InvalidCertificate("no PEM certificate".to_owned()))?;
+
+        Certificate::from_der(&der)
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn issuer(&self) -> &Name {
+        &self.issuer
+    }
+
+    pub fn subject(&self) -> &Name {
+        &self.subject
+    }
+
+    /// Start of the validity period in seconds since the Unix epoch.
+    pub fn not_before(&self) -> i64 {
+        self.not_before
+    }
+
+    /// End of the validity period in seconds since the Unix epoch.
+    pub fn not_after(&self) -> i64 {
+        self.not_after
+    }
+
+    /// DER encoded SubjectPublicKeyInfo, which public key pins are taken of.
+    pub fn public_key_info(&self) -> &[u8] {
+        &self.public_key_info
+    }
+
+    /// DNS names, URIs, email and IP addresses the certificate was issued for.
+    pub fn alt_names(&self) -> &[String] {
+        &self.alt_names
+    }
+
+    /// Checks a signature made with the key of this certificate. Algorithms
+    /// ring does not implement, such as RSA-PSS, never verify.
+    fn verify_signature(&self, algorithm: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let verify = || -> Result<bool, Error> {
+            let mut info = Reader::new(Reader::new(&self.public_key_info).read(SEQUENCE)?);
+            let mut key_algorithm = Reader::new(info.read(SEQUENCE)?);
+            key_algorithm.read(OID)?;
+            let curve = key_algorithm.read_optional(OID)?;
+            let key = bit_string(info.read(BIT_STRING)?)?;
+
+            let mut algorithm = Reader::new(algorithm);
+            let algorithm = algorithm.read(OID)?;
+            let algorithm: &dyn VerificationAlgorithm = match (algorithm, curve) {
+                (RSA_SHA256, _) => &signature::RSA_PKCS1_2048_8192_SHA256,
+                (RSA_SHA384, _) => &signature::RSA_PKCS1_2048_8192_SHA384,
+                (RSA_SHA512, _) => &signature::RSA_PKCS1_2048_8192_SHA512,
+                (ECDSA_SHA256, Some(P256)) => &signature::ECDSA_P256_SHA256_ASN1,
+                (ECDSA_SHA384, Some(P256)) => &signature::ECDSA_P256_SHA384_ASN1,
+                (ECDSA_SHA256, Some(P384)) => &signature::ECDSA_P384_SHA256_ASN1,
+                (ECDSA_SHA384, Some(P384)) => &signature::ECDSA_P384_SHA384_ASN1,
+                (ED25519, _) => &signature::ED25519,
+                _ => return Ok(false),
+            };
+
+            Ok(signature::UnparsedPublicKey::new(algorithm, key)
+                .verify(message, signature)
+                .is_ok())
+        };
+
+        verify().unwrap_or(false)
+    }
+}
+
+/// Certificate revocation list.
+#[derive(Clone, Debug)]
+pub struct Crl {
+    issuer: Name,
+    next_update: Option<i64>,
+    revoked: Vec<Vec<u8>>,
+    tbs: Vec<u8>,
+    algorithm: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Crl {
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let mut crl = Reader::new(Reader::new(der).read(SEQUENCE)?);
+        let tbs = crl.read_element(SEQUENCE)?;
+        let algorithm = crl.read(SEQUENCE)?.to_vec();
+        let signature = bit_string(crl.read(BIT_STRING)?)?.to_vec();
+
+        let mut list = Reader::new(Reader::new(tbs).read(SEQUENCE)?);
+        list.read_optional(INTEGER)?;
+        list.read(SEQUENCE)?;
+        let issuer = Name::parse(list.read_element(SEQUENCE)?)?;
+        list.read_time()?;
+        let next_update = match list.peek() {
+            Some(UTC_TIME) | Some(GENERALIZED_TIME) => Some(list.read_time()?),
+            _ => None,
+        };
+
+        let mut revoked = Vec::new();
+        if list.peek() == Some(SEQUENCE) {
+            let mut entries = Reader::new(list.read(SEQUENCE)?);
+            while !entries.is_empty() {
+                let mut entry = Reader::new(entries.read(SEQUENCE)?);
+                revoked.push(entry.read(INTEGER)?.to_vec());
+            }
+        }
+        list.read_optional(CRL_EXTENSIONS)?;
+
+        Ok(Crl {
+            issuer,
+            next_update,
+            revoked,
+            tbs: tbs.to_vec(),
+            algorithm,
+            signature,
+        })
+    }
+
+    pub fn issuer(&self) -> &Name {
+        &self.issuer
+    }
+
+    /// Time the next list is due in seconds since the Unix epoch.
+    pub fn next_update(&self) -> Option<i64> {
+        self.next_update
+    }
+
+    pub fn is_signed_by(&self, issuer: &Certificate) -> bool {
+        issuer.verify_signature(&self.algorithm, &self.tbs, &self.signature)
+    }
+
+    pub fn is_revoked(&self, cert: &Certificate) -> bool {
+        self.revoked.contains(&cert.serial)
+    }
+}
+
+/// Decodes the base64 bodies of the PEM blocks with the given label.
+pub fn pem_blocks(pem: &str, label: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+
+    let mut blocks = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(&begin) {
+        rest = &rest[start + begin.len()..];
+        let stop = rest
+            .find(&end)
+            .ok_or_else(|| ErrorKind::InvalidCertificate(format!("unterminated {}", label)))?;
+
+        let body = rest[..stop]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        let der = base64::decode(&body)
+            .map_err(|_| ErrorKind::InvalidCertificate(format!("invalid {} encoding", label)))?;
+        blocks.push(der);
+        rest = &rest[stop + end.len()..];
+    }
+
+    Ok(blocks)
+}
+
+fn subject_alt_names(extensions: &[u8]) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+
+    let mut extensions = Reader::new(Reader::new(extensions).read(SEQUENCE)?);
+    while !extensions.is_empty() {
+        let mut extension = Reader::new(extensions.read(SEQUENCE)?);
+        if extension.read(OID)? != SUBJECT_ALT_NAME {
+            continue;
+        }
+        extension.read_optional(0x01)?;
+
+        let value = extension.read(OCTET_STRING)?;
+        let mut general_names = Reader::new(Reader::new(value).read(SEQUENCE)?);
+        while !general_names.is_empty() {
+            let (tag, value, _) = general_names.read_any()?;
+            let name = match tag {
+                SAN_EMAIL | SAN_DNS | SAN_URI => Some(String::from_utf8_lossy(value).into_owned()),
+                SAN_IP => ip_address(value),
+                _ => None,
+            };
+            names.extend(name);
+        }
+    }
+
+    Ok(names)
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::from(octets).to_string())
+        }
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+fn text(tag: u8, value: &[u8]) -> String {
+    if tag != BMP_STRING {
+        return String::from_utf8_lossy(value).into_owned();
+    }
+
+    let units = value
+        .chunks(2)
+        .map(|unit| u16::from_be_bytes([unit[0], *unit.get(1).unwrap_or(&0)]))
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for byte in oid {
+        arc = (arc << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+
+    arcs.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn bit_string(value: &[u8]) -> Result<&[u8], Error> {
+    match value.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(malformed()),
+    }
+}
+
+fn malformed() -> Error {
+    Error::from(ErrorKind::InvalidCertificate("malformed DER".to_owned()))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Reads the next element, returning its tag, its contents and the whole
+    /// encoded element.
+    fn read_any(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), Error> {
+        let data = self.data;
+        let (&tag, rest) = data.split_first().ok_or_else(malformed)?;
+        let (&first, rest) = rest.split_first().ok_or_else(malformed)?;
+
+        let (length, rest) = if first < 0x80 {
+            (usize::from(first), rest)
+        } else {
+            let count = usize::from(first & 0x7f);
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err(malformed());
+            }
+            let length = rest[..count]
+                .iter()
+                .fold(0usize, |length, byte| (length << 8) | usize::from(*byte));
+            (length, &rest[count..])
+        };
+
+        if rest.len() < length {
+            return Err(malformed());
+        }
+
+        let header = data.len() - rest.len();
+        self.data = &rest[length..];
+        Ok((tag, &rest[..length], &data[..header + length]))
+    }
+
+    fn read(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        self.read_element(tag)
+            .and_then(|element| Reader::new(element).read_any())
+            .map(|(_, contents, _)| contents)
+    }
+
+    fn read_element(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        let (actual, _, element) = self.read_any()?;
+        if actual != tag {
+            return Err(malformed());
+        }
+
+        Ok(element)
+    }
+
+    fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek() != Some(tag) {
+            return Ok(None);
+        }
+
+        self.read(tag).map(Some)
+    }
+
+    /// Reads a UTCTime or GeneralizedTime as seconds since the Unix epoch.
+    fn read_time(&mut self) -> Result<i64, Error> {
+        let (tag, value, _) = self.read_any()?;
+        let value = std::str::from_utf8(value).map_err(|_| malformed())?;
+        let value = value.strip_suffix('Z').ok_or_else(malformed)?;
+
+        let (year, rest) = match tag {
+            UTC_TIME if value.len() == 12 => {
+                let year = number(&value[..2])?;
+                (
+                    if year < 50 { 2000 + year } else { 1900 + year },
+                    &value[2..],
+                )
+            }
+            GENERALIZED_TIME if value.len() == 14 => (number(&value[..4])?, &value[4..]),
+            _ => return Err(malformed()),
+        };
+
+        let field = |index: usize| number(&rest[index * 2..index * 2 + 2]);
+        let days = days_from_civil(year, field(0)?, field(1)?);
+        Ok(days * SECONDS_PER_DAY + field(2)? * 3600 + field(3)? * 60 + field(4)?)
+    }
+}
+
+fn number(digits: &str) -> Result<i64, Error> {
+    if !digits.bytes().all(|digit| digit.is_ascii_digit()) {
+        return Err(malformed());
+    }
+
+    digits.parse().map_err(|_| malformed())
+}
+
+/// Days since the epoch of a civil date, see
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+
+    use crate::cert::{display_name, CertGenerator, CertKind};
+    use crate::proxy::revocation::tests::crl;
+    use crate::x509::{days_from_civil, pem_blocks, Certificate};
+
+    #[test]
+    fn it_reads_certificate_fields() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .san("10.0.0.1")
+            .san("iotedged.local")
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+
+        let parsed = Certificate::from_der(&cert.cert().to_der().unwrap()).unwrap();
+
+        assert_eq!(parsed.subject().to_string(), "CN=iotedged");
+        assert_eq!(parsed.issuer().to_string(), "CN=ca");
+        assert_eq!(
+            parsed.subject().common_names().collect::<Vec<_>>(),
+            ["iotedged"]
+        );
+        assert_eq!(
+            parsed.alt_names(),
+            &["iotedged", "10.0.0.1", "iotedged.local"]
+        );
+        assert_eq!(
+            parsed.public_key_info(),
+            &cert
+                .cert()
+                .public_key()
+                .unwrap()
+                .public_key_to_der()
+                .unwrap()[..]
+        );
+
+        let epoch = Asn1Time::from_unix(0).unwrap();
+        let diff = epoch.diff(cert.cert().not_after()).unwrap();
+        assert_eq!(
+            parsed.not_after(),
+            i64::from(diff.days) * 86_400 + i64::from(diff.secs)
+        );
+        assert!(parsed.not_before() <= parsed.not_after());
+        assert_eq!(
+            parsed.subject().to_string(),
+            display_name(cert.cert().subject_name())
+        );
+    }
+
+    #[test]
+    fn it_reads_pem_certificates() {
+        let cert = CertGenerator::new("iotedged", CertKind::Server)
+            .generate()
+            .unwrap();
+        let mut pem = cert.cert().to_pem().unwrap();
+        pem.extend(cert.cert().to_pem().unwrap());
+
+        let blocks = pem_blocks(&String::from_utf8(pem.clone()).unwrap(), "CERTIFICATE").unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], cert.cert().to_der().unwrap());
+
+        let parsed = Certificate::from_pem(&pem).unwrap();
+        assert_eq!(parsed.der(), &blocks[0][..]);
+    }
+
+    #[test]
+    fn it_checks_signature_and_entries_of_revocation_list() {
+        let ca = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let other = CertGenerator::new("ca", CertKind::Ca).generate().unwrap();
+        let revoked = CertGenerator::new("module-a", CertKind::Client)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        let valid = CertGenerator::new("module-b", CertKind::Client)
+            .issuer(&ca)
+            .generate()
+            .unwrap();
+        let parse = |cert: &crate::cert::Certificate| {
+            Certificate::from_der(&cert.cert().to_der().unwrap()).unwrap()
+        };
+
+        let list = crl(&ca, &[&revoked]);
+
+        assert_eq!(list.issuer(), parse(&ca).subject());
+        assert!(list.next_update().is_some());
+        assert!(list.is_signed_by(&parse(&ca)));
+        assert!(!list.is_signed_by(&parse(&other)));
+        assert!(list.is_revoked(&parse(&revoked)));
+        assert!(!list.is_revoked(&parse(&valid)));
+    }
+
+    #[test]
+    fn it_converts_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2049, 12, 31), 29_219);
+    }
+}